@@ -0,0 +1,325 @@
+// 二进制字节码文件的格式
+// 所有多字节整数一律按小端序存放，与运行编译器的机器无关。文件由三部分组成：
+//
+//   文件头（24 字节）
+//     0   魔数 "TSTC"
+//     4   版本号 u16，目前为 1
+//     6   字节序 u8，1 表示小端，其余取值都不接受
+//     7   保留 u8，为 0
+//     8   标志 u32，目前没有定义任何标志，各位都必须为 0
+//     12  指令条数 u32
+//     16  段的个数 u32
+//     20  校验和 u32：文件头之后全部内容的 CRC-32
+//   指令：每条 5 字节，操作码 u8（OPCODES 中的下标）和操作数 i32
+//   段：每段以种类 u8、3 个保留字节和内容的字节数 u32 开头，随后是内容。
+//     读取时跳过不认识的段，以后增加新的段不影响旧的虚拟机
+//
+// 常量池段的内容为字符串个数 u32，以及每个字符串的字节数 u32 和 UTF-8 内容。
+// 符号表段的内容为函数个数 u32，每个函数依次为名字、入口地址 u32、结束地址 u32、栈帧单元数 u32、
+// 单元个数 u32 和各单元：名字、相对基地址的偏移 i32、种类 u8、进入作用域处的地址 u32。名字都是字节数 u32 加 UTF-8 内容。
+// 行号表段的内容为条目个数 u32，每个条目为地址 u32、行 u32、列 u32，表示从该地址起到下一个条目之前的指令
+// 都由源程序这一位置生成，行为 0 表示没有对应的位置。条目按地址递增排列
+use std::io::{self, ErrorKind};
+
+use crate::frame::{FrameLayout, Slot, SlotKind};
+use crate::syntax::Code;
+use crate::virtual_machine::{self, Program};
+
+pub const MAGIC: [u8; 4] = *b"TSTC";
+pub const VERSION: u16 = 1;
+const LITTLE_ENDIAN: u8 = 1;
+const HEADER_SIZE: usize = 24;
+
+// 目前没有定义任何标志
+const KNOWN_FLAGS: u32 = 0;
+
+// 段的种类
+pub const SECTION_CONSTANTS: u8 = 1; // 字符串常量池
+pub const SECTION_SYMBOLS: u8 = 2;   // 各函数的栈帧布局和变量名
+pub const SECTION_LINES: u8 = 3;     // 指令对应的源程序位置
+
+// 符号表段中单元种类的编号
+const SLOT_KINDS: [SlotKind; 5] = [SlotKind::SavedBase, SlotKind::ReturnAddress, SlotKind::Parameter, SlotKind::Local, SlotKind::Temp];
+
+// 一字节操作码与助记符的对应关系，顺序一经发布就不能改动，新的操作码只能加在末尾
+pub const OPCODES: [&str; 34] = [
+    "LOAD", "LOADI", "LOADA", "LOADR", "STO", "STOR", "STI", "ADD", "SUB", "MULT", "DIV", "BR", "BRF",
+    "EQ", "NOTEQ", "GT", "LES", "GE", "LE", "AND", "OR", "NOT", "IN", "OUT", "OUTC", "OUTN", "OUTS",
+    "POP", "DUP", "RETURN", "ENTER", "CAL", "PAS", "HALT",
+];
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+// CRC-32（IEEE 802.3 多项式）
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// 把指令、字符串常量和调试信息编码为字节码文件的内容；
+// frames 为空时不写符号表段，lines 为每条指令的（行, 列），为空时不写行号表段
+pub fn encode(codes: &[Code], strings: &[String], frames: &[FrameLayout], lines: &[Option<(usize, usize)>]) -> io::Result<Vec<u8>> {
+    let mut body = Vec::with_capacity(codes.len() * 5);
+    for code in codes {
+        let opcode = OPCODES.iter().position(|&name| name == code.opt)
+            .ok_or_else(|| invalid(format!("无法编码的操作码 {}", code.opt)))?;
+        body.push(opcode as u8);
+        body.extend_from_slice(&code.operand.to_le_bytes());
+    }
+
+    let mut sections = 0u32;
+    if !strings.is_empty() {
+        let mut pool = Vec::new();
+        pool.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        for text in strings {
+            pool.extend_from_slice(&(text.len() as u32).to_le_bytes());
+            pool.extend_from_slice(text.as_bytes());
+        }
+        write_section(&mut body, SECTION_CONSTANTS, &pool);
+        sections += 1;
+    }
+    if !frames.is_empty() {
+        let mut symbols = Vec::new();
+        symbols.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+        for frame in frames {
+            write_name(&mut symbols, &frame.function);
+            for value in [frame.entry as u32, frame.end as u32, frame.size.max(0) as u32, frame.slots.len() as u32] {
+                symbols.extend_from_slice(&value.to_le_bytes());
+            }
+            for slot in &frame.slots {
+                write_name(&mut symbols, &slot.name);
+                symbols.extend_from_slice(&slot.offset.to_le_bytes());
+                symbols.push(SLOT_KINDS.iter().position(|&kind| kind == slot.kind).unwrap() as u8);
+                symbols.extend_from_slice(&(slot.start as u32).to_le_bytes());
+            }
+        }
+        write_section(&mut body, SECTION_SYMBOLS, &symbols);
+        sections += 1;
+    }
+    if !lines.is_empty() {
+        // 相邻的指令位置相同时只记一个条目
+        let mut entries = Vec::new();
+        let mut previous = None;
+        for (address, &position) in lines.iter().enumerate().take(codes.len()) {
+            if address == 0 || position != previous {
+                let (row, col) = position.unwrap_or((0, 0));
+                entries.push([address as u32, row as u32, col as u32]);
+                previous = position;
+            }
+        }
+        let mut table = Vec::with_capacity(4 + entries.len() * 12);
+        table.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        for entry in entries.iter().flatten() {
+            table.extend_from_slice(&entry.to_le_bytes());
+        }
+        write_section(&mut body, SECTION_LINES, &table);
+        sections += 1;
+    }
+
+    let mut file = Vec::with_capacity(HEADER_SIZE + body.len());
+    file.extend_from_slice(&MAGIC);
+    file.extend_from_slice(&VERSION.to_le_bytes());
+    file.push(LITTLE_ENDIAN);
+    file.push(0);
+    file.extend_from_slice(&0u32.to_le_bytes());
+    file.extend_from_slice(&(codes.len() as u32).to_le_bytes());
+    file.extend_from_slice(&sections.to_le_bytes());
+    file.extend_from_slice(&crc32(&body).to_le_bytes());
+    file.extend_from_slice(&body);
+    Ok(file)
+}
+
+fn write_section(out: &mut Vec<u8>, kind: u8, content: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&[0; 3]);
+    out.extend_from_slice(&(content.len() as u32).to_le_bytes());
+    out.extend_from_slice(content);
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    out.extend_from_slice(name.as_bytes());
+}
+
+// 按顺序读取字节，越过末尾时返回错误而不是越界
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize, what: &str) -> io::Result<&'a [u8]> {
+        let end = self.pos.checked_add(count).filter(|&end| end <= self.bytes.len()).ok_or_else(|| {
+            let message = format!("读取{}时文件意外结束：需要 {} 字节，只剩 {} 字节", what, count, self.bytes.len() - self.pos);
+            io::Error::new(ErrorKind::UnexpectedEof, message)
+        })?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self, what: &str) -> io::Result<u8> {
+        Ok(self.take(1, what)?[0])
+    }
+
+    fn u16(&mut self, what: &str) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2, what)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self, what: &str) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4, what)?.try_into().unwrap()))
+    }
+
+    fn i32(&mut self, what: &str) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4, what)?.try_into().unwrap()))
+    }
+
+    fn name(&mut self, what: &str) -> io::Result<String> {
+        let size = self.u32(what)? as usize;
+        let text = self.take(size, what)?;
+        String::from_utf8(text.to_vec()).map_err(|_| invalid(format!("{}中的名字不是合法的 UTF-8", what)))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+}
+
+// 解码字节码文件，格式不对、被截断或校验和不符时返回错误
+pub fn decode(bytes: &[u8]) -> io::Result<Program> {
+    let mut header = Reader { bytes, pos: 0 };
+    if header.take(4, "魔数")? != MAGIC {
+        return Err(invalid("不是 TEST 的字节码文件：魔数不符".to_string()));
+    }
+    let version = header.u16("版本号")?;
+    if version != VERSION {
+        return Err(invalid(format!("不支持的字节码版本 {}，只支持版本 {}", version, VERSION)));
+    }
+    let endian = header.u8("字节序")?;
+    if endian != LITTLE_ENDIAN {
+        return Err(invalid(format!("不支持的字节序标记 {}", endian)));
+    }
+    header.u8("保留字节")?;
+    let flags = header.u32("标志")?;
+    if flags & !KNOWN_FLAGS != 0 {
+        return Err(invalid(format!("未知的标志 {:#x}", flags & !KNOWN_FLAGS)));
+    }
+    let count = header.u32("指令条数")? as usize;
+    let sections = header.u32("段的个数")?;
+    let checksum = header.u32("校验和")?;
+    let body = &bytes[HEADER_SIZE..];
+    let actual = crc32(body);
+    if actual != checksum {
+        return Err(invalid(format!("校验和不符（文件中为 {:#010x}，实际为 {:#010x}），文件已损坏", checksum, actual)));
+    }
+
+    let mut reader = Reader { bytes: body, pos: 0 };
+    let mut codes = Vec::with_capacity(count.min(body.len() / 5));
+    for address in 0..count {
+        let opcode = reader.u8("指令")?;
+        let name = OPCODES.get(opcode as usize)
+            .ok_or_else(|| invalid(format!("第 {} 条指令的操作码 {:#04x} 未知", address, opcode)))?;
+        let operand = reader.i32("指令")?;
+        codes.push(virtual_machine::Code::new(name, operand));
+    }
+
+    let mut strings = Vec::new();
+    let mut frames = Vec::new();
+    let mut lines = Vec::new();
+    for _ in 0..sections {
+        let kind = reader.u8("段的种类")?;
+        reader.take(3, "段的保留字节")?;
+        let size = reader.u32("段的长度")? as usize;
+        let content = reader.take(size, "段的内容")?;
+        if kind == SECTION_CONSTANTS {
+            strings = decode_constants(content)?;
+        } else if kind == SECTION_SYMBOLS {
+            frames = decode_symbols(content, count)?;
+        } else if kind == SECTION_LINES {
+            lines = decode_lines(content, count)?;
+        }
+    }
+    if !reader.is_empty() {
+        return Err(invalid(format!("最后一段之后还有 {} 字节多余的内容", body.len() - reader.pos)));
+    }
+    Ok(Program { codes, strings, frames, lines })
+}
+
+fn decode_constants(content: &[u8]) -> io::Result<Vec<String>> {
+    let mut reader = Reader { bytes: content, pos: 0 };
+    let count = reader.u32("常量池")?;
+    let mut strings = Vec::new();
+    for index in 0..count {
+        let size = reader.u32("常量池")? as usize;
+        let text = reader.take(size, "常量池")?;
+        let text = String::from_utf8(text.to_vec())
+            .map_err(|_| invalid(format!("常量池中第 {} 个字符串不是合法的 UTF-8", index)))?;
+        strings.push(text);
+    }
+    if !reader.is_empty() {
+        return Err(invalid("常量池段的长度与内容不符".to_string()));
+    }
+    Ok(strings)
+}
+
+fn decode_symbols(content: &[u8], count: usize) -> io::Result<Vec<FrameLayout>> {
+    let mut reader = Reader { bytes: content, pos: 0 };
+    let functions = reader.u32("符号表")?;
+    let mut frames = Vec::new();
+    for _ in 0..functions {
+        let function = reader.name("符号表")?;
+        let entry = reader.u32("符号表")? as usize;
+        let end = reader.u32("符号表")? as usize;
+        if entry > end || end > count {
+            return Err(invalid(format!("符号表中函数 {} 的地址范围 {}..{} 不在代码之内", function, entry, end)));
+        }
+        let size = reader.u32("符号表")? as i32;
+        let slot_count = reader.u32("符号表")?;
+        let mut slots = Vec::new();
+        for _ in 0..slot_count {
+            let name = reader.name("符号表")?;
+            let offset = reader.i32("符号表")?;
+            let kind = reader.u8("符号表")?;
+            let kind = *SLOT_KINDS.get(kind as usize)
+                .ok_or_else(|| invalid(format!("符号表中变量 {}.{} 的种类 {} 未知", function, name, kind)))?;
+            let start = reader.u32("符号表")? as usize;
+            slots.push(Slot { name, offset, kind, start });
+        }
+        frames.push(FrameLayout { function, entry, end, size, slots });
+    }
+    if !reader.is_empty() {
+        return Err(invalid("符号表段的长度与内容不符".to_string()));
+    }
+    Ok(frames)
+}
+
+// 展开为每条指令的位置
+fn decode_lines(content: &[u8], count: usize) -> io::Result<Vec<Option<(usize, usize)>>> {
+    let mut reader = Reader { bytes: content, pos: 0 };
+    let entries = reader.u32("行号表")?;
+    let mut lines = Vec::with_capacity(count);
+    let mut current = None;
+    for index in 0..entries {
+        let address = reader.u32("行号表")? as usize;
+        let row = reader.u32("行号表")? as usize;
+        let col = reader.u32("行号表")? as usize;
+        if address >= count || (index > 0 && address <= lines.len()) {
+            return Err(invalid(format!("行号表中的地址 {} 不是递增的或超出了代码的范围", address)));
+        }
+        lines.resize(address, current);
+        current = (row != 0).then_some((row, col));
+    }
+    if !reader.is_empty() {
+        return Err(invalid("行号表段的长度与内容不符".to_string()));
+    }
+    lines.resize(count, current);
+    Ok(lines)
+}
@@ -0,0 +1,286 @@
+// 调用图
+// 以各函数的栈帧布局为结点，以函数中的 CAL 指令为边。从 main 出发到达不了的函数不会执行；
+// 调用图中的强连通分量就是递归。没有递归时调用深度和栈用量都有静态上界：
+// 函数的栈用量是 ENTER 开辟的栈帧加上操作数栈，调用时被调函数的栈帧从主调函数当时的栈顶开始
+use std::fmt::Write;
+
+use crate::cfg;
+use crate::frame::FrameLayout;
+use crate::syntax::Code;
+use crate::virtual_machine::stack_effect;
+
+#[derive(Debug, Clone)]
+pub struct CallSite {
+    pub address: usize, // CAL 指令的地址
+    pub callee: usize,
+    pub depth: usize,   // 调用时主调函数操作数栈的深度，实参已经由 PAS 移入被调函数的栈帧
+}
+
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    pub names: Vec<String>,
+    pub frame_sizes: Vec<usize>,
+    pub operand_depths: Vec<usize>, // 函数内操作数栈的最大深度
+    pub sites: Vec<Vec<CallSite>>,
+    pub reachable: Vec<bool>,       // 从 main 出发能调用到
+    pub recursive: Vec<bool>,       // 沿调用关系能回到自身
+    pub cycles: Vec<Vec<usize>>,    // 每个递归的强连通分量中的一个环，首尾是同一个函数
+    pub stack: Vec<Option<usize>>,  // 最坏情况下从函数的基地址起用到的单元数，可能递归时没有上界
+    pub depth: Option<usize>,       // 从 main 开始的最大调用深度，main 自己算一层
+    reach: Vec<Vec<bool>>,          // reach[f][g]：f 经过至少一次调用能到达 g
+}
+
+impl CallGraph {
+    pub fn build(codes: &[Code], frames: &[FrameLayout]) -> CallGraph {
+        let count = frames.len();
+        let returns: Vec<bool> = frames.iter()
+            .map(|frame| codes[frame.entry..frame.end].iter().any(|code| code.opt == "RETURN" && code.operand != 0))
+            .collect();
+        let mut operand_depths = Vec::with_capacity(count);
+        let mut sites = Vec::with_capacity(count);
+        for frame in frames {
+            let (depth, calls) = walk(codes, frames, frame, &returns);
+            operand_depths.push(depth);
+            sites.push(calls);
+        }
+        let callees: Vec<Vec<usize>> = sites.iter()
+            .map(|calls| {
+                let mut callees: Vec<usize> = calls.iter().map(|site| site.callee).collect();
+                callees.sort_unstable();
+                callees.dedup();
+                callees
+            })
+            .collect();
+
+        let reach: Vec<Vec<bool>> = (0..count)
+            .map(|start| {
+                let mut seen = vec![false; count];
+                let mut work = callees[start].clone();
+                while let Some(f) = work.pop() {
+                    if !seen[f] {
+                        seen[f] = true;
+                        work.extend(&callees[f]);
+                    }
+                }
+                seen
+            })
+            .collect();
+        let main = frames.iter().position(|frame| frame.function == "main");
+        let reachable: Vec<bool> = (0..count).map(|f| Some(f) == main || main.is_some_and(|main| reach[main][f])).collect();
+        let recursive: Vec<bool> = (0..count).map(|f| reach[f][f]).collect();
+
+        // 每个强连通分量取编号最小的函数，沿分量内的边找回到它自身的最短环
+        let mut cycles = Vec::new();
+        for f in 0..count {
+            if recursive[f] && (0..f).all(|g| !(reach[f][g] && reach[g][f])) {
+                cycles.push(shortest_cycle(f, &callees, &reach));
+            }
+        }
+
+        let mut graph = CallGraph {
+            names: frames.iter().map(|frame| frame.function.clone()).collect(),
+            frame_sizes: frames.iter().map(|frame| frame.size.max(0) as usize).collect(),
+            operand_depths,
+            sites,
+            reachable,
+            recursive,
+            cycles,
+            stack: vec![None; count],
+            depth: None,
+            reach,
+        };
+        let bounded: Vec<bool> = (0..count)
+            .map(|f| !graph.recursive[f] && !(0..count).any(|g| graph.reach[f][g] && graph.recursive[g]))
+            .collect();
+        let mut memo = vec![None; count];
+        for (f, &finite) in bounded.iter().enumerate() {
+            if finite {
+                graph.stack[f] = Some(graph.stack_of(f, &mut memo));
+            }
+        }
+        if let Some(main) = main.filter(|&main| bounded[main]) {
+            let mut depths = vec![None; count];
+            graph.depth = Some(call_depth(main, &callees, &mut depths));
+        }
+        graph
+    }
+
+    // 没有递归时的最坏栈用量：栈帧加上操作数栈，或者某处调用时的操作数栈加上被调函数的用量
+    fn stack_of(&self, f: usize, memo: &mut Vec<Option<usize>>) -> usize {
+        if let Some(stack) = memo[f] {
+            return stack;
+        }
+        let mut peak = self.operand_depths[f];
+        for site in &self.sites[f] {
+            peak = peak.max(site.depth + self.stack_of(site.callee, memo));
+        }
+        let stack = self.frame_sizes[f] + peak;
+        memo[f] = Some(stack);
+        stack
+    }
+
+    // 程序所需的运行栈单元数：虚拟机执行每条指令前要求从栈顶起至少还有 3 个单元，main 的栈帧从 0 号单元开始
+    pub fn main_stack(&self) -> Option<usize> {
+        let main = self.names.iter().position(|name| name == "main")?;
+        self.stack[main].map(|stack| stack + 3)
+    }
+
+    // 文字形式的报告
+    pub fn report(&self) -> String {
+        let mut out = String::from("==调用图==\n");
+        for f in 0..self.names.len() {
+            let stack = match self.stack[f] {
+                Some(stack) => stack.to_string(),
+                None => "无上界".to_string(),
+            };
+            writeln!(out, "{}\t栈帧 {}\t操作数栈 {}\t最坏栈用量 {}", self.names[f], self.frame_sizes[f], self.operand_depths[f], stack).unwrap();
+            for (callee, count) in self.callee_counts(f) {
+                let addresses: Vec<String> = self.sites[f].iter()
+                    .filter(|site| site.callee == callee)
+                    .map(|site| site.address.to_string())
+                    .collect();
+                writeln!(out, "\t-> {}\t{} 处调用，地址 {}", self.names[callee], count, addresses.join(" ")).unwrap();
+            }
+        }
+        let unreachable: Vec<&str> = (0..self.names.len())
+            .filter(|&f| !self.reachable[f])
+            .map(|f| self.names[f].as_str())
+            .collect();
+        if unreachable.is_empty() {
+            out.push_str("不可达的函数：无\n");
+        } else {
+            writeln!(out, "不可达的函数：{}", unreachable.join(" ")).unwrap();
+        }
+        if self.cycles.is_empty() {
+            out.push_str("递归：无\n");
+        }
+        for cycle in &self.cycles {
+            let path: Vec<&str> = cycle.iter().map(|&f| self.names[f].as_str()).collect();
+            writeln!(out, "递归：{}", path.join(" -> ")).unwrap();
+        }
+        match self.depth {
+            Some(depth) => writeln!(out, "最大调用深度：{}", depth).unwrap(),
+            None => out.push_str("最大调用深度：有递归，没有静态上界\n"),
+        }
+        if let Some(stack) = self.main_stack() {
+            writeln!(out, "运行栈至少需要 {} 个单元（--stack-size）", stack).unwrap();
+        }
+        out
+    }
+
+    // 导出为 DOT：不可达的函数用虚线，递归的函数用红色，边上标出调用处的个数
+    pub fn write_dot(&self) -> String {
+        let mut out = String::from("digraph callgraph {\n  node [shape=box, fontname=\"monospace\"];\n");
+        for f in 0..self.names.len() {
+            let stack = self.stack[f].map_or("无上界".to_string(), |stack| stack.to_string());
+            let mut attrs = format!("label=\"{}\\n栈帧 {} 栈用量 {}\"", self.names[f], self.frame_sizes[f], stack);
+            if !self.reachable[f] {
+                attrs.push_str(", style=dashed");
+            }
+            if self.recursive[f] {
+                attrs.push_str(", color=red");
+            }
+            writeln!(out, "  f{} [{}];", f, attrs).unwrap();
+        }
+        for f in 0..self.names.len() {
+            for (callee, count) in self.callee_counts(f) {
+                // 被调函数能再调用回来的边在递归的环上
+                let color = if self.reach[callee][f] { ", color=red" } else { "" };
+                writeln!(out, "  f{} -> f{} [label=\"{}\"{}];", f, callee, count, color).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    // 按被调函数第一次出现的先后列出各被调函数和调用处的个数
+    fn callee_counts(&self, f: usize) -> Vec<(usize, usize)> {
+        let mut counts: Vec<(usize, usize)> = Vec::new();
+        for site in &self.sites[f] {
+            match counts.iter_mut().find(|(callee, _)| *callee == site.callee) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((site.callee, 1)),
+            }
+        }
+        counts
+    }
+}
+
+// 逐条指令推算函数内操作数栈的深度，返回最大深度和各调用处；
+// 汇合处按先到达的深度计算，这里只做估计，不检查代码是否正确
+fn walk(codes: &[Code], frames: &[FrameLayout], frame: &FrameLayout, returns: &[bool]) -> (usize, Vec<CallSite>) {
+    let (entry, end) = (frame.entry, frame.end);
+    let mut depths: Vec<Option<i64>> = vec![None; end - entry];
+    depths[0] = Some(0);
+    let mut work = vec![entry];
+    let mut peak = 0;
+    while let Some(address) = work.pop() {
+        let code = &codes[address];
+        let depth = depths[address - entry].unwrap();
+        // CAL 的返回值由被调函数是否带值返回决定
+        let (pops, pushes) = stack_effect(&code.opt, code.operand).unwrap_or((0, 0));
+        let mut after = depth - pops as i64 + pushes as i64;
+        if code.opt == "CAL" {
+            let callee = frames.iter().position(|frame| frame.entry == code.operand as usize);
+            after += callee.is_some_and(|callee| returns[callee]) as i64;
+        }
+        peak = peak.max(depth).max(after);
+        for next in cfg::successors(code, address) {
+            if !(entry..end).contains(&next) {
+                continue;
+            }
+            if depths[next - entry].is_none() {
+                depths[next - entry] = Some(after);
+                work.push(next);
+            }
+        }
+    }
+    let sites = (entry..end)
+        .filter(|&address| codes[address].opt == "CAL")
+        .filter_map(|address| {
+            let callee = frames.iter().position(|frame| frame.entry == codes[address].operand as usize)?;
+            let depth = depths[address - entry].unwrap_or(0).max(0) as usize;
+            Some(CallSite { address, callee, depth })
+        })
+        .collect();
+    (peak.max(0) as usize, sites)
+}
+
+// 从 start 出发沿调用关系回到 start 的最短路径
+fn shortest_cycle(start: usize, callees: &[Vec<usize>], reach: &[Vec<bool>]) -> Vec<usize> {
+    let mut previous = vec![None; callees.len()];
+    let mut seen = vec![false; callees.len()];
+    let mut queue = std::collections::VecDeque::from([start]);
+    seen[start] = true;
+    while let Some(f) = queue.pop_front() {
+        for &g in &callees[f] {
+            if g == start {
+                let mut cycle = vec![f];
+                let mut at = f;
+                while let Some(p) = previous[at] {
+                    cycle.push(p);
+                    at = p;
+                }
+                cycle.reverse();
+                cycle.push(start);
+                return cycle;
+            }
+            if !seen[g] && reach[g][start] {
+                seen[g] = true;
+                previous[g] = Some(f);
+                queue.push_back(g);
+            }
+        }
+    }
+    vec![start, start]
+}
+
+// 没有递归时从 f 开始的最大调用深度
+fn call_depth(f: usize, callees: &[Vec<usize>], memo: &mut Vec<Option<usize>>) -> usize {
+    if let Some(depth) = memo[f] {
+        return depth;
+    }
+    let depth = 1 + callees[f].iter().map(|&g| call_depth(g, callees, memo)).max().unwrap_or(0);
+    memo[f] = Some(depth);
+    depth
+}
@@ -0,0 +1,117 @@
+// 控制流图
+// 把一个函数的指令序列划分为基本块：函数入口、跳转目标以及跳转、返回、停机指令之后的指令
+// 都是基本块的首指令。每个基本块记录后继和前驱，可以导出为 Graphviz 的 DOT 格式
+use std::fmt::Write;
+
+use crate::syntax::Code;
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub start: usize,      // 第一条指令的地址
+    pub end: usize,        // 最后一条指令之后的地址
+    pub succs: Vec<usize>, // 后继基本块的编号
+    pub preds: Vec<usize>, // 前驱基本块的编号
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub function: String,
+    pub blocks: Vec<BasicBlock>, // 0 号基本块为函数入口
+}
+
+// 指令执行后可能到达的下一条指令；CAL 返回后继续执行下一条指令
+pub fn successors(code: &Code, address: usize) -> Vec<usize> {
+    match code.opt.as_str() {
+        "BR" => vec![code.operand as usize],
+        "BRF" => vec![address + 1, code.operand as usize],
+        "RETURN" | "HALT" => Vec::new(),
+        _ => vec![address + 1],
+    }
+}
+
+// 执行后不会顺序执行下一条指令的指令
+fn ends_block(code: &Code) -> bool {
+    matches!(code.opt.as_str(), "BR" | "BRF" | "RETURN" | "HALT")
+}
+
+impl Cfg {
+    // 为地址范围 entry..end 内的函数建立控制流图
+    pub fn build(codes: &[Code], function: &str, entry: usize, end: usize) -> Cfg {
+        let mut leader = vec![false; end - entry + 1];
+        leader[0] = true;
+        for (address, code) in codes.iter().enumerate().take(end).skip(entry) {
+            if matches!(code.opt.as_str(), "BR" | "BRF") {
+                let target = code.operand as usize;
+                if (entry..end).contains(&target) {
+                    leader[target - entry] = true;
+                }
+            }
+            if ends_block(code) {
+                leader[address + 1 - entry] = true;
+            }
+        }
+
+        let mut blocks = Vec::new();
+        let mut block_of = vec![0; end - entry];
+        for address in entry..end {
+            if leader[address - entry] {
+                blocks.push(BasicBlock { start: address, end: address + 1, succs: Vec::new(), preds: Vec::new() });
+            }
+            let id = blocks.len() - 1;
+            blocks[id].end = address + 1;
+            block_of[address - entry] = id;
+        }
+
+        for id in 0..blocks.len() {
+            let last = blocks[id].end - 1;
+            for next in successors(&codes[last], last) {
+                if !(entry..end).contains(&next) {
+                    continue;
+                }
+                let succ = block_of[next - entry];
+                if !blocks[id].succs.contains(&succ) {
+                    blocks[id].succs.push(succ);
+                    blocks[succ].preds.push(id);
+                }
+            }
+        }
+
+        Cfg { function: function.to_string(), blocks }
+    }
+
+    // 导出为 DOT 的子图，source_line 给出指令对应的源程序行号和该行的文字
+    pub fn write_dot(&self, out: &mut String, codes: &[Code], source_line: &dyn Fn(usize) -> Option<(usize, String)>) {
+        let name = &self.function;
+        writeln!(out, "  subgraph cluster_{} {{", name).unwrap();
+        writeln!(out, "    label=\"{}\";", escape(name)).unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        for (id, block) in self.blocks.iter().enumerate() {
+            let mut label = format!("B{}\\l", id);
+            let mut last_line = None;
+            for (address, code) in codes[block.start..block.end].iter().enumerate() {
+                let address = block.start + address;
+                // 源程序的行在该行第一条指令之前列出
+                if let Some((row, text)) = source_line(code.token) {
+                    if last_line != Some(row) {
+                        label.push_str(&escape(&format!("{:>3}| {}", row, text.trim())));
+                        label.push_str("\\l");
+                        last_line = Some(row);
+                    }
+                }
+                label.push_str(&escape(&format!("    {:3} {:<6} {}", address, code.opt, code.operand)));
+                label.push_str("\\l");
+            }
+            writeln!(out, "    {}_B{} [label=\"{}\"];", name, id, label).unwrap();
+        }
+        for (id, block) in self.blocks.iter().enumerate() {
+            for &succ in &block.succs {
+                writeln!(out, "    {}_B{} -> {}_B{};", name, id, name, succ).unwrap();
+            }
+        }
+        writeln!(out, "  }}").unwrap();
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,96 @@
+// 确定赋值分析
+// 在一个函数的栈式代码上做前向数据流分析：每条指令之前记录一定已经赋过值的栈帧单元，
+// 汇合处取各前驱的交集。形参在调用时已经赋值；STO 给单元赋值；
+// 按引用传出的变量（LOADA）可能由被调函数赋值，也当作已赋值；
+// STOR 经引用形参写的是调用者的变量，不是本函数的栈帧单元，不记录；
+// 块内变量在块的第一条指令处重新变为未赋值。LOAD 读取的单元不一定已赋值时给出警告
+use crate::cfg::successors;
+use crate::frame::{FrameLayout, SlotKind};
+use crate::syntax::Code;
+
+// 返回可能读取未赋值变量的指令地址和变量名
+pub fn uninitialized_reads(codes: &[Code], frame: &FrameLayout) -> Vec<(usize, String)> {
+    let size = frame.size.max(0) as usize;
+    let len = frame.end - frame.entry;
+
+    // states[i] 为第 entry + i 条指令之前一定已赋值的单元，None 表示还没有路径到达
+    let mut states: Vec<Option<Vec<bool>>> = vec![None; len];
+    let mut initial = vec![false; size];
+    for slot in &frame.slots {
+        if slot.kind != SlotKind::Local {
+            initial[slot.offset as usize] = true;
+        }
+    }
+    states[0] = Some(initial);
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in 0..len {
+            let Some(mut state) = states[i].clone() else {
+                continue;
+            };
+            let address = frame.entry + i;
+            transfer(&codes[address], address, frame, &mut state);
+            for next in successors(&codes[address], address) {
+                if next < frame.entry || next >= frame.end {
+                    continue;
+                }
+                let target = &mut states[next - frame.entry];
+                match target {
+                    None => {
+                        *target = Some(state.clone());
+                        changed = true;
+                    }
+                    Some(old) => {
+                        for (cell, &assigned) in old.iter_mut().zip(&state) {
+                            if *cell && !assigned {
+                                *cell = false;
+                                changed = true;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut reads = Vec::new();
+    for (i, state) in states.iter().enumerate() {
+        let address = frame.entry + i;
+        let code = &codes[address];
+        if code.opt != "LOAD" {
+            continue;
+        }
+        let Some(state) = state else {
+            continue; // 不可达的指令
+        };
+        let mut state = state.clone();
+        kill_block_locals(address, frame, &mut state);
+        let offset = code.operand;
+        if !state.get(offset as usize).copied().unwrap_or(true) {
+            if let Some(slot) = frame.slot_at(offset, address) {
+                reads.push((address, slot.name.clone()));
+            }
+        }
+    }
+    reads
+}
+
+// 进入块的第一条指令时，块内声明的变量还没有赋值
+fn kill_block_locals(address: usize, frame: &FrameLayout, state: &mut [bool]) {
+    for slot in &frame.slots {
+        if slot.kind == SlotKind::Local && slot.start == address {
+            state[slot.offset as usize] = false;
+        }
+    }
+}
+
+fn transfer(code: &Code, address: usize, frame: &FrameLayout, state: &mut [bool]) {
+    kill_block_locals(address, frame, state);
+    if code.opt == "STO" || code.opt == "LOADA" {
+        if let Some(cell) = state.get_mut(code.operand as usize) {
+            *cell = true;
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fmt;
+
+// 可以单独开关的警告
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WarningKind {
+    UnusedVariable,   // 声明后从未使用的变量
+    UnusedParameter,  // 从未使用的形参
+    UnusedFunction,   // 从 main 出发不会被调用的函数
+    UnreadVariable,   // 赋过值但从未读取的变量
+    ShadowParameter,  // 块内声明遮蔽了同名形参
+    Uninitialized,    // 变量可能在赋值之前被读取
+    DivisionByZero,   // 除数可能为 0
+    Overflow,         // 算术运算的结果可能超出 int 的范围
+}
+
+impl WarningKind {
+    pub const ALL: [WarningKind; 8] = [
+        WarningKind::UnusedVariable,
+        WarningKind::UnusedParameter,
+        WarningKind::UnusedFunction,
+        WarningKind::UnreadVariable,
+        WarningKind::ShadowParameter,
+        WarningKind::Uninitialized,
+        WarningKind::DivisionByZero,
+        WarningKind::Overflow,
+    ];
+
+    // 命令行中使用的名字
+    pub fn name(&self) -> &'static str {
+        match self {
+            WarningKind::UnusedVariable => "unused-variable",
+            WarningKind::UnusedParameter => "unused-parameter",
+            WarningKind::UnusedFunction => "unused-function",
+            WarningKind::UnreadVariable => "unread-variable",
+            WarningKind::ShadowParameter => "shadow-parameter",
+            WarningKind::Uninitialized => "uninitialized",
+            WarningKind::DivisionByZero => "division-by-zero",
+            WarningKind::Overflow => "overflow",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WarningKind> {
+        WarningKind::ALL.iter().copied().find(|kind| kind.name() == name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Allow, // 不报告
+    Warn,  // 作为警告报告
+    Deny,  // 作为错误报告，编译失败
+}
+
+// 各种警告的级别，默认除 overflow 外全部为警告。
+// overflow 对每个参数、读入的值都会报告，需要用 -Woverflow 打开
+#[derive(Debug, Clone)]
+pub struct WarningConfig {
+    levels: HashMap<WarningKind, Level>,
+}
+
+impl Default for WarningConfig {
+    fn default() -> Self {
+        WarningConfig {
+            levels: WarningKind::ALL
+                .iter()
+                .map(|&kind| (kind, if kind == WarningKind::Overflow { Level::Allow } else { Level::Warn }))
+                .collect(),
+        }
+    }
+}
+
+impl WarningConfig {
+    pub fn level(&self, kind: WarningKind) -> Level {
+        self.levels.get(&kind).copied().unwrap_or(Level::Warn)
+    }
+
+    // 解析 -W<名字>、-Wno-<名字>、-D<名字>，名字为 all 时作用于全部警告；
+    // 不是警告选项时返回 Ok(false)
+    pub fn apply_option(&mut self, arg: &str) -> Result<bool, String> {
+        let (name, level) = if let Some(name) = arg.strip_prefix("-Wno-") {
+            (name, Level::Allow)
+        } else if let Some(name) = arg.strip_prefix("-W") {
+            (name, Level::Warn)
+        } else if let Some(name) = arg.strip_prefix("-D") {
+            (name, Level::Deny)
+        } else {
+            return Ok(false);
+        };
+        if name == "all" {
+            for kind in WarningKind::ALL {
+                self.levels.insert(kind, level);
+            }
+        } else {
+            let kind = WarningKind::from_name(name).ok_or_else(|| format!("未知的警告 {}", name))?;
+            self.levels.insert(kind, level);
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Diagnostic {
+    pub kind: WarningKind,
+    pub level: Level,
+    pub message: String,
+    pub position: Option<(usize, usize)>, // 行、列
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = if self.level == Level::Deny { "错误" } else { "警告" };
+        write!(f, "{}[{}]：{}", label, self.kind.name(), self.message)?;
+        if let Some((row, col)) = self.position {
+            write!(f, "\t位置：第{}行第{}列", row, col)?;
+        }
+        Ok(())
+    }
+}
+
+// 编译过程中收集的警告
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    pub config: WarningConfig,
+    items: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    // 按配置的级别记录一条警告，被关闭的警告直接丢弃
+    pub fn report(&mut self, kind: WarningKind, position: Option<(usize, usize)>, message: String) {
+        let level = self.config.level(kind);
+        if level != Level::Allow {
+            self.items.push(Diagnostic { kind, level, message, position });
+        }
+    }
+
+    // 撤回某个位置上的一种警告，用于已经作为错误报告的情形
+    pub fn retract(&mut self, kind: WarningKind, position: Option<(usize, usize)>) {
+        self.items.retain(|item| item.kind != kind || item.position != position);
+    }
+
+    // 是否有被 -D 提升为错误的警告
+    pub fn has_errors(&self) -> bool {
+        self.items.iter().any(|item| item.level == Level::Deny)
+    }
+
+    pub fn items(&self) -> &[Diagnostic] {
+        &self.items
+    }
+}
@@ -0,0 +1,125 @@
+// 栈帧布局
+// 每个函数的栈帧从 base 开始：0 号单元保存主调函数的基地址，1 号单元保存返回地址，
+// 之后依次是形参和局部变量。复合语句中声明的变量只在块内存活，
+// 相互并列的块可以复用同一段单元，所以栈帧大小取分析过程中用到的最大单元数
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotKind {
+    SavedBase,
+    ReturnAddress,
+    Parameter,
+    Local,
+    Temp, // 四元式生成栈式代码时为临时变量分配的单元
+}
+
+#[derive(Debug, Clone)]
+pub struct Slot {
+    pub name: String,
+    pub offset: i32, // 相对基地址的偏移
+    pub kind: SlotKind,
+    pub start: usize, // 变量进入作用域处的指令地址，块内的变量从块的第一条指令开始存活
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameLayout {
+    pub function: String,
+    pub entry: usize, // 函数第一条指令的地址
+    pub end: usize,   // 函数最后一条指令之后的地址
+    pub size: i32,    // ENTER 开辟的单元数
+    pub slots: Vec<Slot>,
+}
+
+impl FrameLayout {
+    // 形参的个数
+    pub fn parameter_count(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.kind == SlotKind::Parameter).count()
+    }
+
+    // 执行到 address 处时偏移为 offset 的单元中存放的变量，即在此之前最后进入作用域的那个
+    pub fn slot_at(&self, offset: i32, address: usize) -> Option<&Slot> {
+        self.slots.iter()
+            .filter(|slot| slot.offset == offset && slot.start <= address)
+            .max_by_key(|slot| slot.start)
+    }
+
+    // 偏移为 offset 的单元的名字，被并列的块复用的单元会有多个名字
+    pub fn slot_name(&self, offset: i32) -> Option<String> {
+        let names: Vec<&str> = self.slots.iter()
+            .filter(|slot| slot.offset == offset)
+            .map(|slot| slot.name.as_str())
+            .collect();
+        if names.is_empty() {
+            None
+        } else {
+            Some(names.join("/"))
+        }
+    }
+}
+
+// 在语法分析的同时为当前函数分配栈帧单元
+#[derive(Debug, Default)]
+pub struct FrameBuilder {
+    entry: usize,
+    next: i32,         // 下一个空闲单元
+    size: i32,         // 目前用到的最大单元数
+    blocks: Vec<i32>,  // 各层块开始时的空闲单元，退出块时归还块内的单元
+    slots: Vec<Slot>,
+}
+
+impl FrameBuilder {
+    // 开始分析一个新函数，entry 为函数体的入口地址
+    pub fn begin(&mut self, entry: usize) {
+        self.entry = entry;
+        self.next = 2;
+        self.size = 2;
+        self.blocks.clear();
+        self.slots = vec![
+            Slot { name: "(base)".to_string(), offset: 0, kind: SlotKind::SavedBase, start: entry },
+            Slot { name: "(ret)".to_string(), offset: 1, kind: SlotKind::ReturnAddress, start: entry },
+        ];
+    }
+
+    // 为形参分配单元，返回它相对基地址的偏移
+    pub fn alloc_parameter(&mut self, name: &str) -> i32 {
+        self.alloc(name, SlotKind::Parameter, self.entry)
+    }
+
+    // 为局部变量分配单元，start 为变量进入作用域处的指令地址，返回它相对基地址的偏移
+    pub fn alloc_local(&mut self, name: &str, start: usize) -> i32 {
+        self.alloc(name, SlotKind::Local, start)
+    }
+
+    fn alloc(&mut self, name: &str, kind: SlotKind, start: usize) -> i32 {
+        let offset = self.next;
+        self.next += 1;
+        self.size = self.size.max(self.next);
+        self.slots.push(Slot { name: name.to_string(), offset, kind, start });
+        offset
+    }
+
+    pub fn enter_block(&mut self) {
+        self.blocks.push(self.next);
+    }
+
+    pub fn exit_block(&mut self) {
+        if let Some(next) = self.blocks.pop() {
+            self.next = next;
+        }
+    }
+
+    // 函数分析结束，end 为函数最后一条指令之后的地址
+    pub fn finish(&mut self, function: &str, end: usize) -> FrameLayout {
+        FrameLayout {
+            function: function.to_string(),
+            entry: self.entry,
+            end,
+            size: self.size,
+            slots: std::mem::take(&mut self.slots),
+        }
+    }
+}
+
+// 按指令地址找出所在函数的栈帧布局
+pub fn layout_at(frames: &[FrameLayout], ip: usize) -> Option<&FrameLayout> {
+    frames.iter().find(|frame| frame.entry <= ip && ip < frame.end)
+}
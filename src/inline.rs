@@ -0,0 +1,234 @@
+// 函数内联和尾递归消除
+// 内联把被调函数的四元式复制到调用处：实参赋给被调函数的形参，形参和局部变量
+// 在主调函数的栈帧末尾另开单元，临时变量和标号重新编号，ret 改为给调用结果赋值后跳到调用之后。
+// 尾递归消除把紧跟着 ret 的自身调用改为给形参赋值后跳回函数开头，递归再深也不再占用栈
+use crate::frame::{Slot, SlotKind};
+use crate::ir::{Function, Module, Operand, Quad, QuadOp};
+
+// 不计标号不超过这么多条四元式的函数才内联
+pub const INLINE_LIMIT: usize = 12;
+
+// 函数直接调用的函数
+pub fn callees(function: &Function) -> Vec<usize> {
+    let mut callees = Vec::new();
+    for quad in &function.quads {
+        if let (QuadOp::Call, Operand::Func(callee)) = (quad.op, quad.arg1) {
+            if !callees.contains(&callee) {
+                callees.push(callee);
+            }
+        }
+    }
+    callees
+}
+
+// 沿调用关系能回到自身的函数
+pub fn recursive_functions(module: &Module) -> Vec<bool> {
+    let graph: Vec<Vec<usize>> = module.functions.iter().map(callees).collect();
+    (0..graph.len())
+        .map(|start| {
+            let mut seen = vec![false; graph.len()];
+            let mut work = graph[start].clone();
+            while let Some(f) = work.pop() {
+                if f == start {
+                    return true;
+                }
+                if !seen[f] {
+                    seen[f] = true;
+                    work.extend(&graph[f]);
+                }
+            }
+            false
+        })
+        .collect()
+}
+
+fn size(function: &Function) -> usize {
+    function.quads.iter().filter(|quad| quad.op != QuadOp::Label).count()
+}
+
+// 调用之前紧挨着的 count 条 param 四元式的下标
+fn params_before(quads: &[Quad], call: usize, count: usize) -> Option<std::ops::Range<usize>> {
+    let start = call.checked_sub(count)?;
+    quads[start..call].iter().all(|quad| quad.op == QuadOp::Param).then_some(start..call)
+}
+
+fn call_count(quad: &Quad) -> usize {
+    match quad.arg2 {
+        Operand::Const(count) => count as usize,
+        _ => 0,
+    }
+}
+
+// 内联小的非递归函数，被调函数先于主调函数处理，它自己的调用已经内联过
+pub fn inline_calls(module: &mut Module, notes: &mut Vec<String>) {
+    let recursive = recursive_functions(module);
+    let mut order = Vec::new();
+    let mut visited = vec![false; module.functions.len()];
+    for start in 0..module.functions.len() {
+        post_order(module, start, &mut visited, &mut order);
+    }
+    for caller in order {
+        let inlinable = |callee: usize, module: &Module| {
+            callee != caller && !recursive[callee] && module.functions[callee].name() != "main"
+                && size(&module.functions[callee]) <= INLINE_LIMIT
+        };
+        let mut function = module.functions[caller].clone();
+        let mut quads = Vec::with_capacity(function.quads.len());
+        let mut position = Vec::with_capacity(function.quads.len() + 1);
+        let mut new_starts = Vec::new();
+        for (q, quad) in function.quads.iter().enumerate() {
+            position.push(quads.len());
+            let Quad { op: QuadOp::Call, arg1: Operand::Func(callee), .. } = *quad else {
+                quads.push(quad.clone());
+                continue;
+            };
+            let count = call_count(quad);
+            if !inlinable(callee, module) || params_before(&function.quads, q, count).is_none() {
+                quads.push(quad.clone());
+                continue;
+            }
+            let args: Vec<Operand> = quads.split_off(quads.len() - count).into_iter().map(|param| param.arg1).collect();
+            let body = &module.functions[callee];
+            let (offsets, temps, labels) = (function.frame.size - 2, function.temps, function.labels);
+            let end = Operand::Label(labels + body.labels);
+            let remap = |operand: Operand| match operand {
+                Operand::Var(offset) => Operand::Var(offset + offsets),
+                Operand::Temp(t) => Operand::Temp(t + temps),
+                Operand::Label(label) => Operand::Label(label + labels),
+                other => other,
+            };
+
+            let start = quads.len();
+            for (i, arg) in args.into_iter().enumerate() {
+                quads.push(Quad { op: QuadOp::Assign, arg1: arg, arg2: Operand::None, result: Operand::Var(2 + i as i32 + offsets), token: quad.token });
+            }
+            let mut body_position = Vec::with_capacity(body.quads.len() + 1);
+            let mut jumps_to_end = false;
+            for (k, inner) in body.quads.iter().enumerate() {
+                body_position.push(quads.len());
+                if inner.op == QuadOp::Return {
+                    if inner.arg1 != Operand::None && quad.result != Operand::None {
+                        quads.push(Quad { op: QuadOp::Assign, arg1: remap(inner.arg1), arg2: Operand::None, result: quad.result, token: inner.token });
+                    }
+                    if k + 1 < body.quads.len() {
+                        quads.push(Quad { op: QuadOp::Jump, arg1: Operand::None, arg2: Operand::None, result: end, token: inner.token });
+                        jumps_to_end = true;
+                    }
+                    continue;
+                }
+                quads.push(Quad { arg1: remap(inner.arg1), arg2: remap(inner.arg2), result: remap(inner.result), ..inner.clone() });
+            }
+            body_position.push(quads.len());
+            if jumps_to_end {
+                quads.push(Quad { op: QuadOp::Label, arg1: Operand::None, arg2: Operand::None, result: end, token: quad.token });
+            }
+
+            // 被调函数的形参和局部变量成为主调函数的局部变量
+            for (slot, &slot_start) in body.frame.slots.iter().zip(&body.starts) {
+                if matches!(slot.kind, SlotKind::Parameter | SlotKind::Local) {
+                    function.frame.slots.push(Slot {
+                        name: format!("{}.{}", body.name(), slot.name),
+                        offset: slot.offset + offsets,
+                        kind: SlotKind::Local,
+                        start: slot.start,
+                    });
+                    let at = if slot.kind == SlotKind::Parameter { start } else { body_position[slot_start.min(body.quads.len())] };
+                    new_starts.push(at);
+                }
+            }
+            function.frame.size += body.frame.size - 2;
+            function.temps += body.temps;
+            function.labels += body.labels + 1;
+            notes.push(format!("函数 {} 中内联 {}（{} 条四元式）", function.name(), body.name(), size(body)));
+        }
+        position.push(quads.len());
+        for start in &mut function.starts {
+            *start = position[(*start).min(position.len() - 1)];
+        }
+        function.starts.extend(new_starts);
+        function.quads = quads;
+        module.functions[caller] = function;
+    }
+}
+
+fn post_order(module: &Module, f: usize, visited: &mut [bool], order: &mut Vec<usize>) {
+    if visited[f] {
+        return;
+    }
+    visited[f] = true;
+    for callee in callees(&module.functions[f]) {
+        post_order(module, callee, visited, order);
+    }
+    order.push(f);
+}
+
+// 自身调用之后紧跟着返回它的结果（或无值返回）时，改为给形参赋值并跳回函数开头。
+// 取了自身变量地址的函数不处理：新一层调用的变量和上一层的不再是不同的单元
+pub fn eliminate_tail_calls(module: &mut Module, notes: &mut Vec<String>) {
+    for index in 0..module.functions.len() {
+        let function = &mut module.functions[index];
+        if function.name() == "main" || function.quads.iter().any(|quad| quad.op == QuadOp::AddrOf) {
+            continue;
+        }
+        let sites: Vec<usize> = (0..function.quads.len().saturating_sub(1))
+            .filter(|&q| {
+                let (call, ret) = (&function.quads[q], &function.quads[q + 1]);
+                call.op == QuadOp::Call && call.arg1 == Operand::Func(index)
+                    && ret.op == QuadOp::Return && ret.arg1 == call.result
+                    && params_before(&function.quads, q, call_count(call)).is_some()
+            })
+            .collect();
+        if sites.is_empty() {
+            continue;
+        }
+        let top = Operand::Label(function.labels);
+        function.labels += 1;
+        let mut quads = vec![Quad { op: QuadOp::Label, arg1: Operand::None, arg2: Operand::None, result: top, token: function.enter_token }];
+        let mut position = Vec::with_capacity(function.quads.len() + 1);
+        for (q, quad) in function.quads.iter().enumerate() {
+            position.push(quads.len());
+            if q > 0 && sites.contains(&(q - 1)) {
+                continue; // 尾调用紧跟着的 ret
+            }
+            if !sites.contains(&q) {
+                quads.push(quad.clone());
+                continue;
+            }
+            let count = call_count(quad);
+            let args: Vec<Operand> = quads.split_off(quads.len() - count).into_iter().map(|param| param.arg1).collect();
+            // 实参中的形参先复制到临时变量，以免被前面的赋值改掉
+            let mut values = Vec::with_capacity(count);
+            for (i, arg) in args.into_iter().enumerate() {
+                match arg {
+                    Operand::Var(offset) if (2..2 + count as i32).contains(&offset) && offset != 2 + i as i32 => {
+                        let temp = Operand::Temp(function.temps);
+                        function.temps += 1;
+                        quads.push(Quad { op: QuadOp::Assign, arg1: arg, arg2: Operand::None, result: temp, token: quad.token });
+                        values.push(temp);
+                    }
+                    _ => values.push(arg),
+                }
+            }
+            // 按临时变量定义的逆序赋值，生成栈式代码时它们都可以留在操作数栈上
+            let defined_at = |value: &Operand| match value {
+                Operand::Temp(_) => quads.iter().rposition(|quad| quad.result == *value),
+                _ => None,
+            };
+            let mut order: Vec<usize> = (0..count).collect();
+            order.sort_by_key(|&i| std::cmp::Reverse(defined_at(&values[i])));
+            for i in order {
+                let param = Operand::Var(2 + i as i32);
+                if values[i] != param {
+                    quads.push(Quad { op: QuadOp::Assign, arg1: values[i], arg2: Operand::None, result: param, token: quad.token });
+                }
+            }
+            quads.push(Quad { op: QuadOp::Jump, arg1: Operand::None, arg2: Operand::None, result: top, token: quad.token });
+        }
+        position.push(quads.len());
+        for start in &mut function.starts {
+            *start = position[(*start).min(position.len() - 1)];
+        }
+        function.quads = quads;
+        notes.push(format!("函数 {} 中 {} 处尾递归改为跳转", function.name(), sites.len()));
+    }
+}
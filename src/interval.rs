@@ -0,0 +1,472 @@
+// 区间分析
+// 在一个函数的栈式代码上做抽象解释：每条指令之前记录栈帧各单元和操作数栈上各个值的取值区间，
+// 汇合处取区间的并，循环开头更新几次之后还在变化就把变化的一端放宽到 int 的边界，保证分析能结束。
+// 条件跳转按比较的结果收窄两个分支上变量的区间，所以 if (d != 0) 之内的 d 不会被当作可能为 0。
+// 分析结束后检查每条算术指令：除数的区间包含 0，或者结果的区间超出 int 时给出警告
+use std::fmt;
+
+use crate::cfg::successors;
+use crate::frame::{FrameLayout, SlotKind};
+use crate::syntax::Code;
+
+const MIN: i64 = i32::MIN as i64;
+const MAX: i64 = i32::MAX as i64;
+
+// 循环开头的状态更新这么多次之后开始放宽
+const WIDEN_AFTER: usize = 3;
+
+// 取值区间 [lo, hi]；nonzero 表示区间虽然包含 0 但已知不为 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub lo: i64,
+    pub hi: i64,
+    pub nonzero: bool,
+}
+
+impl Range {
+    pub const ANY: Range = Range { lo: MIN, hi: MAX, nonzero: false };
+    const BOOL: Range = Range { lo: 0, hi: 1, nonzero: false };
+
+    pub fn constant(value: i64) -> Range {
+        Range { lo: value, hi: value, nonzero: false }
+    }
+
+    fn excludes_zero(&self) -> bool {
+        self.nonzero || self.lo > 0 || self.hi < 0
+    }
+
+    pub fn may_be_zero(&self) -> bool {
+        !self.excludes_zero()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lo > self.hi
+    }
+
+    // nonzero 只在区间包含 0 时才有意义
+    fn normalize(self) -> Range {
+        Range { nonzero: self.nonzero && self.lo <= 0 && self.hi >= 0, ..self }
+    }
+
+    fn union(&self, other: &Range) -> Range {
+        Range {
+            lo: self.lo.min(other.lo),
+            hi: self.hi.max(other.hi),
+            nonzero: self.excludes_zero() && other.excludes_zero(),
+        }.normalize()
+    }
+
+    // 与上一次的区间相比，变小的下界放宽到 int 的最小值，变大的上界放宽到最大值
+    fn widen(&self, next: &Range) -> Range {
+        Range {
+            lo: if next.lo < self.lo { MIN } else { self.lo },
+            hi: if next.hi > self.hi { MAX } else { self.hi },
+            nonzero: self.excludes_zero() && next.excludes_zero(),
+        }.normalize()
+    }
+
+    // 截到 int 的范围之内，超出的部分在运行时已经出错
+    fn clamp(&self) -> Range {
+        Range { lo: self.lo.clamp(MIN, MAX), hi: self.hi.clamp(MIN, MAX), nonzero: self.nonzero }
+    }
+
+    fn overflows(&self) -> bool {
+        self.lo < MIN || self.hi > MAX
+    }
+
+    // 已知 self op other 成立时 self 的区间
+    fn refine(&self, op: &str, other: &Range) -> Range {
+        let mut range = *self;
+        match op {
+            "LES" => range.hi = range.hi.min(other.hi - 1),
+            "LE" => range.hi = range.hi.min(other.hi),
+            "GT" => range.lo = range.lo.max(other.lo + 1),
+            "GE" => range.lo = range.lo.max(other.lo),
+            "EQ" => {
+                range.lo = range.lo.max(other.lo);
+                range.hi = range.hi.min(other.hi);
+                range.nonzero |= other.excludes_zero();
+            }
+            "NOTEQ" if other.lo == other.hi => {
+                if range.lo == other.lo {
+                    range.lo += 1;
+                }
+                if range.hi == other.lo {
+                    range.hi -= 1;
+                }
+                range.nonzero |= other.lo == 0;
+            }
+            _ => {}
+        }
+        range.normalize()
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.lo == self.hi {
+            write!(f, "{}", self.lo)
+        } else if self.lo == MIN && self.hi == MAX {
+            write!(f, "任意整数")
+        } else {
+            write!(f, "[{}, {}]", self.lo, self.hi)?;
+            if self.nonzero {
+                write!(f, " 且不为 0")?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// 比较的一边：取值区间，以及它是否是直接从某个栈帧单元取出的
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Side {
+    cell: Option<i32>,
+    range: Range,
+}
+
+impl Side {
+    fn union(&self, other: &Side) -> Side {
+        Side { cell: self.cell, range: self.range.union(&other.range) }
+    }
+}
+
+// 操作数栈上的值；比较的结果记下比较的两边，供条件跳转收窄变量的区间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Value {
+    range: Range,
+    cell: Option<i32>,
+    compare: Option<(Side, &'static str, Side)>,
+}
+
+impl Value {
+    fn of(range: Range) -> Value {
+        Value { range, cell: None, compare: None }
+    }
+
+    fn union(&self, other: &Value) -> Value {
+        Value {
+            range: self.range.union(&other.range),
+            cell: if self.cell == other.cell { self.cell } else { None },
+            compare: match (self.compare, other.compare) {
+                (Some((l1, op1, r1)), Some((l2, op2, r2))) if op1 == op2 && l1.cell == l2.cell && r1.cell == r2.cell => {
+                    Some((l1.union(&l2), op1, r1.union(&r2)))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    fn widen(&self, next: &Value) -> Value {
+        Value { range: self.range.widen(&next.range), ..self.union(next) }
+    }
+
+    fn refers_to(&self, offset: i32) -> bool {
+        self.cell == Some(offset)
+            || self.compare.is_some_and(|(left, _, right)| left.cell == Some(offset) || right.cell == Some(offset))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    cells: Vec<Range>,
+    stack: Vec<Value>,
+}
+
+impl State {
+    fn combine(&self, next: &State, widen: bool) -> State {
+        let cells = self.cells.iter().zip(&next.cells)
+            .map(|(old, new)| if widen { old.widen(new) } else { old.union(new) })
+            .collect();
+        let stack = self.stack.iter().zip(&next.stack)
+            .map(|(old, new)| if widen { old.widen(new) } else { old.union(new) })
+            .collect();
+        State { cells, stack }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().unwrap_or(Value::of(Range::ANY))
+    }
+
+    fn store(&mut self, offset: i32, range: Range) {
+        for value in &mut self.stack {
+            if value.refers_to(offset) {
+                *value = Value::of(value.range);
+            }
+        }
+        if let Some(cell) = self.cells.get_mut(offset as usize) {
+            *cell = range;
+        }
+    }
+
+    // 已知 left op right 成立，收窄两边的变量；不可能成立时返回 false
+    fn assume(&mut self, left: Side, op: &'static str, right: Side) -> bool {
+        for (side, op, other) in [(left, op, right), (right, flip(op), left)] {
+            let Some(offset) = side.cell else {
+                continue;
+            };
+            if let Some(cell) = self.cells.get_mut(offset as usize) {
+                *cell = cell.refine(op, &other.range);
+                if cell.is_empty() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
+
+// a op b 等价于 b flip(op) a
+fn flip(op: &'static str) -> &'static str {
+    match op {
+        "LES" => "GT",
+        "LE" => "GE",
+        "GT" => "LES",
+        "GE" => "LE",
+        other => other,
+    }
+}
+
+// 比较结果取反
+fn negate(op: &'static str) -> &'static str {
+    match op {
+        "LES" => "GE",
+        "LE" => "GT",
+        "GT" => "LE",
+        "GE" => "LES",
+        "EQ" => "NOTEQ",
+        "NOTEQ" => "EQ",
+        other => other,
+    }
+}
+
+fn comparison(opt: &str) -> Option<&'static str> {
+    ["EQ", "NOTEQ", "GT", "LES", "GE", "LE"].into_iter().find(|&op| op == opt)
+}
+
+// 算术运算结果的区间，不截到 int 的范围；除法不计除数为 0 的情况
+pub fn arithmetic(opt: &str, a: &Range, b: &Range) -> Range {
+    let span = |values: &[i64]| Range {
+        lo: *values.iter().min().unwrap(),
+        hi: *values.iter().max().unwrap(),
+        nonzero: false,
+    };
+    match opt {
+        "ADD" => Range { lo: a.lo + b.lo, hi: a.hi + b.hi, nonzero: false },
+        "SUB" => Range { lo: a.lo - b.hi, hi: a.hi - b.lo, nonzero: false },
+        "MULT" => span(&[a.lo * b.lo, a.lo * b.hi, a.hi * b.lo, a.hi * b.hi]),
+        "DIV" => {
+            // 除数分成负数和正数两段，每一段上商随被除数和除数单调变化
+            let mut quotients = Vec::new();
+            for (lo, hi) in [(b.lo, b.hi.min(-1)), (b.lo.max(1), b.hi)] {
+                if lo <= hi {
+                    for divisor in [lo, hi] {
+                        quotients.extend([a.lo / divisor, a.hi / divisor]);
+                    }
+                }
+            }
+            if quotients.is_empty() {
+                Range::ANY // 除数只能是 0
+            } else {
+                span(&quotients)
+            }
+        }
+        _ => Range::ANY,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hazard {
+    DivisionByZero,
+    Overflow,
+}
+
+// 返回可能除以 0 或溢出的指令地址、问题的种类和说明
+pub fn arithmetic_hazards(codes: &[Code], frame: &FrameLayout, frames: &[FrameLayout]) -> Vec<(usize, Hazard, String)> {
+    let states = analyze(codes, frame, frames);
+    let mut hazards = Vec::new();
+    for (i, state) in states.iter().enumerate() {
+        let address = frame.entry + i;
+        let opt = codes[address].opt.as_str();
+        if !matches!(opt, "ADD" | "SUB" | "MULT" | "DIV") {
+            continue;
+        }
+        let Some(state) = state else {
+            continue; // 不可达的指令
+        };
+        let mut state = state.clone();
+        let b = state.pop();
+        let a = state.pop();
+        if opt == "DIV" && b.range.may_be_zero() {
+            let divisor = match b.cell.and_then(|offset| frame.slot_at(offset, address)) {
+                Some(slot) => format!("除数 {} ", slot.name),
+                None => "除数".to_string(),
+            };
+            let message = if b.range == Range::constant(0) {
+                format!("{}为 0", divisor)
+            } else {
+                format!("{}的取值范围 {} 包含 0", divisor, b.range)
+            };
+            hazards.push((address, Hazard::DivisionByZero, message));
+        }
+        if arithmetic(opt, &a.range, &b.range).overflows() {
+            let (name, symbol) = match opt {
+                "ADD" => ("加法", "+"),
+                "SUB" => ("减法", "-"),
+                _ => ("乘法", "*"),
+            };
+            let message = if opt == "DIV" {
+                // 除法只有 int 的最小值除以 -1 会溢出
+                format!("除法的结果可能超出 int 的范围：被除数 {} 可能是 {}，除数 {} 可能是 -1", a.range, MIN, b.range)
+            } else {
+                format!("{}的结果可能超出 int 的范围：{} {} {}", name, a.range, symbol, b.range)
+            };
+            hazards.push((address, Hazard::Overflow, message));
+        }
+    }
+    hazards
+}
+
+// 求出每条指令之前的状态，None 表示到达不了
+fn analyze(codes: &[Code], frame: &FrameLayout, frames: &[FrameLayout]) -> Vec<Option<State>> {
+    let len = frame.end - frame.entry;
+    let size = frame.size.max(0) as usize;
+    // 形参由调用者传入，局部变量在赋值之前是运行栈上残留的值，都可能是任意整数
+    let mut states: Vec<Option<State>> = vec![None; len];
+    states[0] = Some(State { cells: vec![Range::ANY; size], stack: Vec::new() });
+    let mut updates = vec![0; len];
+    // 只在循环的开头放宽：向回跳转的目标
+    let mut heads = vec![false; len];
+    for (address, code) in codes.iter().enumerate().take(frame.end).skip(frame.entry) {
+        if matches!(code.opt.as_str(), "BR" | "BRF") && (frame.entry..=address).contains(&(code.operand as usize)) {
+            heads[code.operand as usize - frame.entry] = true;
+        }
+    }
+    let mut work = vec![frame.entry];
+    while let Some(address) = work.pop() {
+        let Some(mut state) = states[address - frame.entry].clone() else {
+            continue;
+        };
+        let code = &codes[address];
+        let mut branches = Vec::new();
+        transfer(code, address, frame, frames, codes, &mut state, &mut branches);
+        for (next, state) in branches {
+            if next < frame.entry || next >= frame.end {
+                continue;
+            }
+            let i = next - frame.entry;
+            let merged = match &states[i] {
+                None => state,
+                Some(old) => {
+                    let merged = old.combine(&state, heads[i] && updates[i] >= WIDEN_AFTER);
+                    if merged == *old {
+                        continue;
+                    }
+                    merged
+                }
+            };
+            states[i] = Some(merged);
+            updates[i] += 1;
+            work.push(next);
+        }
+    }
+    states
+}
+
+// 执行一条指令，把到达各后继时的状态放进 branches
+fn transfer(code: &Code, address: usize, frame: &FrameLayout, frames: &[FrameLayout], codes: &[Code],
+            state: &mut State, branches: &mut Vec<(usize, State)>) {
+    // 块内的变量在块的第一条指令处还没有赋值
+    for slot in &frame.slots {
+        if slot.kind == SlotKind::Local && slot.start == address {
+            state.store(slot.offset, Range::ANY);
+        }
+    }
+    let operand = code.operand;
+    match code.opt.as_str() {
+        "LOAD" => {
+            let range = state.cells.get(operand as usize).copied().unwrap_or(Range::ANY);
+            state.stack.push(Value { range, cell: Some(operand), compare: None });
+        }
+        "LOADI" => state.stack.push(Value::of(Range::constant(operand as i64))),
+        "LOADA" => {
+            // 变量按引用传给被调函数，调用之后可能是任意值
+            state.store(operand, Range::ANY);
+            state.stack.push(Value::of(Range::ANY));
+        }
+        "LOADR" | "IN" => state.stack.push(Value::of(Range::ANY)),
+        "STO" => {
+            let value = state.pop();
+            state.store(operand, value.range);
+        }
+        "DUP" => {
+            let value = state.stack.last().copied().unwrap_or(Value::of(Range::ANY));
+            state.stack.push(value);
+        }
+        "STOR" | "OUT" | "OUTC" | "OUTN" | "POP" | "PAS" => {
+            state.pop();
+        }
+        "ADD" | "SUB" | "MULT" | "DIV" => {
+            let b = state.pop();
+            let a = state.pop();
+            let range = arithmetic(&code.opt, &a.range, &b.range).clamp();
+            state.stack.push(Value::of(range));
+        }
+        "AND" | "OR" => {
+            state.pop();
+            state.pop();
+            state.stack.push(Value::of(Range::BOOL));
+        }
+        "NOT" => {
+            let value = state.pop();
+            let compare = match (value.compare, value.cell) {
+                (Some((left, op, right)), _) => Some((left, negate(op), right)),
+                (None, Some(_)) => Some((Side { cell: value.cell, range: value.range }, "EQ", Side { cell: None, range: Range::constant(0) })),
+                (None, None) => None,
+            };
+            state.stack.push(Value { range: Range::BOOL, cell: None, compare });
+        }
+        "CAL" => {
+            let returns = frames.iter().chain(std::iter::once(frame))
+                .find(|callee| callee.entry == operand as usize)
+                .is_some_and(|callee| codes[callee.entry..callee.end.min(codes.len())].iter().any(|code| code.opt == "RETURN" && code.operand != 0));
+            if returns {
+                state.stack.push(Value::of(Range::ANY));
+            }
+        }
+        "BRF" => {
+            let value = state.pop();
+            let mut taken = state.clone(); // 条件为假，跳转
+            let mut fallthrough = state.clone();
+            let (jump, next) = match (value.compare, value.cell) {
+                (Some((left, op, right)), _) => (taken.assume(left, negate(op), right), fallthrough.assume(left, op, right)),
+                (None, Some(offset)) => {
+                    let zero = Side { cell: None, range: Range::constant(0) };
+                    let cell = Side { cell: Some(offset), range: value.range };
+                    (taken.assume(cell, "EQ", zero), fallthrough.assume(cell, "NOTEQ", zero))
+                }
+                (None, None) => (true, true),
+            };
+            // 条件一定成立或一定不成立时只有一个分支可走
+            if jump && !value.range.excludes_zero() {
+                branches.push((operand as usize, taken));
+            }
+            if next && value.range != Range::constant(0) {
+                branches.push((address + 1, fallthrough));
+            }
+            return;
+        }
+        "RETURN" | "HALT" => return,
+        opt => {
+            if let Some(op) = comparison(opt) {
+                let right = state.pop();
+                let left = state.pop();
+                let side = |value: Value| Side { cell: value.cell, range: value.range };
+                state.stack.push(Value { range: Range::BOOL, cell: None, compare: Some((side(left), op, side(right))) });
+            }
+        }
+    }
+    for next in successors(code, address) {
+        branches.push((next, state.clone()));
+    }
+}
@@ -0,0 +1,955 @@
+// 四元式中间表示
+// 语法分析生成的栈式代码先翻译为四元式 (op, arg1, arg2, result)：操作数栈上的中间结果改为临时变量，
+// 跳转目标改为标号。-O1 以上时，各优化遍使用的栈式代码再由四元式生成。
+//
+// 四元式不是语法分析和代码生成之间的中间层，而是从生成好的栈式代码提升得到：语法分析仍是一遍完成的
+// 递归下降加回填，行号表、栈帧布局和各项语义检查都建立在栈式代码上，提升只读取这些结果，
+// 不必在语法分析中维护两套代码。-O0 没有优化遍，不做这一往返，输出的仍是语法分析生成的代码，
+// 只有 --emit-ir 时才翻译。栈式代码无法翻译时返回错误，由编译器报告错误 55，不会中止编译器
+//
+// 翻译时用一个符号栈模拟操作数栈：LOAD、LOADI 只把变量、常量压入符号栈，不生成四元式，
+// 运算指令从符号栈取出操作数，生成一条结果为新临时变量的四元式。符号栈中的变量在被改写之前
+// （STO、STOR、CAL）先复制到临时变量，复制的四元式放在原来 LOAD 的位置；
+// 基本块结束时符号栈中剩下的值存入按栈深度分配的临时变量，供后继基本块使用。
+//
+// 生成栈式代码时，只定义一次、在同一基本块中只使用一次的临时变量不占用栈帧单元：
+// 定义它的四元式推迟到使用处生成，值留在操作数栈上，于是表达式重新生成为原来的栈式代码
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fmt::Write as FmtWrite;
+
+use crate::cfg::Cfg;
+use crate::frame::{FrameLayout, Slot, SlotKind};
+use crate::syntax::Code;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    None,
+    Const(i32),
+    Var(i32),     // 栈帧中偏移为 n 的变量
+    Temp(usize),  // 临时变量
+    Label(usize), // 标号
+    Func(usize),  // 函数在 Module::functions 中的下标
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuadOp {
+    Assign,    // result = arg1
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Eq,
+    NotEq,
+    Gt,
+    Less,
+    Ge,
+    Le,
+    And,
+    Or,
+    Not,       // result = !arg1
+    AddrOf,    // result = &arg1
+    LoadRef,   // result = *arg1，arg1 中保存变量的地址
+    StoreRef,  // *result = arg1
+    Jump,      // 跳到 result
+    JumpFalse, // arg1 为假时跳到 result
+    Label,     // 标号 result
+    Param,     // 传入实参 arg1
+    Call,      // result = arg1(前面 arg2 个实参)，被调函数没有返回值时 result 为空
+    Return,    // 返回 arg1，arg1 为空时没有返回值
+    Halt,      // 以 arg1 为退出码结束程序
+    Read,      // result = 读入的整数
+    Write,     // 输出 arg1
+    WriteChar,
+    WriteNum,
+    WriteStr,  // 输出常量池中第 arg1 个字符串
+}
+
+impl QuadOp {
+    // 二元运算对应的指令
+    fn from_binary(opt: &str) -> Option<QuadOp> {
+        Some(match opt {
+            "ADD" => QuadOp::Add,
+            "SUB" => QuadOp::Sub,
+            "MULT" => QuadOp::Mul,
+            "DIV" => QuadOp::Div,
+            "EQ" => QuadOp::Eq,
+            "NOTEQ" => QuadOp::NotEq,
+            "GT" => QuadOp::Gt,
+            "LES" => QuadOp::Less,
+            "GE" => QuadOp::Ge,
+            "LE" => QuadOp::Le,
+            "AND" => QuadOp::And,
+            "OR" => QuadOp::Or,
+            _ => return None,
+        })
+    }
+
+    pub fn is_binary(self) -> bool {
+        matches!(self, QuadOp::Add | QuadOp::Sub | QuadOp::Mul | QuadOp::Div | QuadOp::Eq | QuadOp::NotEq
+            | QuadOp::Gt | QuadOp::Less | QuadOp::Ge | QuadOp::Le | QuadOp::And | QuadOp::Or)
+    }
+
+    // 执行后不会顺序执行下一条四元式
+    pub fn ends_block(self) -> bool {
+        matches!(self, QuadOp::Jump | QuadOp::JumpFalse | QuadOp::Return | QuadOp::Halt)
+    }
+
+    // 生成栈式代码时使用的指令，Assign、Label、Param、Call 没有对应的单条指令
+    pub fn opcode(self) -> &'static str {
+        match self {
+            QuadOp::Add => "ADD",
+            QuadOp::Sub => "SUB",
+            QuadOp::Mul => "MULT",
+            QuadOp::Div => "DIV",
+            QuadOp::Eq => "EQ",
+            QuadOp::NotEq => "NOTEQ",
+            QuadOp::Gt => "GT",
+            QuadOp::Less => "LES",
+            QuadOp::Ge => "GE",
+            QuadOp::Le => "LE",
+            QuadOp::And => "AND",
+            QuadOp::Or => "OR",
+            QuadOp::Not => "NOT",
+            QuadOp::AddrOf => "LOADA",
+            QuadOp::LoadRef => "LOADR",
+            QuadOp::StoreRef => "STOR",
+            QuadOp::Jump => "BR",
+            QuadOp::JumpFalse => "BRF",
+            QuadOp::Return => "RETURN",
+            QuadOp::Halt => "HALT",
+            QuadOp::Read => "IN",
+            QuadOp::Write => "OUT",
+            QuadOp::WriteChar => "OUTC",
+            QuadOp::WriteNum => "OUTN",
+            QuadOp::WriteStr => "OUTS",
+            QuadOp::Assign | QuadOp::Label | QuadOp::Param | QuadOp::Call => "",
+        }
+    }
+}
+
+impl fmt::Display for QuadOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            QuadOp::Assign => "=",
+            QuadOp::Add => "+",
+            QuadOp::Sub => "-",
+            QuadOp::Mul => "*",
+            QuadOp::Div => "/",
+            QuadOp::Eq => "==",
+            QuadOp::NotEq => "!=",
+            QuadOp::Gt => ">",
+            QuadOp::Less => "<",
+            QuadOp::Ge => ">=",
+            QuadOp::Le => "<=",
+            QuadOp::And => "&&",
+            QuadOp::Or => "||",
+            QuadOp::Not => "!",
+            QuadOp::AddrOf => "&",
+            QuadOp::LoadRef => "=*",
+            QuadOp::StoreRef => "*=",
+            QuadOp::Jump => "j",
+            QuadOp::JumpFalse => "jf",
+            QuadOp::Label => "label",
+            QuadOp::Param => "param",
+            QuadOp::Call => "call",
+            QuadOp::Return => "ret",
+            QuadOp::Halt => "halt",
+            QuadOp::Read => "in",
+            QuadOp::Write => "out",
+            QuadOp::WriteChar => "outc",
+            QuadOp::WriteNum => "outn",
+            QuadOp::WriteStr => "outs",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Quad {
+    pub op: QuadOp,
+    pub arg1: Operand,
+    pub arg2: Operand,
+    pub result: Operand,
+    pub token: usize, // 生成这条四元式的单词在单词流中的下标
+}
+
+impl Quad {
+    fn new(op: QuadOp, arg1: Operand, arg2: Operand, result: Operand, token: usize) -> Quad {
+        Quad { op, arg1, arg2, result, token }
+    }
+
+    // 按求值顺序排列的取值操作数；AddrOf、LoadRef 的 arg1 是变量本身而不是它的值
+    pub fn value_operands(&self) -> Vec<Operand> {
+        match self.op {
+            QuadOp::Assign | QuadOp::Not | QuadOp::StoreRef | QuadOp::JumpFalse | QuadOp::Param
+            | QuadOp::Return | QuadOp::Halt | QuadOp::Write | QuadOp::WriteChar | QuadOp::WriteNum => {
+                vec![self.arg1].into_iter().filter(|arg| *arg != Operand::None).collect()
+            }
+            op if op.is_binary() => vec![self.arg1, self.arg2],
+            _ => Vec::new(),
+        }
+    }
+
+    // 结果为临时变量时返回它的编号
+    pub fn defined_temp(&self) -> Option<usize> {
+        match (self.op, self.result) {
+            (QuadOp::StoreRef, _) => None,
+            (_, Operand::Temp(t)) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub frame: FrameLayout,
+    pub quads: Vec<Quad>,
+    pub temps: usize,          // 临时变量的个数
+    pub labels: usize,         // 标号的个数
+    pub starts: Vec<usize>,    // frame.slots 中各单元进入作用域处的四元式下标
+    pub enter_token: usize,    // 函数入口 ENTER 指令对应的单词
+}
+
+impl Function {
+    pub fn name(&self) -> &str {
+        &self.frame.function
+    }
+
+    // 第 q 条四元式处偏移为 offset 的变量的名字
+    fn var_name(&self, offset: i32, q: usize) -> String {
+        self.frame.slots.iter().zip(&self.starts)
+            .filter(|(slot, &start)| slot.offset == offset && start <= q)
+            .max_by_key(|(_, &start)| start)
+            .map(|(slot, _)| slot.name.clone())
+            .unwrap_or_else(|| format!("[{}]", offset))
+    }
+
+    fn operand_text(&self, operand: Operand, q: usize, module: &Module) -> String {
+        match operand {
+            Operand::None => "_".to_string(),
+            Operand::Const(c) => c.to_string(),
+            Operand::Var(offset) => self.var_name(offset, q),
+            Operand::Temp(t) => format!("t{}", t),
+            Operand::Label(l) => format!("L{}", l),
+            Operand::Func(f) => module.functions[f].name().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Module {
+    pub functions: Vec<Function>,
+    pub start_token: usize, // 0 号单元跳到 main 的 BR 对应的单词
+}
+
+// 符号栈中的一项：pos 为它被压入时函数中已有的四元式条数，addr 为压入它的指令地址
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    operand: Operand,
+    pos: usize,
+    addr: usize,
+    token: usize,
+}
+
+// 把一个函数的栈式代码翻译为四元式
+struct Builder<'a> {
+    codes: &'a [Code],
+    frames: &'a [FrameLayout],
+    returns: &'a [bool],
+    frame: &'a FrameLayout,
+    quads: Vec<Quad>,
+    stack: Vec<Entry>,
+    temps: usize,
+    joins: HashMap<(usize, usize), usize>, // 各汇合点、各栈深度上跨基本块传递值的临时变量
+    groups: Vec<usize>,          // 各基本块所属的汇合点：有共同前驱的基本块属于同一汇合点
+    exit_group: usize,           // 当前基本块的后继所属的汇合点
+    labels: HashMap<usize, usize>, // 跳转目标地址对应的标号
+    positions: Vec<usize>,       // 各指令翻译出的第一条四元式的下标
+    params: usize,               // CAL 之前连续的 PAS 条数
+}
+
+impl<'a> Builder<'a> {
+    fn new_temp(&mut self) -> usize {
+        self.temps += 1;
+        self.temps - 1
+    }
+
+    fn join_temp(&mut self, group: usize, depth: usize) -> usize {
+        if let Some(&t) = self.joins.get(&(group, depth)) {
+            return t;
+        }
+        let t = self.new_temp();
+        self.joins.insert((group, depth), t);
+        t
+    }
+
+    fn label(&mut self, address: usize) -> usize {
+        let next = self.labels.len();
+        *self.labels.entry(address).or_insert(next)
+    }
+
+    fn pop(&mut self) -> Result<Entry, String> {
+        self.stack.pop().ok_or_else(|| "栈式代码的操作数栈下溢".to_string())
+    }
+
+    fn push_temp(&mut self, op: QuadOp, arg1: Operand, arg2: Operand, token: usize, addr: usize) {
+        let t = self.new_temp();
+        let pos = self.quads.len();
+        self.quads.push(Quad::new(op, arg1, arg2, Operand::Temp(t), token));
+        self.stack.push(Entry { operand: Operand::Temp(t), pos, addr, token });
+    }
+
+    // 把符号栈第 i 项中的变量复制到临时变量 t，复制的四元式插在压入该变量的位置
+    fn materialize(&mut self, i: usize, t: usize) {
+        let entry = self.stack[i];
+        self.quads.insert(entry.pos, Quad::new(QuadOp::Assign, entry.operand, Operand::None, Operand::Temp(t), entry.token));
+        for (j, other) in self.stack.iter_mut().enumerate() {
+            if j != i && (other.pos > entry.pos || (other.pos == entry.pos && other.addr > entry.addr)) {
+                other.pos += 1;
+            }
+        }
+        let entry_index = entry.addr - self.frame.entry;
+        for pos in self.positions.iter_mut().skip(entry_index + 1) {
+            if *pos >= entry.pos {
+                *pos += 1;
+            }
+        }
+        self.stack[i].operand = Operand::Temp(t);
+    }
+
+    // 变量即将被改写：符号栈中满足条件的变量先复制到临时变量
+    fn materialize_vars(&mut self, affected: impl Fn(i32) -> bool) {
+        for i in 0..self.stack.len() {
+            if let Operand::Var(offset) = self.stack[i].operand {
+                if affected(offset) {
+                    let t = self.new_temp();
+                    self.materialize(i, t);
+                }
+            }
+        }
+    }
+
+    // 基本块结束：符号栈中剩下的值存入各深度的临时变量
+    fn spill(&mut self) {
+        for depth in 0..self.stack.len() {
+            let t = self.join_temp(self.exit_group, depth);
+            let entry = self.stack[depth];
+            match entry.operand {
+                Operand::Var(_) => self.materialize(depth, t),
+                operand if operand != Operand::Temp(t) => {
+                    self.quads.push(Quad::new(QuadOp::Assign, operand, Operand::None, Operand::Temp(t), entry.token));
+                    self.stack[depth].operand = Operand::Temp(t);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // 栈式代码不合规范（操作数栈下溢、CAL 的目标不是函数入口、未知指令）时返回说明
+    fn translate(&mut self, address: usize) -> Result<(), String> {
+        let code = &self.codes[address];
+        let token = code.token;
+        let operand = code.operand;
+        let pos = self.quads.len();
+        match code.opt.as_str() {
+            "LOAD" => self.stack.push(Entry { operand: Operand::Var(operand), pos, addr: address, token }),
+            "LOADI" => self.stack.push(Entry { operand: Operand::Const(operand), pos, addr: address, token }),
+            "LOADA" => self.push_temp(QuadOp::AddrOf, Operand::Var(operand), Operand::None, token, address),
+            "LOADR" => self.push_temp(QuadOp::LoadRef, Operand::Var(operand), Operand::None, token, address),
+            "IN" => self.push_temp(QuadOp::Read, Operand::None, Operand::None, token, address),
+            "STO" => {
+                let value = self.pop()?;
+                self.materialize_vars(|offset| offset == operand);
+                self.quads.push(Quad::new(QuadOp::Assign, value.operand, Operand::None, Operand::Var(operand), token));
+            }
+            "STOR" => {
+                let value = self.pop()?;
+                self.materialize_vars(|_| true); // 引用可能指向任何变量
+                self.quads.push(Quad::new(QuadOp::StoreRef, value.operand, Operand::None, Operand::Var(operand), token));
+            }
+            "NOT" => {
+                let value = self.pop()?;
+                self.push_temp(QuadOp::Not, value.operand, Operand::None, token, address);
+            }
+            "DUP" => {
+                let top = *self.stack.last().ok_or_else(|| "栈式代码的操作数栈下溢".to_string())?;
+                self.stack.push(top);
+            }
+            "POP" => {
+                self.pop()?;
+            }
+            "OUT" | "OUTC" | "OUTN" => {
+                let value = self.pop()?;
+                let op = match code.opt.as_str() {
+                    "OUT" => QuadOp::Write,
+                    "OUTC" => QuadOp::WriteChar,
+                    _ => QuadOp::WriteNum,
+                };
+                self.quads.push(Quad::new(op, value.operand, Operand::None, Operand::None, token));
+            }
+            "OUTS" => self.quads.push(Quad::new(QuadOp::WriteStr, Operand::Const(operand), Operand::None, Operand::None, token)),
+            "ENTER" => {}
+            "PAS" => self.params += 1,
+            "CAL" => {
+                let count = std::mem::take(&mut self.params);
+                let first = self.stack.len().checked_sub(count).ok_or_else(|| "栈式代码的操作数栈下溢".to_string())?;
+                let args = self.stack.split_off(first);
+                self.materialize_vars(|_| true); // 被调函数可能经引用形参改写变量
+                for arg in &args {
+                    self.quads.push(Quad::new(QuadOp::Param, arg.operand, Operand::None, Operand::None, token));
+                }
+                let callee = self.frames.iter().position(|frame| frame.entry == operand as usize)
+                    .ok_or_else(|| format!("第 {} 条指令 CAL {} 的目标不是函数入口", address, operand))?;
+                let result = if self.returns[callee] { Operand::Temp(self.new_temp()) } else { Operand::None };
+                let pos = self.quads.len();
+                self.quads.push(Quad::new(QuadOp::Call, Operand::Func(callee), Operand::Const(count as i32), result, token));
+                if result != Operand::None {
+                    self.stack.push(Entry { operand: result, pos, addr: address, token });
+                }
+            }
+            "BR" => {
+                self.spill();
+                let label = self.label(operand as usize);
+                self.quads.push(Quad::new(QuadOp::Jump, Operand::None, Operand::None, Operand::Label(label), token));
+            }
+            "BRF" => {
+                let value = self.pop()?;
+                self.spill();
+                let label = self.label(operand as usize);
+                self.quads.push(Quad::new(QuadOp::JumpFalse, value.operand, Operand::None, Operand::Label(label), token));
+            }
+            "RETURN" => {
+                let value = if operand != 0 { self.pop()?.operand } else { Operand::None };
+                self.quads.push(Quad::new(QuadOp::Return, value, Operand::None, Operand::None, token));
+            }
+            "HALT" => {
+                let value = self.pop()?;
+                self.quads.push(Quad::new(QuadOp::Halt, value.operand, Operand::None, Operand::None, token));
+            }
+            opt => match QuadOp::from_binary(opt) {
+                Some(op) => {
+                    let right = self.pop()?;
+                    let left = self.pop()?;
+                    self.push_temp(op, left.operand, right.operand, token, address);
+                }
+                None => return Err(format!("第 {} 条指令 {} 无法翻译为四元式", address, opt)),
+            },
+        }
+        Ok(())
+    }
+
+    // 每个基本块入口处操作数栈的深度，到达不了的基本块为 None
+    fn block_depths(&self, cfg: &Cfg) -> Vec<Option<usize>> {
+        let mut depths = vec![None; cfg.blocks.len()];
+        depths[0] = Some(0);
+        let mut work = vec![0];
+        while let Some(id) = work.pop() {
+            let block = &cfg.blocks[id];
+            let mut depth = depths[id].unwrap() as i64;
+            let mut params = 0;
+            for code in &self.codes[block.start..block.end] {
+                depth += match code.opt.as_str() {
+                    "LOAD" | "LOADI" | "LOADA" | "LOADR" | "IN" | "DUP" => 1,
+                    "STO" | "STOR" | "OUT" | "OUTC" | "OUTN" | "POP" | "BRF" | "HALT" => -1,
+                    "PAS" => {
+                        params += 1;
+                        0
+                    }
+                    "CAL" => {
+                        let callee = self.frames.iter().position(|frame| frame.entry == code.operand as usize);
+                        let value = callee.is_some_and(|callee| self.returns[callee]) as i64;
+                        value - std::mem::take(&mut params)
+                    }
+                    "RETURN" => -((code.operand != 0) as i64),
+                    opt if QuadOp::from_binary(opt).is_some() => -1,
+                    _ => 0,
+                };
+            }
+            for &succ in &block.succs {
+                if depths[succ].is_none() {
+                    depths[succ] = Some(depth.max(0) as usize);
+                    work.push(succ);
+                }
+            }
+        }
+        depths
+    }
+
+    fn build(mut self) -> Result<Function, String> {
+        let frame = self.frame;
+        let cfg = Cfg::build(self.codes, &frame.function, frame.entry, frame.end);
+        let depths = self.block_depths(&cfg);
+        self.positions = vec![0; frame.end - frame.entry];
+        let targets: HashSet<usize> = self.codes[frame.entry..frame.end].iter()
+            .filter(|code| matches!(code.opt.as_str(), "BR" | "BRF"))
+            .map(|code| code.operand as usize)
+            .collect();
+
+        // 同一基本块的各后继从同一组临时变量中取得栈上的值
+        let mut groups: Vec<usize> = (0..cfg.blocks.len()).collect();
+        fn find(groups: &mut [usize], id: usize) -> usize {
+            let mut root = id;
+            while groups[root] != root {
+                root = groups[root];
+            }
+            groups[id] = root;
+            root
+        }
+        for block in &cfg.blocks {
+            for pair in block.succs.windows(2) {
+                let (a, b) = (find(&mut groups, pair[0]), find(&mut groups, pair[1]));
+                groups[a] = b;
+            }
+        }
+        self.groups = (0..cfg.blocks.len()).map(|id| find(&mut groups, id)).collect();
+
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            self.exit_group = block.succs.first().map_or(id, |&succ| self.groups[succ]);
+            let Some(depth) = depths[id] else {
+                // 到达不了的基本块不翻译
+                for address in block.start..block.end {
+                    self.positions[address - frame.entry] = self.quads.len();
+                }
+                continue;
+            };
+            if targets.contains(&block.start) {
+                let label = self.label(block.start);
+                self.quads.push(Quad::new(QuadOp::Label, Operand::None, Operand::None, Operand::Label(label), self.codes[block.start].token));
+            }
+            self.stack.clear();
+            for d in 0..depth {
+                let t = self.join_temp(self.groups[id], d);
+                let pos = self.quads.len();
+                self.stack.push(Entry { operand: Operand::Temp(t), pos, addr: block.start, token: self.codes[block.start].token });
+            }
+            for address in block.start..block.end {
+                self.positions[address - frame.entry] = self.quads.len();
+                self.translate(address)?;
+            }
+            let last = &self.codes[block.end - 1];
+            if !matches!(last.opt.as_str(), "BR" | "BRF" | "RETURN" | "HALT") {
+                self.spill();
+            }
+        }
+
+        let starts = frame.slots.iter()
+            .map(|slot| self.positions.get(slot.start.wrapping_sub(frame.entry)).copied().unwrap_or(self.quads.len()))
+            .collect();
+        Ok(Function {
+            frame: frame.clone(),
+            quads: self.quads,
+            temps: self.temps,
+            labels: self.labels.len(),
+            starts,
+            enter_token: self.codes[frame.entry].token,
+        })
+    }
+}
+
+// 推迟生成的四元式，children 为它的操作数中同样被推迟、值留在操作数栈上的四元式
+struct Node {
+    quad: usize,
+    children: Vec<Node>,
+    calls: bool,            // 其中是否有函数调用
+    on_stack: Option<usize>, // 不是四元式，而是基本块入口处已经在操作数栈上的临时变量
+}
+
+impl Node {
+    // 这一项留在操作数栈上的值所属的临时变量
+    fn temp(&self, quads: &[Quad]) -> Option<usize> {
+        self.on_stack.or_else(|| quads[self.quad].defined_temp())
+    }
+}
+
+// 把一个函数的四元式生成为栈式代码
+struct Lowerer<'a> {
+    function: &'a Function,
+    codes: &'a mut Vec<Code>,
+    calls: &'a mut Vec<(usize, usize)>, // 待回填的 CAL：指令地址、被调函数
+    uses: Vec<usize>,
+    single: Vec<bool>,                  // 只定义一次、只在同一基本块中稍后使用一次的临时变量
+    resident: Vec<Option<usize>>,       // 留在操作数栈上跨过基本块的临时变量，以及值所到达的标号
+    slots: HashMap<usize, i32>,         // 需要栈帧单元的临时变量
+    size: i32,
+    labels: Vec<usize>,
+    jumps: Vec<(usize, usize)>,         // 待回填的跳转：指令地址、标号
+    addresses: Vec<usize>,              // 各四元式开始生成时的指令地址
+    pending: Vec<Node>,
+    param_children: VecDeque<Option<Node>>, // 同一次调用中后面几个 Param 的操作数
+}
+
+impl<'a> Lowerer<'a> {
+    fn new(function: &'a Function, codes: &'a mut Vec<Code>, calls: &'a mut Vec<(usize, usize)>) -> Lowerer<'a> {
+        let quads = &function.quads;
+        let mut defs = vec![Vec::new(); function.temps];
+        let mut uses = vec![Vec::new(); function.temps];
+        let mut blocks = Vec::with_capacity(quads.len());
+        let mut block = 0;
+        for (q, quad) in quads.iter().enumerate() {
+            if quad.op == QuadOp::Label {
+                block += 1;
+            }
+            blocks.push(block);
+            if quad.op.ends_block() {
+                block += 1;
+            }
+            for operand in quad.value_operands() {
+                if let Operand::Temp(t) = operand {
+                    uses[t].push(q);
+                }
+            }
+            if let Some(t) = quad.defined_temp() {
+                defs[t].push(q);
+            }
+        }
+        let single = (0..function.temps)
+            .map(|t| defs[t].len() == 1 && uses[t].len() == 1
+                && defs[t][0] < uses[t][0] && blocks[defs[t][0]] == blocks[uses[t][0]])
+            .collect();
+
+        // 跨基本块传递的临时变量（如条件表达式的值）在下面的条件下不存入栈帧，直接留在操作数栈上：
+        // 每个定义之后紧接着跳到（或顺序进入）同一个标号，进入该标号只有这些路径，
+        // 并且它只在该标号开始的基本块中使用一次
+        let mut label_at = HashMap::new();
+        for (q, quad) in quads.iter().enumerate() {
+            if let (QuadOp::Label, Operand::Label(label)) = (quad.op, quad.result) {
+                label_at.insert(label, q);
+            }
+        }
+        let mut resident = vec![None; function.temps];
+        for t in 0..function.temps {
+            if defs[t].is_empty() || uses[t].len() != 1 {
+                continue;
+            }
+            let targets: Vec<Option<usize>> = defs[t].iter()
+                .map(|&d| match quads.get(d + 1) {
+                    Some(next) if quads[d].op == QuadOp::Assign && matches!(next.op, QuadOp::Jump | QuadOp::Label) => match next.result {
+                        Operand::Label(label) => Some(label),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect();
+            let Some(label) = targets[0] else { continue };
+            if targets.iter().any(|&target| target != Some(label)) {
+                continue;
+            }
+            let at = label_at[&label];
+            let entered_only_from_defs = at > 0
+                && (quads[at - 1].op.ends_block() || defs[t].contains(&(at - 1)))
+                && quads.iter().enumerate().all(|(q, quad)| {
+                    !matches!(quad.op, QuadOp::Jump | QuadOp::JumpFalse)
+                        || quad.result != Operand::Label(label)
+                        || (quad.op == QuadOp::Jump && q > 0 && defs[t].contains(&(q - 1)))
+                });
+            let used_in_block = uses[t][0] > at && blocks[uses[t][0]] == blocks[at];
+            if entered_only_from_defs && used_in_block {
+                resident[t] = Some(label);
+            }
+        }
+        Lowerer {
+            function,
+            codes,
+            calls,
+            uses: uses.iter().map(Vec::len).collect(),
+            single,
+            resident,
+            slots: HashMap::new(),
+            size: function.frame.size,
+            labels: vec![0; function.labels],
+            jumps: Vec::new(),
+            addresses: Vec::with_capacity(quads.len()),
+            pending: Vec::new(),
+            param_children: VecDeque::new(),
+        }
+    }
+
+    fn emit(&mut self, opt: &str, operand: i32, token: usize) -> usize {
+        self.codes.push(Code { opt: opt.to_string(), operand, token });
+        self.codes.len() - 1
+    }
+
+    fn slot(&mut self, t: usize) -> i32 {
+        if let Some(&offset) = self.slots.get(&t) {
+            return offset;
+        }
+        let offset = self.size;
+        self.size += 1;
+        self.slots.insert(t, offset);
+        offset
+    }
+
+    fn load(&mut self, operand: Operand, token: usize) {
+        match operand {
+            Operand::Const(c) => {
+                self.emit("LOADI", c, token);
+            }
+            Operand::Var(offset) => {
+                self.emit("LOAD", offset, token);
+            }
+            Operand::Temp(t) => {
+                let offset = self.slot(t);
+                self.emit("LOAD", offset, token);
+            }
+            _ => {}
+        }
+    }
+
+    // 按求值顺序生成四元式的操作数，推迟的操作数就地生成；as_value 为真时结果留在操作数栈上
+    fn emit_node(&mut self, node: Node, as_value: bool) {
+        let function = self.function;
+        if let Some(t) = node.on_stack {
+            if !as_value {
+                let offset = self.slot(t); // 不能留在栈上时再存入栈帧
+                self.emit("STO", offset, function.quads[node.quad].token);
+            }
+            return;
+        }
+        let quad = &function.quads[node.quad];
+        let token = quad.token;
+        let mut children = node.children.into_iter().peekable();
+        for operand in quad.value_operands() {
+            let child = children.next_if(|child| child.temp(&function.quads).map(Operand::Temp) == Some(operand));
+            match child {
+                Some(child) => self.emit_node(child, true),
+                None => self.load(operand, token),
+            }
+        }
+        match quad.op {
+            QuadOp::Call => {
+                for child in children {
+                    self.emit_node(child, true); // 实参
+                }
+                let Operand::Const(count) = quad.arg2 else { unreachable!() };
+                for _ in 0..count {
+                    self.emit("PAS", 0, token);
+                }
+                let Operand::Func(callee) = quad.arg1 else { unreachable!() };
+                let address = self.emit("CAL", 0, token);
+                self.calls.push((address, callee));
+            }
+            QuadOp::Assign | QuadOp::Param => {}
+            QuadOp::AddrOf | QuadOp::LoadRef | QuadOp::StoreRef => {
+                let Operand::Var(offset) = (if quad.op == QuadOp::StoreRef { quad.result } else { quad.arg1 }) else { unreachable!() };
+                self.emit(quad.op.opcode(), offset, token);
+            }
+            QuadOp::Jump | QuadOp::JumpFalse => {
+                let Operand::Label(label) = quad.result else { unreachable!() };
+                let address = self.emit(quad.op.opcode(), 0, token);
+                self.jumps.push((address, label));
+            }
+            QuadOp::Return => {
+                self.emit("RETURN", (quad.arg1 != Operand::None) as i32, token);
+            }
+            QuadOp::WriteStr => {
+                let Operand::Const(index) = quad.arg1 else { unreachable!() };
+                self.emit("OUTS", index, token);
+            }
+            QuadOp::Label => {}
+            op => {
+                self.emit(op.opcode(), 0, token);
+            }
+        }
+        if as_value {
+            return;
+        }
+        match quad.result {
+            Operand::Var(offset) if quad.op == QuadOp::Assign => {
+                self.emit("STO", offset, token);
+            }
+            Operand::Temp(t) if quad.op != QuadOp::StoreRef => {
+                if self.resident[t].is_some() {
+                    // 值留在操作数栈上带到标号处
+                } else if self.uses[t] == 0 {
+                    self.emit("POP", 0, token); // 结果没有被使用
+                } else {
+                    let offset = self.slot(t);
+                    self.emit("STO", offset, token);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn flush(&mut self) {
+        for node in std::mem::take(&mut self.pending) {
+            self.emit_node(node, false);
+        }
+    }
+
+    // 从推迟的四元式中取出 q 的操作数：它们必须恰好是 pending 末尾的几项，且顺序一致
+    fn take_children(&mut self, q: usize) -> Vec<Node> {
+        let quads = &self.function.quads;
+        let quad = &quads[q];
+        match quad.op {
+            QuadOp::Call => {
+                // 实参就是 pending 末尾的 Param
+                let Operand::Const(count) = quad.arg2 else { unreachable!() };
+                let count = count as usize;
+                let tail = self.pending.len().saturating_sub(count);
+                if self.pending.len() < count || self.pending[tail..].iter().any(|node| quads[node.quad].op != QuadOp::Param) {
+                    return Vec::new();
+                }
+                self.pending.split_off(tail)
+            }
+            QuadOp::Param => {
+                // 一次调用的各实参依次求值，连续的 Param 一起从 pending 中取出操作数
+                if q == 0 || quads[q - 1].op != QuadOp::Param {
+                    let run: Vec<usize> = (q..quads.len()).take_while(|&p| quads[p].op == QuadOp::Param).collect();
+                    let operands = run.iter().flat_map(|&p| quads[p].value_operands()).collect();
+                    let mut children = self.take(operands).into_iter().peekable();
+                    self.param_children = run.iter()
+                        .map(|&p| children.next_if(|child| child.temp(quads).map(Operand::Temp) == Some(quads[p].arg1)))
+                        .collect();
+                }
+                self.param_children.pop_front().flatten().into_iter().collect()
+            }
+            _ => self.take(quad.value_operands()),
+        }
+    }
+
+    // 取出依次求值的操作数 operands 中被推迟的四元式
+    fn take(&mut self, operands: Vec<Operand>) -> Vec<Node> {
+        let quads = &self.function.quads;
+        let mut wanted = Vec::new();
+        let mut loaded_var = false; // 之前的操作数中是否有直接读取的变量
+        for operand in operands {
+            let node = self.pending.iter().find(|node| node.temp(quads).map(Operand::Temp) == Some(operand));
+            match node {
+                // 推迟的函数调用可能改写已经读取的变量，不能放到变量之后
+                Some(node) if !(loaded_var && node.calls) => wanted.push(node.quad),
+                Some(_) => return Vec::new(),
+                None => loaded_var |= matches!(operand, Operand::Var(_)),
+            }
+        }
+        let tail = self.pending.len().saturating_sub(wanted.len());
+        let matches = self.pending.len() >= wanted.len()
+            && self.pending[tail..].iter().map(|node| node.quad).eq(wanted.iter().copied());
+        if matches {
+            self.pending.split_off(tail)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn run(mut self) -> FrameLayout {
+        let function = self.function;
+        let entry = self.codes.len();
+        let enter = self.emit("ENTER", 0, function.enter_token);
+        for (q, quad) in function.quads.iter().enumerate() {
+            self.addresses.push(self.codes.len());
+            if quad.op == QuadOp::Label {
+                self.flush();
+                let Operand::Label(label) = quad.result else { unreachable!() };
+                self.labels[label] = self.codes.len();
+                for t in 0..function.temps {
+                    if self.resident[t] == Some(label) {
+                        self.pending.push(Node { quad: q, children: Vec::new(), calls: false, on_stack: Some(t) });
+                    }
+                }
+                continue;
+            }
+            let children = self.take_children(q);
+            let calls = quad.op == QuadOp::Call || children.iter().any(|child| child.calls);
+            let node = Node { quad: q, children, calls, on_stack: None };
+            let deferred = quad.op == QuadOp::Param || quad.defined_temp().is_some_and(|t| self.single[t]);
+            if deferred {
+                self.pending.push(node);
+            } else {
+                self.flush();
+                self.emit_node(node, false);
+            }
+            if quad.op.ends_block() {
+                self.flush();
+            }
+        }
+        self.flush();
+        let end = self.codes.len();
+
+        for &(address, label) in &self.jumps {
+            self.codes[address].operand = self.labels[label] as i32;
+        }
+        self.codes[enter].operand = self.size;
+
+        let mut slots: Vec<Slot> = function.frame.slots.iter().zip(&function.starts)
+            .map(|(slot, &start)| Slot { start: self.addresses.get(start).copied().unwrap_or(end), ..slot.clone() })
+            .collect();
+        let mut temps: Vec<(usize, i32)> = self.slots.iter().map(|(&t, &offset)| (t, offset)).collect();
+        temps.sort_by_key(|&(_, offset)| offset);
+        for (t, offset) in temps {
+            slots.push(Slot { name: format!("t{}", t), offset, kind: SlotKind::Temp, start: entry });
+        }
+        FrameLayout { function: function.frame.function.clone(), entry, end, size: self.size, slots }
+    }
+}
+
+impl Module {
+    // 把语法分析生成的栈式代码翻译为四元式，frames 为各函数的栈帧布局；
+    // 栈式代码无法翻译时返回说明，由调用者报告编译错误
+    pub fn build(codes: &[Code], frames: &[FrameLayout]) -> Result<Module, String> {
+        let returns: Vec<bool> = frames.iter()
+            .map(|frame| codes[frame.entry..frame.end].iter().any(|code| code.opt == "RETURN" && code.operand != 0))
+            .collect();
+        let functions = frames.iter()
+            .map(|frame| Builder {
+                codes,
+                frames,
+                returns: &returns,
+                frame,
+                quads: Vec::new(),
+                stack: Vec::new(),
+                temps: 0,
+                joins: HashMap::new(),
+                groups: Vec::new(),
+                exit_group: 0,
+                labels: HashMap::new(),
+                positions: Vec::new(),
+                params: 0,
+            }.build())
+            .collect::<Result<_, _>>()?;
+        Ok(Module { functions, start_token: codes.first().map_or(0, |code| code.token) })
+    }
+
+    // 生成栈式代码和各函数新的栈帧布局；0 号单元仍是跳到 main 的 BR
+    pub fn lower(&self) -> (Vec<Code>, Vec<FrameLayout>) {
+        let mut codes = vec![Code { opt: "BR".to_string(), operand: 0, token: self.start_token }];
+        let mut calls = Vec::new();
+        let mut frames = Vec::new();
+        for function in &self.functions {
+            frames.push(Lowerer::new(function, &mut codes, &mut calls).run());
+        }
+        for (address, callee) in calls {
+            codes[address].operand = frames[callee].entry as i32;
+        }
+        if let Some(main) = frames.iter().find(|frame| frame.function == "main") {
+            codes[0].operand = main.entry as i32;
+        }
+        (codes, frames)
+    }
+
+    // 文本形式的四元式
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        for function in &self.functions {
+            writeln!(out, "function {}\t栈帧大小 {}\t临时变量 {}", function.name(), function.frame.size, function.temps).unwrap();
+            for (q, quad) in function.quads.iter().enumerate() {
+                if quad.op == QuadOp::Label {
+                    writeln!(out, "{}:", function.operand_text(quad.result, q, self)).unwrap();
+                    continue;
+                }
+                writeln!(
+                    out,
+                    " {:3}  ({}, {}, {}, {})",
+                    q,
+                    quad.op,
+                    function.operand_text(quad.arg1, q, self),
+                    function.operand_text(quad.arg2, q, self),
+                    function.operand_text(quad.result, q, self)
+                ).unwrap();
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
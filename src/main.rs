@@ -2,8 +2,21 @@ use std::fs::File;
 use std::path::Path;
 use std::io::{self, Read, Write, Seek, SeekFrom};
 mod word_analysis;
+mod symbol_table;
+mod frame;
+mod diagnostics;
+mod dataflow;
+mod interval;
+mod cfg;
+mod callgraph;
+mod ir;
+mod ssa;
+mod inline;
+mod optimize;
 mod syntax;
+mod bytecode;
 mod virtual_machine;
+mod verifier;
 
 
 
@@ -20,7 +33,111 @@ mod virtual_machine;
 
 
 
+// 命令行选项：资源上限用 --max-symbols=N、--max-codes=N、--stack-size=N 修改，
+// 警告用 -W<名字> 打开、-Wno-<名字> 关闭、-D<名字> 当作错误，
+// --poison-stack 让虚拟机在读取未赋值的局部变量时报错，--emit-cfg 输出各函数的控制流图，--emit-ir 输出四元式，
+// --emit-callgraph 输出调用图以及各函数最坏情况下的栈用量，
+// -O0、-O1、-O2、-O3 选择优化级别（默认 -O0），--print-after=<优化遍> 在该优化遍之后输出中间代码，
+// --run=<二进制文件> 不编译，直接校验并运行已有的字节码文件
+struct Options {
+    max_symbols: usize,
+    max_codes: usize,
+    stack_size: usize,
+    poison: bool,
+    emit_cfg: bool,
+    emit_ir: bool,
+    emit_callgraph: bool,
+    opt_level: u32,
+    print_after: Vec<String>,
+    opt_stats: bool,
+    warnings: diagnostics::WarningConfig,
+    run: Option<String>,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut limits = Options {
+        max_symbols: syntax::MAX_SYMBOL_INDEX,
+        max_codes: syntax::MAX_CODE_INDEX,
+        stack_size: virtual_machine::STACK_SIZE,
+        poison: false,
+        emit_cfg: false,
+        emit_ir: false,
+        emit_callgraph: false,
+        opt_level: optimize::DEFAULT_LEVEL,
+        print_after: Vec::new(),
+        opt_stats: false,
+        warnings: diagnostics::WarningConfig::default(),
+        run: None,
+    };
+    for arg in std::env::args().skip(1) {
+        if limits.warnings.apply_option(&arg)? {
+            continue;
+        }
+        if arg == "--emit-cfg" {
+            limits.emit_cfg = true; // 输出控制流图
+            continue;
+        }
+        if arg == "--emit-ir" {
+            limits.emit_ir = true; // 输出四元式
+            continue;
+        }
+        if arg == "--emit-callgraph" {
+            limits.emit_callgraph = true; // 输出调用图
+            continue;
+        }
+        if arg == "--opt-stats" {
+            limits.opt_stats = true; // 输出各优化遍前后的代码长度
+            continue;
+        }
+        if arg == "--poison-stack" {
+            limits.poison = true; // 虚拟机调试模式
+            continue;
+        }
+        if let Some(level) = arg.strip_prefix("-O") {
+            limits.opt_level = level.parse().ok().filter(|&level| level <= optimize::MAX_LEVEL)
+                .ok_or_else(|| format!("优化级别必须是 0 到 {}", optimize::MAX_LEVEL))?;
+            continue;
+        }
+        if let Some(binary) = arg.strip_prefix("--run=") {
+            limits.run = Some(binary.to_string()); // 运行已有的字节码文件
+            continue;
+        }
+        if let Some(pass) = arg.strip_prefix("--print-after=") {
+            if !optimize::PASS_NAMES.contains(&pass) {
+                return Err(format!("未知的优化遍 {}", pass));
+            }
+            limits.print_after.push(pass.to_string());
+            continue;
+        }
+        let (name, value) = arg.split_once('=').ok_or_else(|| format!("无法识别的参数 {}", arg))?;
+        let value: usize = value.parse().ok().filter(|&v| v > 0)
+            .ok_or_else(|| format!("参数 {} 的值必须是正整数", name))?;
+        match name {
+            "--max-symbols" => limits.max_symbols = value,
+            "--max-codes" => limits.max_codes = value,
+            "--stack-size" => limits.stack_size = value,
+            _ => return Err(format!("无法识别的参数 {}", name)),
+        }
+    }
+    Ok(limits)
+}
+
 fn main() -> io::Result<()> {
+    let limits = match parse_options() {
+        Ok(limits) => limits,
+        Err(e) => {
+            println!("{}", e);
+            println!("用法：rust_compiler [--max-symbols=N] [--max-codes=N] [--stack-size=N] [--poison-stack] [--emit-cfg] [--emit-ir] [--emit-callgraph] [-O0|-O1|-O2|-O3] [--print-after=<优化遍>] [--opt-stats] [-W<警告>] [-Wno-<警告>] [-D<警告>] [--run=<二进制文件>]");
+            println!("警告：all {}", diagnostics::WarningKind::ALL.map(|kind| kind.name()).join(" "));
+            println!("优化遍：{}", optimize::PASS_NAMES.join(" "));
+            std::process::exit(2);
+        }
+    };
+
+    if let Some(binary) = &limits.run {
+        std::process::exit(run_binary(binary, limits.stack_size, limits.poison));
+    }
+
     let mut input_filepath = String::new();
     println!("请输入文件路径：");
 
@@ -56,8 +173,21 @@ fn main() -> io::Result<()> {
 
     let mut compiler = syntax::Compiler::new();
     compiler.set_tokenfile(output_word);
+    compiler.set_sourcefile(input_filepath.to_string());
     compiler.set_codeout(output_syntax_readable);
     compiler.set_codeout2(output_syntax_binary.clone());
+    compiler.set_max_symbols(limits.max_symbols);
+    compiler.set_max_codes(limits.max_codes);
+    compiler.set_warnings(limits.warnings);
+    let mut passes = optimize::PassManager::for_level(limits.opt_level);
+    for pass in &limits.print_after {
+        passes.print_after(pass);
+    }
+    if limits.opt_stats {
+        passes.report_stats();
+    }
+    compiler.set_passes(passes);
+    compiler.set_emit_ir(limits.emit_ir);
 
     let es = compiler.test_parse();
 
@@ -66,19 +196,64 @@ fn main() -> io::Result<()> {
     } else {
         println!("错误代码：{}", es);
         println!("语法、语义分析并生成代码错误!");
+        std::process::exit(1); // 编译失败时不运行虚拟机
     }
 
-    // 虚拟机部分
-    match virtual_machine::read_codes(&output_syntax_binary){
-        Ok(codes) => {
-            let map = virtual_machine::init_map();
-            // virtual_machine::display_codes(&codes);  // 显示中间代码
-            virtual_machine::test_machine(&codes, &map);  // 执行虚拟机
+    if limits.emit_ir {
+        let output_ir = input_filepath.replace(file_name, &format!("{}_ir", file_name));
+        match compiler.write_ir(&output_ir) {
+            Ok(()) => println!("四元式已写入 {}", output_ir),
+            Err(e) => println!("写入 {} 错误!{}", output_ir, e),
+        }
+    }
+
+    if limits.emit_cfg {
+        let output_cfg = input_filepath.replace(file_name, &format!("{}_cfg", file_name)).replace(".txt", ".dot");
+        match compiler.write_cfg_dot(&output_cfg) {
+            Ok(()) => println!("控制流图已写入 {}", output_cfg),
+            Err(e) => println!("写入 {} 错误!{}", output_cfg, e),
         }
-        Err(e) => println!("Error reading codes: {}", e),
     }
 
-    Ok(())
+    if limits.emit_callgraph {
+        let output_calls = input_filepath.replace(file_name, &format!("{}_callgraph", file_name));
+        let output_calls_dot = output_calls.replace(".txt", ".dot");
+        match compiler.write_call_graph(&output_calls, &output_calls_dot) {
+            Ok(()) => println!("调用图已写入 {} 和 {}", output_calls, output_calls_dot),
+            Err(e) => println!("写入 {} 错误!{}", output_calls, e),
+        }
+    }
+
+    std::process::exit(run_binary(&output_syntax_binary, limits.stack_size, limits.poison));
+}
+
+// 虚拟机部分：读取字节码文件，校验后执行，main 的返回值作为进程的退出码
+fn run_binary(binary: &str, stack_size: usize, poison: bool) -> i32 {
+    match virtual_machine::read_codes(binary) {
+        Ok(program) => {
+            // 执行之前先校验，有错误的程序不交给虚拟机
+            if let Err(errors) = verifier::verify(&program) {
+                println!("字节码校验失败：");
+                for error in &errors {
+                    println!("  {}", error);
+                }
+                return 1;
+            }
+            let map = virtual_machine::init_map();
+            // virtual_machine::display_codes(&program.codes);  // 显示中间代码
+            match virtual_machine::test_machine(&program, &map, stack_size, poison) {  // 执行虚拟机
+                Ok(status) => status,
+                Err(e) => {
+                    println!("运行错误：{}", e);
+                    1
+                }
+            }
+        }
+        Err(e) => {
+            println!("Error reading codes: {}", e);
+            1
+        }
+    }
 }
 
 
@@ -0,0 +1,664 @@
+// 中间代码优化
+// 各个优化都直接改写指令序列：先把要删除的指令标记出来，再统一压缩指令序列，
+// 同时改写跳转和调用的目标地址以及各函数栈帧布局中记录的地址。
+// 每个优化包装成一个优化遍（Pass），由 PassManager 按优化级别依次运行。
+// 基于 SSA 的优化在四元式上运行，排在栈式代码的优化之前
+use crate::cfg::Cfg;
+use crate::frame::FrameLayout;
+use crate::inline;
+use crate::ir::{Function, Module, Operand, QuadOp};
+use crate::ssa::{self, Ssa};
+use crate::syntax::Code;
+
+// 优化中发现的错误
+#[derive(Debug, Clone)]
+pub enum OptimizeError {
+    InvalidJump { pass: &'static str, message: String }, // 优化遍运行后跳转目标无效，是优化器自身的错误
+}
+
+// 优化遍：在整个程序的指令序列上运行，可以改写、删除指令，并相应调整栈帧布局中的地址；
+// on_ir 为 true 的优化遍改为在四元式上运行 run_ir，notes 收集给 --opt-stats 输出的说明
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    fn on_ir(&self) -> bool {
+        false
+    }
+    fn run_ir(&self, _module: &mut Module, _notes: &mut Vec<String>) -> Result<(), OptimizeError> {
+        Ok(())
+    }
+    fn run(&self, _codes: &mut Vec<Code>, _frames: &mut [FrameLayout]) -> Result<(), OptimizeError> {
+        Ok(())
+    }
+}
+
+// 把每个函数转换为 SSA 形式，做一种优化后再退出 SSA
+fn run_ssa(module: &mut Module, optimize: fn(&mut Ssa, &mut Function)) {
+    for function in &mut module.functions {
+        let mut ssa = Ssa::build(function);
+        optimize(&mut ssa, function);
+        ssa.destruct(function);
+    }
+}
+
+pub struct TailCall;
+
+impl Pass for TailCall {
+    fn name(&self) -> &'static str {
+        "tailcall"
+    }
+
+    fn on_ir(&self) -> bool {
+        true
+    }
+
+    fn run_ir(&self, module: &mut Module, notes: &mut Vec<String>) -> Result<(), OptimizeError> {
+        inline::eliminate_tail_calls(module, notes);
+        Ok(())
+    }
+}
+
+pub struct Inline;
+
+impl Pass for Inline {
+    fn name(&self) -> &'static str {
+        "inline"
+    }
+
+    fn on_ir(&self) -> bool {
+        true
+    }
+
+    fn run_ir(&self, module: &mut Module, notes: &mut Vec<String>) -> Result<(), OptimizeError> {
+        inline::inline_calls(module, notes);
+        Ok(())
+    }
+}
+
+pub struct Sccp;
+
+impl Pass for Sccp {
+    fn name(&self) -> &'static str {
+        "sccp"
+    }
+
+    fn on_ir(&self) -> bool {
+        true
+    }
+
+    fn run_ir(&self, module: &mut Module, _notes: &mut Vec<String>) -> Result<(), OptimizeError> {
+        run_ssa(module, Ssa::sccp);
+        Ok(())
+    }
+}
+
+pub struct Gvn;
+
+impl Pass for Gvn {
+    fn name(&self) -> &'static str {
+        "gvn"
+    }
+
+    fn on_ir(&self) -> bool {
+        true
+    }
+
+    fn run_ir(&self, module: &mut Module, _notes: &mut Vec<String>) -> Result<(), OptimizeError> {
+        run_ssa(module, Ssa::gvn);
+        Ok(())
+    }
+}
+
+pub struct Licm;
+
+impl Pass for Licm {
+    fn name(&self) -> &'static str {
+        "licm"
+    }
+
+    fn on_ir(&self) -> bool {
+        true
+    }
+
+    fn run_ir(&self, module: &mut Module, notes: &mut Vec<String>) -> Result<(), OptimizeError> {
+        for function in &mut module.functions {
+            ssa::insert_preheaders(function);
+            let mut ssa = Ssa::build(function);
+            ssa.licm(function, notes);
+            ssa.destruct(function);
+        }
+        Ok(())
+    }
+}
+
+pub struct StrengthReduction;
+
+impl Pass for StrengthReduction {
+    fn name(&self) -> &'static str {
+        "sr"
+    }
+
+    fn on_ir(&self) -> bool {
+        true
+    }
+
+    fn run_ir(&self, module: &mut Module, notes: &mut Vec<String>) -> Result<(), OptimizeError> {
+        for function in &mut module.functions {
+            ssa::insert_preheaders(function);
+            let mut ssa = Ssa::build(function);
+            ssa.strength_reduce(function, notes);
+            ssa.destruct(function);
+        }
+        Ok(())
+    }
+}
+
+pub struct Dce;
+
+impl Pass for Dce {
+    fn name(&self) -> &'static str {
+        "dce"
+    }
+
+    fn on_ir(&self) -> bool {
+        true
+    }
+
+    fn run_ir(&self, module: &mut Module, _notes: &mut Vec<String>) -> Result<(), OptimizeError> {
+        run_ssa(module, Ssa::dce);
+        Ok(())
+    }
+}
+
+pub struct ConstantFolding;
+
+impl Pass for ConstantFolding {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run(&self, codes: &mut Vec<Code>, frames: &mut [FrameLayout]) -> Result<(), OptimizeError> {
+        fold_constants(codes, frames)
+    }
+}
+
+pub struct Peephole;
+
+impl Pass for Peephole {
+    fn name(&self) -> &'static str {
+        "peephole"
+    }
+
+    fn run(&self, codes: &mut Vec<Code>, frames: &mut [FrameLayout]) -> Result<(), OptimizeError> {
+        peephole(codes, frames);
+        Ok(())
+    }
+}
+
+// 可以用 --print-after 指定的优化遍
+pub const PASS_NAMES: [&str; 9] = ["tailcall", "inline", "sccp", "gvn", "licm", "sr", "dce", "fold", "peephole"];
+
+pub const MAX_LEVEL: u32 = 3;
+
+// 不指定 -O 时的优化级别：默认不优化，优化需要显式用 -O1 以上打开
+pub const DEFAULT_LEVEL: u32 = 0;
+
+type PassFactory = fn() -> Box<dyn Pass>;
+
+// 按顺序运行一组优化遍，每个优化遍之后检查跳转目标，并按需输出中间代码
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    print_after: Vec<String>,
+    stats: bool,
+}
+
+impl std::fmt::Debug for PassManager {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names: Vec<&str> = self.passes.iter().map(|pass| pass.name()).collect();
+        f.debug_struct("PassManager")
+            .field("passes", &names)
+            .field("print_after", &self.print_after)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+impl PassManager {
+    // 优化级别对应的优化遍：-O0 不优化，-O1 常量折叠，-O2 先消除尾递归、内联小函数，
+    // 再在 SSA 形式上做稀疏条件常量传播、全局值编号、循环不变代码外提和死代码删除，
+    // 最后做常量折叠和窥孔优化。
+    // 抽象机上 MULT 和 ADD 一样只是一条指令，强度削弱反而让循环变长，只在 -O3 中运行，供对比演示
+    pub fn for_level(level: u32) -> PassManager {
+        // (最低优化级别, 优化遍)，按运行顺序排列
+        let pipeline: [(u32, PassFactory); 9] = [
+            (2, || Box::new(TailCall)),
+            (2, || Box::new(Inline)),
+            (2, || Box::new(Sccp)),
+            (2, || Box::new(Gvn)),
+            (2, || Box::new(Licm)),
+            (3, || Box::new(StrengthReduction)),
+            (2, || Box::new(Dce)),
+            (1, || Box::new(ConstantFolding)),
+            (2, || Box::new(Peephole)),
+        ];
+        let mut manager = PassManager::default();
+        for (min_level, pass) in pipeline {
+            if level >= min_level {
+                manager.add(pass());
+            }
+        }
+        manager
+    }
+
+    pub fn add(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    // -O0 时没有任何优化遍
+    pub fn is_empty(&self) -> bool {
+        self.passes.is_empty()
+    }
+
+    // 输出每个优化遍前后的四元式条数或指令条数
+    pub fn report_stats(&mut self) {
+        self.stats = true;
+    }
+
+    // 在名为 name 的优化遍运行之后输出中间代码
+    pub fn print_after(&mut self, name: &str) {
+        self.print_after.push(name.to_string());
+    }
+
+    // 先在四元式上运行 on_ir 的优化遍，再由四元式生成栈式代码，运行其余的优化遍
+    pub fn run(&self, module: &mut Module) -> Result<(Vec<Code>, Vec<FrameLayout>), OptimizeError> {
+        if self.stats {
+            println!("==各优化遍前后的代码长度==");
+        }
+        for pass in self.passes.iter().filter(|pass| pass.on_ir()) {
+            let before = quad_count(module);
+            let mut notes = Vec::new();
+            pass.run_ir(module, &mut notes)?;
+            verify_labels(module)
+                .map_err(|message| OptimizeError::InvalidJump { pass: pass.name(), message })?;
+            if self.stats {
+                println!("{}\t四元式 {} -> {}", pass.name(), before, quad_count(module));
+                for note in notes {
+                    println!("\t{}", note);
+                }
+            }
+            if self.print_after.iter().any(|name| name == pass.name()) {
+                println!("==优化遍 {} 之后的四元式==", pass.name());
+                print!("{}", module.dump());
+            }
+        }
+        let (mut codes, mut frames) = module.lower();
+        if self.stats {
+            println!("生成栈式代码\t指令 {}", codes.len());
+        }
+        for pass in self.passes.iter().filter(|pass| !pass.on_ir()) {
+            let before = codes.len();
+            let codes = &mut codes;
+            let frames = &mut frames[..];
+            pass.run(codes, frames)?;
+            verify_jumps(codes, frames)
+                .map_err(|message| OptimizeError::InvalidJump { pass: pass.name(), message })?;
+            if self.stats {
+                println!("{}\t指令 {} -> {}", pass.name(), before, codes.len());
+            }
+            if self.print_after.iter().any(|name| name == pass.name()) {
+                println!("==优化遍 {} 之后的中间代码==", pass.name());
+                for (address, code) in codes.iter().enumerate() {
+                    println!("{}", code.listing(address));
+                }
+            }
+        }
+        Ok((codes, frames))
+    }
+}
+
+fn quad_count(module: &Module) -> usize {
+    module.functions.iter().map(|function| function.quads.len()).sum()
+}
+
+// 检查四元式中的标号：每个函数中跳转到的标号都恰好定义一次
+pub fn verify_labels(module: &Module) -> Result<(), String> {
+    for function in &module.functions {
+        let mut defined = vec![0; function.labels];
+        for quad in &function.quads {
+            if let (QuadOp::Label, Operand::Label(label)) = (quad.op, quad.result) {
+                match defined.get_mut(label) {
+                    Some(count) => *count += 1,
+                    None => return Err(format!("函数 {} 中的标号 L{} 超出范围", function.name(), label)),
+                }
+            }
+        }
+        for quad in &function.quads {
+            if let (QuadOp::Jump | QuadOp::JumpFalse, Operand::Label(label)) = (quad.op, quad.result) {
+                if defined.get(label) != Some(&1) {
+                    return Err(format!("函数 {} 中跳转到的标号 L{} 没有唯一的定义", function.name(), label));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// 检查跳转目标：函数内的 BR、BRF 只能跳到本函数之内，CAL 只能跳到函数入口，
+// 不属于任何函数的指令（0 号单元跳到 main 的 BR）只要跳到代码之内
+pub fn verify_jumps(codes: &[Code], frames: &[FrameLayout]) -> Result<(), String> {
+    for frame in frames {
+        if frame.entry >= frame.end || frame.end > codes.len() {
+            return Err(format!("函数 {} 的地址范围 {}..{} 无效", frame.function, frame.entry, frame.end));
+        }
+    }
+    for (address, code) in codes.iter().enumerate() {
+        let target = code.operand;
+        let valid = if is_jump(code) {
+            match frames.iter().find(|frame| frame.entry <= address && address < frame.end) {
+                Some(frame) => target >= frame.entry as i32 && target < frame.end as i32,
+                None => target >= 0 && (target as usize) < codes.len(),
+            }
+        } else if code.opt == "CAL" {
+            frames.iter().any(|frame| frame.entry as i32 == target)
+        } else {
+            continue;
+        };
+        if !valid {
+            return Err(format!("第 {} 条指令 {} 的目标地址 {} 无效", address, code.opt, target));
+        }
+    }
+    Ok(())
+}
+
+// 删除标记为 removed 的指令，返回旧地址到新地址的映射；
+// 被删除的指令映射到它之后第一条保留下来的指令，映射表的最后一项是原来的代码长度
+pub fn compact(codes: &mut Vec<Code>, frames: &mut [FrameLayout], removed: &[bool]) -> Vec<usize> {
+    let mut map = Vec::with_capacity(codes.len() + 1);
+    let mut next = 0;
+    for &gone in removed {
+        map.push(next);
+        if !gone {
+            next += 1;
+        }
+    }
+    map.push(next);
+
+    let mut kept = Vec::with_capacity(next);
+    for (code, &gone) in codes.drain(..).zip(removed) {
+        if !gone {
+            kept.push(code);
+        }
+    }
+    for code in &mut kept {
+        if is_jump(code) || code.opt == "CAL" {
+            code.operand = map[code.operand as usize] as i32;
+        }
+    }
+    *codes = kept;
+
+    for frame in frames.iter_mut() {
+        frame.entry = map[frame.entry];
+        frame.end = map[frame.end];
+        for slot in &mut frame.slots {
+            slot.start = map[slot.start];
+        }
+    }
+    map
+}
+
+pub fn is_jump(code: &Code) -> bool {
+    code.opt == "BR" || code.opt == "BRF"
+}
+
+// 所有跳转和调用的目标地址
+pub fn jump_targets(codes: &[Code]) -> Vec<bool> {
+    let mut targets = vec![false; codes.len() + 1];
+    for code in codes {
+        if is_jump(code) || code.opt == "CAL" {
+            if let Some(target) = targets.get_mut(code.operand as usize) {
+                *target = true;
+            }
+        }
+    }
+    targets
+}
+
+// 标记各函数中从入口出发到达不了的指令
+pub fn mark_unreachable(codes: &[Code], frames: &[FrameLayout], removed: &mut [bool]) -> bool {
+    let mut changed = false;
+    for frame in frames {
+        let cfg = Cfg::build(codes, &frame.function, frame.entry, frame.end);
+        let mut reached = vec![false; cfg.blocks.len()];
+        let mut work = vec![0];
+        while let Some(id) = work.pop() {
+            if reached[id] {
+                continue;
+            }
+            reached[id] = true;
+            work.extend(cfg.blocks[id].succs.iter().copied());
+        }
+        for (id, block) in cfg.blocks.iter().enumerate() {
+            if !reached[id] {
+                for gone in &mut removed[block.start..block.end] {
+                    changed |= !*gone;
+                    *gone = true;
+                }
+            }
+        }
+    }
+    changed
+}
+
+// 两个常量做二元运算，溢出时不折叠，留到运行时
+pub fn fold_binary(op: &str, a: i32, b: i32) -> Option<i32> {
+    match op {
+        "ADD" => a.checked_add(b),
+        "SUB" => a.checked_sub(b),
+        "MULT" => a.checked_mul(b),
+        "DIV" => a.checked_div(b),
+        "EQ" => Some((a == b) as i32),
+        "NOTEQ" => Some((a != b) as i32),
+        "GT" => Some((a > b) as i32),
+        "LES" => Some((a < b) as i32),
+        "GE" => Some((a >= b) as i32),
+        "LE" => Some((a <= b) as i32),
+        "AND" => Some((a != 0 && b != 0) as i32),
+        "OR" => Some((a != 0 || b != 0) as i32),
+        _ => None,
+    }
+}
+
+// 只由 LOADI 和运算指令组成的代码片段在运行时的值，片段不是常量表达式时为 None
+pub fn constant_value(codes: &[Code]) -> Option<i32> {
+    let mut stack = Vec::new();
+    for code in codes {
+        match code.opt.as_str() {
+            "LOADI" => stack.push(code.operand),
+            "NOT" => {
+                let a = stack.pop()?;
+                stack.push((a == 0) as i32);
+            }
+            op => {
+                let b = stack.pop()?;
+                let a = stack.pop()?;
+                stack.push(fold_binary(op, a, b)?);
+            }
+        }
+    }
+    match stack[..] {
+        [value] => Some(value),
+        _ => None,
+    }
+}
+
+// 只压入一个值、没有副作用的指令
+fn is_simple_push(code: &Code) -> bool {
+    matches!(code.opt.as_str(), "LOAD" | "LOADI" | "LOADR")
+}
+
+fn is_loadi(code: &Code, value: i32) -> bool {
+    code.opt == "LOADI" && code.operand == value
+}
+
+// 常量折叠与代数化简，并删除条件为常量的分支
+// 在每个函数内反复匹配下面几种指令窗口，窗口中除第一条外的指令都不能是跳转目标：
+//   LOADI a; LOADI b; op     => LOADI (a op b)
+//   LOADI a; NOT             => LOADI !a
+//   LOADI 0; ADD|SUB         => （删除），LOADI 1; MULT|DIV 同理
+//   x; LOADI 0; MULT         => LOADI 0，x 为 LOAD、LOADI 或 LOADR
+//   LOADI 0; x; ADD          => x，LOADI 1; x; MULT 同理，LOADI 0; x; MULT => LOADI 0
+//   LOADI c; BRF L           => c 非 0 时删除，c 为 0 时变为 BR L
+// 除数为常量 0 的除法不折叠，留到运行时报错；源程序中的常量 0 除数在语法分析时已报告错误 53
+pub fn fold_constants(codes: &mut Vec<Code>, frames: &mut [FrameLayout]) -> Result<(), OptimizeError> {
+    loop {
+        let targets = jump_targets(codes);
+        let mut removed = vec![false; codes.len()];
+        let mut changed = false;
+
+        for frame in frames.iter() {
+            let mut i = frame.entry;
+            while i < frame.end {
+                // 窗口中的指令都还没有被删除，且第二、三条指令不是跳转目标
+                let window = |len: usize| {
+                    i + len <= frame.end
+                        && (i..i + len).all(|j| !removed[j])
+                        && (i + 1..i + len).all(|j| !targets[j])
+                };
+
+                if window(3) && codes[i].opt == "LOADI" && codes[i + 1].opt == "LOADI" {
+                    if let Some(value) = fold_binary(&codes[i + 2].opt, codes[i].operand, codes[i + 1].operand) {
+                        codes[i].operand = value;
+                        removed[i + 1] = true;
+                        removed[i + 2] = true;
+                        changed = true;
+                        continue;
+                    }
+                }
+                if window(2) && codes[i].opt == "LOADI" && codes[i + 1].opt == "NOT" {
+                    codes[i].operand = (codes[i].operand == 0) as i32;
+                    removed[i + 1] = true;
+                    changed = true;
+                    continue;
+                }
+                if window(2) && !targets[i] {
+                    let op = codes[i + 1].opt.as_str();
+                    let identity = (is_loadi(&codes[i], 0) && (op == "ADD" || op == "SUB"))
+                        || (is_loadi(&codes[i], 1) && (op == "MULT" || op == "DIV"));
+                    if identity {
+                        removed[i] = true;
+                        removed[i + 1] = true;
+                        changed = true;
+                        i += 2;
+                        continue;
+                    }
+                }
+                if window(3) && is_simple_push(&codes[i]) && is_loadi(&codes[i + 1], 0) && codes[i + 2].opt == "MULT" {
+                    codes[i].opt = "LOADI".to_string();
+                    codes[i].operand = 0;
+                    removed[i + 1] = true;
+                    removed[i + 2] = true;
+                    changed = true;
+                    continue;
+                }
+                if window(3) && codes[i].opt == "LOADI" && is_simple_push(&codes[i + 1]) {
+                    let op = codes[i + 2].opt.as_str();
+                    if (codes[i].operand == 0 && op == "ADD") || (codes[i].operand == 1 && op == "MULT") {
+                        removed[i] = true;
+                        removed[i + 2] = true;
+                        changed = true;
+                        i += 3;
+                        continue;
+                    }
+                    if codes[i].operand == 0 && op == "MULT" {
+                        removed[i + 1] = true;
+                        removed[i + 2] = true;
+                        changed = true;
+                        continue;
+                    }
+                }
+                if window(2) && codes[i].opt == "LOADI" && codes[i + 1].opt == "BRF" {
+                    if codes[i].operand != 0 {
+                        removed[i] = true; // 条件恒为真，顺序执行
+                    } else {
+                        codes[i].opt = "BR".to_string(); // 条件恒为假，总是跳转
+                        codes[i].operand = codes[i + 1].operand;
+                    }
+                    removed[i + 1] = true;
+                    changed = true;
+                    i += 2;
+                    continue;
+                }
+                i += 1;
+            }
+        }
+
+        changed |= mark_unreachable(codes, frames, &mut removed);
+        if !changed {
+            return Ok(());
+        }
+        compact(codes, frames, &removed);
+    }
+}
+
+// 沿 BR 链找到最终的跳转目标，遇到环时停在环上
+fn final_target(codes: &[Code], target: usize) -> usize {
+    let mut target = target;
+    let mut steps = 0;
+    while target < codes.len() && codes[target].opt == "BR" && steps < codes.len() {
+        target = codes[target].operand as usize;
+        steps += 1;
+    }
+    target
+}
+
+// 窥孔优化
+//   跳转到 BR 的跳转直接跳到 BR 链的终点
+//   BR 跳到下一条指令时删除，BRF 跳到下一条指令时只需弹出条件，改为 POP
+//   STO x; LOAD x            => DUP; STO x，STOR x; LOADR x 同理，LOAD x 不能是跳转目标
+//   BR、RETURN、HALT 之后到达不了的指令删除
+pub fn peephole(codes: &mut Vec<Code>, frames: &mut [FrameLayout]) {
+    loop {
+        let mut changed = false;
+        for i in 0..codes.len() {
+            if is_jump(&codes[i]) {
+                let target = final_target(codes, codes[i].operand as usize);
+                if target != codes[i].operand as usize {
+                    codes[i].operand = target as i32;
+                    changed = true;
+                }
+            }
+        }
+
+        let targets = jump_targets(codes);
+        let mut removed = vec![false; codes.len()];
+        for i in 0..codes.len() {
+            if is_jump(&codes[i]) && codes[i].operand as usize == i + 1 {
+                if codes[i].opt == "BR" {
+                    removed[i] = true;
+                } else {
+                    codes[i].opt = "POP".to_string();
+                    codes[i].operand = 0;
+                }
+                changed = true;
+            } else if i + 1 < codes.len() && !targets[i + 1] && codes[i + 1].operand == codes[i].operand {
+                let load = match codes[i].opt.as_str() {
+                    "STO" => "LOAD",
+                    "STOR" => "LOADR",
+                    _ => continue,
+                };
+                if codes[i + 1].opt == load {
+                    codes[i + 1].opt = codes[i].opt.clone();
+                    codes[i].opt = "DUP".to_string();
+                    codes[i].operand = 0;
+                    changed = true;
+                }
+            }
+        }
+
+        changed |= mark_unreachable(codes, frames, &mut removed);
+        if !changed {
+            return;
+        }
+        compact(codes, frames, &removed);
+    }
+}
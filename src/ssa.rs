@@ -0,0 +1,1184 @@
+// 静态单赋值形式
+// 在一个函数的四元式上建立控制流图和支配树，求出支配边界，为跨基本块使用的变量放置 φ 函数，
+// 再沿支配树重命名：每次定义都得到一个新的值，使用处改为到达它的那个值。
+// 地址被取走（&x）的变量可能经引用被改写，不参与 SSA。
+//
+// 值用编号不小于 base 的临时变量表示，origin 记录它原来的变量或临时变量；
+// 优化中新增的变量（如强度削弱的归纳变量）用 base 之下空出来的临时变量编号。
+// 退出 SSA 时把每个值换回原来的变量，φ 函数改为前驱基本块末尾的复制；
+// 条件跳转的目标上需要复制时，拆出一个新的基本块放在函数末尾
+use std::collections::{HashMap, HashSet};
+
+use crate::ir::{Function, Operand, Quad, QuadOp};
+use crate::optimize::fold_binary;
+
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub start: usize, // 第一条四元式的下标
+    pub end: usize,   // 最后一条四元式之后的下标
+    pub preds: Vec<usize>,
+    pub succs: Vec<usize>,
+    pub jump: Option<usize>,        // j、jf 跳到的基本块
+    pub fallthrough: Option<usize>, // 顺序执行到达的基本块
+}
+
+// 把四元式划分为基本块：标号和跳转、返回、停机之后的四元式是基本块的首条四元式
+pub fn blocks(quads: &[Quad]) -> Vec<Block> {
+    let mut leaders = vec![false; quads.len() + 1];
+    leaders[0] = true;
+    for (q, quad) in quads.iter().enumerate() {
+        if quad.op == QuadOp::Label {
+            leaders[q] = true;
+        }
+        if quad.op.ends_block() {
+            leaders[q + 1] = true;
+        }
+    }
+    let mut blocks: Vec<Block> = Vec::new();
+    for (q, &leader) in leaders.iter().take(quads.len()).enumerate() {
+        if leader {
+            blocks.push(Block { start: q, end: q + 1, preds: Vec::new(), succs: Vec::new(), jump: None, fallthrough: None });
+        }
+        blocks.last_mut().unwrap().end = q + 1;
+    }
+
+    let mut label_block = HashMap::new();
+    for (id, block) in blocks.iter().enumerate() {
+        if let (QuadOp::Label, Operand::Label(label)) = (quads[block.start].op, quads[block.start].result) {
+            label_block.insert(label, id);
+        }
+    }
+    for id in 0..blocks.len() {
+        let last = &quads[blocks[id].end - 1];
+        let next = (id + 1 < blocks.len()).then_some(id + 1);
+        let (jump, fallthrough) = match (last.op, last.result) {
+            (QuadOp::Jump, Operand::Label(label)) => (label_block.get(&label).copied(), None),
+            (QuadOp::JumpFalse, Operand::Label(label)) => (label_block.get(&label).copied(), next),
+            (QuadOp::Return | QuadOp::Halt, _) => (None, None),
+            _ => (None, next),
+        };
+        blocks[id].jump = jump;
+        blocks[id].fallthrough = fallthrough;
+        for succ in [fallthrough, jump].into_iter().flatten() {
+            if !blocks[id].succs.contains(&succ) {
+                blocks[id].succs.push(succ);
+                blocks[succ].preds.push(id);
+            }
+        }
+    }
+    blocks
+}
+
+// 四元式中读取值的操作数，LoadRef 的 arg1、StoreRef 的 result 是保存地址的变量，也是读取
+fn uses_mut(quad: &mut Quad) -> Vec<&mut Operand> {
+    match quad.op {
+        QuadOp::LoadRef => vec![&mut quad.arg1],
+        QuadOp::StoreRef => vec![&mut quad.arg1, &mut quad.result],
+        op if op.is_binary() => vec![&mut quad.arg1, &mut quad.arg2],
+        QuadOp::Assign | QuadOp::Not | QuadOp::JumpFalse | QuadOp::Param | QuadOp::Return | QuadOp::Halt
+        | QuadOp::Write | QuadOp::WriteChar | QuadOp::WriteNum => {
+            if quad.arg1 == Operand::None { Vec::new() } else { vec![&mut quad.arg1] }
+        }
+        _ => Vec::new(),
+    }
+}
+
+// 四元式定义的变量或临时变量
+fn def_mut(quad: &mut Quad) -> Option<&mut Operand> {
+    match (quad.op, quad.result) {
+        (QuadOp::StoreRef, _) => None,
+        (_, Operand::Temp(_)) => Some(&mut quad.result),
+        (QuadOp::Assign, Operand::Var(_)) => Some(&mut quad.result),
+        _ => None,
+    }
+}
+
+// 没有副作用、只由操作数决定结果的运算
+fn is_pure(op: QuadOp) -> bool {
+    op.is_binary() || matches!(op, QuadOp::Assign | QuadOp::Not)
+}
+
+#[derive(Debug, Clone)]
+pub struct Phi {
+    pub var: Operand,       // 原来的变量
+    pub result: Operand,
+    pub args: Vec<Operand>, // 与基本块的 preds 一一对应
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Lattice {
+    Top,       // 还不知道
+    Const(i32),
+    Bottom,    // 不是常量
+}
+
+fn meet(a: Lattice, b: Lattice) -> Lattice {
+    match (a, b) {
+        (Lattice::Top, x) | (x, Lattice::Top) => x,
+        (Lattice::Const(x), Lattice::Const(y)) if x == y => a,
+        _ => Lattice::Bottom,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Site {
+    Quad(usize),
+    Phi(usize, usize), // 基本块、φ 函数的下标
+}
+
+pub struct Ssa {
+    pub blocks: Vec<Block>,
+    pub idom: Vec<Option<usize>>, // 直接支配者，入口的直接支配者是它自己，到达不了的基本块为 None
+    pub phis: Vec<Vec<Phi>>,
+    base: usize,
+    origin: Vec<Operand>,          // 值 base + i 原来的变量
+    vars: HashSet<Operand>,        // 参与 SSA 的变量和临时变量
+    deleted: Vec<bool>,            // 被删除的四元式
+    dead_blocks: Vec<bool>,
+    dead_edges: HashSet<(usize, usize)>,
+    entry_label: bool, // build 在入口加了标号
+    next_temp: usize,  // 下一个新增变量的编号
+    hoisted: HashMap<usize, Vec<Quad>>, // 放到基本块末尾（跳转之前）的四元式
+    after: HashMap<usize, Vec<Quad>>,   // 紧跟在某条四元式之后的四元式
+}
+
+// 自然循环
+#[derive(Debug, Clone)]
+pub struct Loop {
+    pub header: usize,
+    pub blocks: Vec<usize>, // 循环中的基本块，按在代码中的顺序
+    pub latches: Vec<usize>, // 有回边跳到 header 的基本块
+    pub preheader: Option<usize>, // 循环外唯一的前驱，且只有 header 一个后继
+}
+
+impl Ssa {
+    // 把函数转换为 SSA 形式
+    pub fn build(function: &mut Function) -> Ssa {
+        // 入口基本块不能有前驱（循环从函数的第一条四元式开始时），否则在它前面加一个只有标号的基本块
+        let mut blocks = blocks(&function.quads);
+        let entry_label = blocks.first().is_some_and(|block| !block.preds.is_empty());
+        if entry_label {
+            let token = function.quads[0].token;
+            let label = Quad { op: QuadOp::Label, arg1: Operand::None, arg2: Operand::None, result: Operand::Label(function.labels), token };
+            function.quads.insert(0, label);
+            function.labels += 1;
+            for start in &mut function.starts {
+                *start += 1;
+            }
+            blocks = self::blocks(&function.quads);
+        }
+        let idom = dominators(&blocks);
+        let frontiers = frontiers(&blocks, &idom);
+
+        let mut taken = HashSet::new();
+        for quad in &function.quads {
+            if quad.op == QuadOp::AddrOf {
+                taken.insert(quad.arg1);
+            }
+        }
+        let eligible = |operand: &Operand| match operand {
+            Operand::Var(_) => !taken.contains(operand),
+            Operand::Temp(_) => true,
+            _ => false,
+        };
+
+        // 在某个基本块中先使用、后定义的变量才可能需要 φ 函数
+        let mut vars = HashSet::new();
+        let mut global = HashSet::new();
+        let mut def_blocks: HashMap<Operand, Vec<usize>> = HashMap::new();
+        for (id, block) in blocks.iter().enumerate() {
+            if idom[id].is_none() {
+                continue;
+            }
+            let mut defined = HashSet::new();
+            for quad in &mut function.quads[block.start..block.end] {
+                for operand in uses_mut(quad) {
+                    if eligible(operand) {
+                        vars.insert(*operand);
+                        if !defined.contains(operand) {
+                            global.insert(*operand);
+                        }
+                    }
+                }
+                if let Some(operand) = def_mut(quad) {
+                    if eligible(operand) {
+                        vars.insert(*operand);
+                        defined.insert(*operand);
+                        def_blocks.entry(*operand).or_default().push(id);
+                    }
+                }
+            }
+        }
+
+        let mut phis: Vec<Vec<Phi>> = vec![Vec::new(); blocks.len()];
+        let mut ordered: Vec<&Operand> = global.iter().collect();
+        ordered.sort_by_key(|operand| match operand {
+            Operand::Var(offset) => (0, *offset as i64),
+            Operand::Temp(t) => (1, *t as i64),
+            _ => (2, 0),
+        });
+        for &var in ordered {
+            let mut work = def_blocks.get(&var).cloned().unwrap_or_default();
+            let mut has_phi = HashSet::new();
+            let mut queued: HashSet<usize> = work.iter().copied().collect();
+            while let Some(id) = work.pop() {
+                for &frontier in &frontiers[id] {
+                    if has_phi.insert(frontier) {
+                        let preds = blocks[frontier].preds.len();
+                        phis[frontier].push(Phi { var, result: var, args: vec![var; preds] });
+                        if queued.insert(frontier) {
+                            work.push(frontier);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut ssa = Ssa {
+            deleted: vec![false; function.quads.len()],
+            dead_blocks: idom.iter().map(Option::is_none).collect(),
+            dead_edges: HashSet::new(),
+            entry_label,
+            next_temp: function.temps,
+            hoisted: HashMap::new(),
+            after: HashMap::new(),
+            blocks,
+            idom,
+            phis,
+            // 每条四元式至多引入一个新变量
+            base: function.temps + function.quads.len(),
+            origin: Vec::new(),
+            vars,
+        };
+        let mut stacks: HashMap<Operand, Vec<Operand>> = HashMap::new();
+        if !ssa.blocks.is_empty() {
+            ssa.rename(function, 0, &mut stacks);
+        }
+        function.temps = ssa.base + ssa.origin.len();
+        ssa
+    }
+
+    fn new_value(&mut self, var: Operand) -> Operand {
+        self.origin.push(var);
+        Operand::Temp(self.base + self.origin.len() - 1)
+    }
+
+    // 沿支配树重命名，stacks 中为各变量当前到达的值
+    fn rename(&mut self, function: &mut Function, id: usize, stacks: &mut HashMap<Operand, Vec<Operand>>) {
+        let mut pushed = Vec::new();
+        for i in 0..self.phis[id].len() {
+            let var = self.phis[id][i].var;
+            let value = self.new_value(var);
+            self.phis[id][i].result = value;
+            stacks.entry(var).or_default().push(value);
+            pushed.push(var);
+        }
+        let (start, end) = (self.blocks[id].start, self.blocks[id].end);
+        for q in start..end {
+            let quad = &mut function.quads[q];
+            for operand in uses_mut(quad) {
+                if self.vars.contains(operand) {
+                    if let Some(&value) = stacks.get(operand).and_then(|stack| stack.last()) {
+                        *operand = value;
+                    }
+                }
+            }
+            if let Some(operand) = def_mut(quad) {
+                if self.vars.contains(operand) {
+                    let var = *operand;
+                    let value = self.new_value(var);
+                    *operand = value;
+                    stacks.entry(var).or_default().push(value);
+                    pushed.push(var);
+                }
+            }
+        }
+        for &succ in &self.blocks[id].succs.clone() {
+            let j = self.blocks[succ].preds.iter().position(|&pred| pred == id).unwrap();
+            for phi in &mut self.phis[succ] {
+                phi.args[j] = stacks.get(&phi.var).and_then(|stack| stack.last()).copied().unwrap_or(phi.var);
+            }
+        }
+        let children: Vec<usize> = (0..self.blocks.len())
+            .filter(|&child| child != id && self.idom[child] == Some(id))
+            .collect();
+        for child in children {
+            self.rename(function, child, stacks);
+        }
+        for var in pushed {
+            stacks.get_mut(&var).unwrap().pop();
+        }
+    }
+
+    fn is_value(&self, operand: Operand) -> bool {
+        matches!(operand, Operand::Temp(t) if t >= self.base)
+    }
+
+    // 值原来的变量；不是 SSA 的值时原样返回
+    fn original(&self, operand: Operand) -> Operand {
+        match operand {
+            Operand::Temp(t) if t >= self.base => self.origin[t - self.base],
+            _ => operand,
+        }
+    }
+
+    fn block_of(&self, q: usize) -> usize {
+        self.blocks.iter().position(|block| block.start <= q && q < block.end).unwrap()
+    }
+
+    // 各值的定义处和使用处
+    fn sites(&self, function: &mut Function) -> (HashMap<Operand, Site>, HashMap<Operand, Vec<Site>>) {
+        let mut defs = HashMap::new();
+        let mut uses: HashMap<Operand, Vec<Site>> = HashMap::new();
+        for (q, quad) in function.quads.iter_mut().enumerate() {
+            if self.deleted[q] {
+                continue;
+            }
+            for operand in uses_mut(quad) {
+                if self.is_value(*operand) {
+                    uses.entry(*operand).or_default().push(Site::Quad(q));
+                }
+            }
+            if let Some(operand) = def_mut(quad) {
+                if self.is_value(*operand) {
+                    defs.insert(*operand, Site::Quad(q));
+                }
+            }
+        }
+        for (id, phis) in self.phis.iter().enumerate() {
+            for (i, phi) in phis.iter().enumerate() {
+                defs.insert(phi.result, Site::Phi(id, i));
+                for &arg in &phi.args {
+                    if self.is_value(arg) {
+                        uses.entry(arg).or_default().push(Site::Phi(id, i));
+                    }
+                }
+            }
+        }
+        (defs, uses)
+    }
+
+    // 稀疏条件常量传播：只沿可能执行的控制流边传播常量，
+    // 条件为常量的分支只有一边可执行，执行不到的基本块删除
+    pub fn sccp(&mut self, function: &mut Function) {
+        let (_, uses) = self.sites(function);
+        let count = self.origin.len();
+        let mut values = vec![Lattice::Top; count];
+        let mut executable = vec![false; self.blocks.len()];
+        let mut edges = HashSet::new();
+        let mut flow: Vec<(Option<usize>, usize)> = vec![(None, 0)];
+        let mut changed: Vec<Operand> = Vec::new();
+
+        let base = self.base;
+        let value_of = |values: &[Lattice], operand: Operand| match operand {
+            Operand::Const(c) => Lattice::Const(c),
+            Operand::Temp(t) if t >= base => values[t - base],
+            _ => Lattice::Bottom, // 函数入口处的值
+        };
+
+        while !flow.is_empty() || !changed.is_empty() {
+            let mut visit_quads = Vec::new();
+            let mut visit_phis = Vec::new();
+            if let Some((from, id)) = flow.pop() {
+                if let Some(from) = from {
+                    if !edges.insert((from, id)) {
+                        continue;
+                    }
+                }
+                visit_phis.extend((0..self.phis[id].len()).map(|i| (id, i)));
+                if !executable[id] {
+                    executable[id] = true;
+                    visit_quads.extend(self.blocks[id].start..self.blocks[id].end);
+                }
+            } else if let Some(value) = changed.pop() {
+                for site in uses.get(&value).into_iter().flatten() {
+                    match *site {
+                        Site::Quad(q) if executable[self.block_of(q)] => visit_quads.push(q),
+                        Site::Phi(id, i) if executable[id] => visit_phis.push((id, i)),
+                        _ => {}
+                    }
+                }
+            }
+
+            for (id, i) in visit_phis {
+                let phi = &self.phis[id][i];
+                let mut value = Lattice::Top;
+                for (j, &pred) in self.blocks[id].preds.iter().enumerate() {
+                    if edges.contains(&(pred, id)) {
+                        value = meet(value, value_of(&values, phi.args[j]));
+                    }
+                }
+                let slot = match phi.result {
+                    Operand::Temp(t) => t - self.base,
+                    _ => unreachable!(),
+                };
+                let value = meet(values[slot], value);
+                if value != values[slot] {
+                    values[slot] = value;
+                    changed.push(phi.result);
+                }
+            }
+
+            for q in visit_quads {
+                let quad = &function.quads[q];
+                let id = self.block_of(q);
+                let block = &self.blocks[id];
+                match quad.op {
+                    QuadOp::JumpFalse => {
+                        let condition = value_of(&values, quad.arg1);
+                        if matches!(condition, Lattice::Bottom) || condition == Lattice::Const(0) {
+                            flow.extend(block.jump.map(|target| (Some(id), target)));
+                        }
+                        if matches!(condition, Lattice::Bottom) || matches!(condition, Lattice::Const(c) if c != 0) {
+                            flow.extend(block.fallthrough.map(|next| (Some(id), next)));
+                        }
+                        continue;
+                    }
+                    QuadOp::Jump => {
+                        flow.extend(block.jump.map(|target| (Some(id), target)));
+                        continue;
+                    }
+                    _ => {}
+                }
+                if q + 1 == block.end && !quad.op.ends_block() {
+                    flow.extend(block.fallthrough.map(|next| (Some(id), next)));
+                }
+                let Some(Operand::Temp(t)) = def_mut(&mut function.quads[q].clone()).copied() else {
+                    continue;
+                };
+                if t < self.base {
+                    continue;
+                }
+                let quad = &function.quads[q];
+                let value = match quad.op {
+                    QuadOp::Assign => value_of(&values, quad.arg1),
+                    QuadOp::Not => match value_of(&values, quad.arg1) {
+                        Lattice::Const(c) => Lattice::Const((c == 0) as i32),
+                        other => other,
+                    },
+                    op if op.is_binary() => match (value_of(&values, quad.arg1), value_of(&values, quad.arg2)) {
+                        (Lattice::Const(a), Lattice::Const(b)) => {
+                            fold_binary(op.opcode(), a, b).map_or(Lattice::Bottom, Lattice::Const)
+                        }
+                        (Lattice::Bottom, _) | (_, Lattice::Bottom) => Lattice::Bottom,
+                        _ => Lattice::Top,
+                    },
+                    _ => Lattice::Bottom,
+                };
+                let value = meet(values[t - self.base], value);
+                if value != values[t - self.base] {
+                    values[t - self.base] = value;
+                    changed.push(Operand::Temp(t));
+                }
+            }
+        }
+
+        // 用常量替换值，删除结果为常量的运算和执行不到的基本块
+        let constant = |operand: Operand| match value_of(&values, operand) {
+            Lattice::Const(c) if matches!(operand, Operand::Temp(t) if t >= base) => Some(c),
+            _ => None,
+        };
+        for (id, block) in self.blocks.iter().enumerate() {
+            if !executable[id] {
+                self.dead_blocks[id] = true;
+                for deleted in &mut self.deleted[block.start..block.end] {
+                    *deleted = true;
+                }
+                continue;
+            }
+            for &succ in &block.succs {
+                if !edges.contains(&(id, succ)) {
+                    self.dead_edges.insert((id, succ));
+                }
+            }
+            for q in block.start..block.end {
+                let quad = &mut function.quads[q];
+                let value_slots: Vec<&mut Operand> = match quad.op {
+                    // 地址（LoadRef 的 arg1、StoreRef 的 result）不会是常量，StoreRef 存入的值可以是
+                    QuadOp::LoadRef => Vec::new(),
+                    QuadOp::StoreRef => vec![&mut quad.arg1],
+                    _ => uses_mut(quad),
+                };
+                for operand in value_slots {
+                    if let Some(c) = constant(*operand) {
+                        *operand = Operand::Const(c);
+                    }
+                }
+                if is_pure(quad.op) && def_mut(quad).is_some_and(|result| constant(*result).is_some()) {
+                    self.deleted[q] = true;
+                }
+                if quad.op == QuadOp::JumpFalse {
+                    if let Operand::Const(c) = quad.arg1 {
+                        if c == 0 {
+                            quad.op = QuadOp::Jump;
+                            quad.arg1 = Operand::None;
+                        } else {
+                            self.deleted[q] = true;
+                        }
+                    }
+                }
+            }
+        }
+        for phis in &mut self.phis {
+            phis.retain(|phi| constant(phi.result).is_none());
+            for phi in phis.iter_mut() {
+                for arg in &mut phi.args {
+                    if let Some(c) = constant(*arg) {
+                        *arg = Operand::Const(c);
+                    }
+                }
+            }
+        }
+    }
+
+    // 全局值编号：沿支配树记录已经算过的表达式，被支配的相同表达式改为复制先前的结果
+    pub fn gvn(&mut self, function: &mut Function) {
+        let mut scopes: Vec<HashMap<(QuadOp, Operand, Operand), Operand>> = Vec::new();
+        if !self.blocks.is_empty() && !self.dead_blocks[0] {
+            self.number(function, 0, &mut scopes);
+        }
+    }
+
+    // 操作数的值在整个函数中不变：常量、SSA 的值，或没有被重新定义的变量在入口处的值
+    fn is_stable(&self, operand: Operand) -> bool {
+        matches!(operand, Operand::Const(_)) || self.is_value(operand) || self.vars.contains(&operand)
+    }
+
+    fn number(&mut self, function: &mut Function, id: usize, scopes: &mut Vec<HashMap<(QuadOp, Operand, Operand), Operand>>) {
+        scopes.push(HashMap::new());
+        for q in self.blocks[id].start..self.blocks[id].end {
+            let quad = &function.quads[q];
+            if self.deleted[q] || !(quad.op.is_binary() || quad.op == QuadOp::Not) || !self.is_value(quad.result) {
+                continue;
+            }
+            if !self.is_stable(quad.arg1) || (quad.op != QuadOp::Not && !self.is_stable(quad.arg2)) {
+                continue;
+            }
+            let (mut a, mut b) = (quad.arg1, quad.arg2);
+            let commutative = matches!(quad.op, QuadOp::Add | QuadOp::Mul | QuadOp::Eq | QuadOp::NotEq | QuadOp::And | QuadOp::Or);
+            if commutative && format!("{:?}", a) > format!("{:?}", b) {
+                std::mem::swap(&mut a, &mut b);
+            }
+            let key = (quad.op, a, b);
+            match scopes.iter().rev().find_map(|scope| scope.get(&key)).copied() {
+                Some(previous) => {
+                    let quad = &mut function.quads[q];
+                    quad.op = QuadOp::Assign;
+                    quad.arg1 = previous;
+                    quad.arg2 = Operand::None;
+                }
+                None => {
+                    scopes.last_mut().unwrap().insert(key, quad.result);
+                }
+            }
+        }
+        let children: Vec<usize> = (0..self.blocks.len())
+            .filter(|&child| child != id && self.idom[child] == Some(id) && !self.dead_blocks[child])
+            .collect();
+        for child in children {
+            self.number(function, child, scopes);
+        }
+        scopes.pop();
+    }
+
+    // 删除结果不会被用到的运算和 φ 函数；有副作用的四元式和可能除以 0 的除法保留
+    pub fn dce(&mut self, function: &mut Function) {
+        let (defs, _) = self.sites(function);
+        let mut live_quads = vec![false; function.quads.len()];
+        let mut live_phis: HashSet<(usize, usize)> = HashSet::new();
+        let mut work = Vec::new();
+        for (q, quad) in function.quads.iter_mut().enumerate() {
+            if self.deleted[q] {
+                continue;
+            }
+            let safe_division = quad.op != QuadOp::Div || matches!(quad.arg2, Operand::Const(c) if c != 0);
+            let removable = (is_pure(quad.op) || matches!(quad.op, QuadOp::AddrOf | QuadOp::LoadRef))
+                && safe_division
+                && def_mut(quad).is_some_and(|result| self.is_value(*result));
+            if !removable {
+                live_quads[q] = true;
+                work.push(Site::Quad(q));
+            }
+        }
+        while let Some(site) = work.pop() {
+            let operands: Vec<Operand> = match site {
+                Site::Quad(q) => uses_mut(&mut function.quads[q]).into_iter().map(|operand| *operand).collect(),
+                Site::Phi(id, i) => self.phis[id][i].args.clone(),
+            };
+            for operand in operands {
+                match defs.get(&operand) {
+                    Some(&Site::Quad(q)) if !live_quads[q] => {
+                        live_quads[q] = true;
+                        work.push(Site::Quad(q));
+                    }
+                    Some(&Site::Phi(id, i)) if live_phis.insert((id, i)) => work.push(Site::Phi(id, i)),
+                    _ => {}
+                }
+            }
+        }
+        for (q, live) in live_quads.iter().enumerate() {
+            if !live {
+                self.deleted[q] = true;
+            }
+        }
+        for (id, phis) in self.phis.iter_mut().enumerate() {
+            let mut i = 0;
+            phis.retain(|_| {
+                i += 1;
+                live_phis.contains(&(id, i - 1))
+            });
+        }
+    }
+
+    // 退出 SSA：值换回原来的变量，φ 函数改为前驱到该基本块的边上的复制
+    pub fn destruct(mut self, function: &mut Function) {
+        if self.entry_label {
+            self.deleted[0] = true;
+            function.labels -= 1;
+        }
+        // 只保留结果被用到的 φ 函数
+        let mut used: HashSet<Operand> = HashSet::new();
+        for (q, quad) in function.quads.iter_mut().enumerate() {
+            if !self.deleted[q] {
+                used.extend(uses_mut(quad).into_iter().map(|operand| *operand));
+            }
+        }
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (id, phis) in self.phis.iter().enumerate() {
+                if self.dead_blocks[id] {
+                    continue;
+                }
+                for phi in phis {
+                    if used.contains(&phi.result) {
+                        for &arg in &phi.args {
+                            changed |= used.insert(arg);
+                        }
+                    }
+                }
+            }
+        }
+
+        // 每条边上的复制 (目的, 来源)
+        let mut copies: HashMap<(usize, usize), Vec<(Operand, Operand)>> = HashMap::new();
+        for (id, phis) in self.phis.iter().enumerate() {
+            if self.dead_blocks[id] {
+                continue;
+            }
+            for phi in phis.iter().filter(|phi| used.contains(&phi.result)) {
+                for (j, &pred) in self.blocks[id].preds.iter().enumerate() {
+                    if self.dead_blocks[pred] || self.dead_edges.contains(&(pred, id)) {
+                        continue;
+                    }
+                    let source = self.original(phi.args[j]);
+                    if source != phi.var {
+                        copies.entry((pred, id)).or_default().push((phi.var, source));
+                    }
+                }
+            }
+        }
+
+        for quad in &mut function.quads {
+            let operands: Vec<&mut Operand> = vec![&mut quad.arg1, &mut quad.arg2, &mut quad.result];
+            for operand in operands {
+                *operand = self.original(*operand);
+            }
+        }
+
+        let mut temps = self.next_temp;
+        let mut labels = function.labels;
+        let restore = |quads: &[Quad]| -> Vec<Quad> {
+            quads.iter().map(|quad| Quad {
+                arg1: self.original(quad.arg1),
+                arg2: self.original(quad.arg2),
+                result: self.original(quad.result),
+                ..quad.clone()
+            }).collect()
+        };
+        let mut quads = Vec::with_capacity(function.quads.len());
+        let mut split = Vec::new();
+        let mut position = vec![0; function.quads.len() + 1];
+        let label_of = |id: usize, function: &Function| match function.quads[self.blocks[id].start] {
+            Quad { op: QuadOp::Label, result, .. } => result,
+            _ => unreachable!("跳转目标的基本块以标号开始"),
+        };
+        for (id, block) in self.blocks.iter().enumerate() {
+            let kept: Vec<usize> = (block.start..block.end).filter(|&q| !self.deleted[q]).collect();
+            for slot in &mut position[block.start..block.end] {
+                *slot = quads.len();
+            }
+            if self.dead_blocks[id] {
+                continue;
+            }
+            let last = kept.last().map(|&q| function.quads[q].op);
+            let token = kept.last().map_or(0, |&q| function.quads[q].token);
+            let edge = |succ: Option<usize>| succ.and_then(|succ| copies.get(&(id, succ)));
+            let hoisted = self.hoisted.get(&id).map(|list| restore(list)).unwrap_or_default();
+            let branches = matches!(last, Some(QuadOp::Jump | QuadOp::JumpFalse));
+            for (k, &q) in kept.iter().enumerate() {
+                position[q] = quads.len();
+                let is_last = k + 1 == kept.len();
+                if is_last && branches {
+                    quads.extend(hoisted.iter().cloned());
+                }
+                if is_last && last == Some(QuadOp::Jump) {
+                    if let Some(list) = edge(block.jump) {
+                        sequentialize(list, &mut quads, &mut temps, token);
+                    }
+                }
+                let mut quad = function.quads[q].clone();
+                if is_last && last == Some(QuadOp::JumpFalse) {
+                    if let Some(list) = edge(block.jump) {
+                        // 拆出新的基本块：复制后再跳到原来的目标
+                        let target = label_of(block.jump.unwrap(), function);
+                        let label = Operand::Label(labels);
+                        labels += 1;
+                        split.push(Quad { op: QuadOp::Label, arg1: Operand::None, arg2: Operand::None, result: label, token });
+                        sequentialize(list, &mut split, &mut temps, token);
+                        split.push(Quad { op: QuadOp::Jump, arg1: Operand::None, arg2: Operand::None, result: target, token });
+                        quad.result = label;
+                    }
+                }
+                quads.push(quad);
+                if let Some(list) = self.after.get(&q) {
+                    quads.extend(restore(list));
+                }
+            }
+            if !branches {
+                quads.extend(hoisted);
+            }
+            let falls_through = !matches!(last, Some(QuadOp::Jump | QuadOp::Return | QuadOp::Halt));
+            if falls_through {
+                if let Some(list) = edge(block.fallthrough) {
+                    sequentialize(list, &mut quads, &mut temps, token);
+                }
+            }
+        }
+        position[function.quads.len()] = quads.len();
+        quads.extend(split);
+
+        // 没有跳转到的标号（如没有用上的前置基本块）删去
+        let targets: HashSet<Operand> = quads.iter()
+            .filter(|quad| matches!(quad.op, QuadOp::Jump | QuadOp::JumpFalse))
+            .map(|quad| quad.result)
+            .collect();
+        let mut kept = 0;
+        let mut shift = vec![0; quads.len() + 1];
+        for (q, quad) in quads.iter().enumerate() {
+            shift[q] = kept;
+            if quad.op != QuadOp::Label || targets.contains(&quad.result) {
+                kept += 1;
+            }
+        }
+        shift[quads.len()] = kept;
+        quads.retain(|quad| quad.op != QuadOp::Label || targets.contains(&quad.result));
+        for slot in &mut position {
+            *slot = shift[*slot];
+        }
+
+        for start in &mut function.starts {
+            *start = position[(*start).min(function.quads.len())];
+        }
+        function.quads = quads;
+        function.temps = temps;
+        function.labels = labels;
+    }
+}
+
+impl Ssa {
+    // a 是否支配 b
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        let mut runner = b;
+        loop {
+            if runner == a {
+                return true;
+            }
+            match self.idom[runner] {
+                Some(dominator) if dominator != runner => runner = dominator,
+                _ => return false,
+            }
+        }
+    }
+
+    // 自然循环：回边 b -> h（h 支配 b）加上不经过 h 能到达 b 的基本块，同一个 header 的循环合并
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut loops: Vec<Loop> = Vec::new();
+        for (id, block) in self.blocks.iter().enumerate() {
+            if self.dead_blocks[id] {
+                continue;
+            }
+            for &header in &block.succs {
+                if self.dead_blocks[header] || self.dead_edges.contains(&(id, header)) || !self.dominates(header, id) {
+                    continue;
+                }
+                let index = match loops.iter().position(|lp| lp.header == header) {
+                    Some(index) => index,
+                    None => {
+                        loops.push(Loop { header, blocks: vec![header], latches: Vec::new(), preheader: None });
+                        loops.len() - 1
+                    }
+                };
+                let lp = &mut loops[index];
+                lp.latches.push(id);
+                let mut work = vec![id];
+                while let Some(member) = work.pop() {
+                    if lp.blocks.contains(&member) {
+                        continue;
+                    }
+                    lp.blocks.push(member);
+                    work.extend(self.blocks[member].preds.iter().filter(|&&pred| !self.dead_blocks[pred]));
+                }
+            }
+        }
+        for lp in &mut loops {
+            lp.blocks.sort_unstable();
+            let outside: Vec<usize> = self.blocks[lp.header].preds.iter().copied()
+                .filter(|pred| !lp.blocks.contains(pred) && !self.dead_blocks[*pred])
+                .collect();
+            if let [pred] = outside[..] {
+                if self.blocks[pred].succs == [lp.header] {
+                    lp.preheader = Some(pred);
+                }
+            }
+        }
+        loops
+    }
+
+    fn loop_size(&self, lp: &Loop) -> usize {
+        lp.blocks.iter()
+            .map(|&id| (self.blocks[id].start..self.blocks[id].end).filter(|&q| !self.deleted[q]).count()
+                + self.after.iter().filter(|(&q, _)| self.blocks[id].start <= q && q < self.blocks[id].end && !self.deleted[q])
+                    .map(|(_, list)| list.len()).sum::<usize>())
+            .sum()
+    }
+
+    fn loop_name(&self, function: &Function, lp: &Loop) -> String {
+        match function.quads[self.blocks[lp.header].start] {
+            Quad { op: QuadOp::Label, result: Operand::Label(label), .. } => format!("函数 {} 中 L{} 开始的循环", function.name(), label),
+            _ => format!("函数 {} 中的循环", function.name()),
+        }
+    }
+
+    // 循环不变代码外提：运算数都是常量或在循环外定义的值时，运算移到前置基本块。
+    // 只外提结果为临时变量的二元运算和取反，除数可能为 0 的除法不外提，以免循环一次也不执行时出错
+    pub fn licm(&mut self, function: &mut Function, notes: &mut Vec<String>) {
+        let mut loops = self.loops();
+        loops.sort_by_key(|lp| std::cmp::Reverse(lp.blocks.len())); // 外层循环先处理
+        let mut single = HashMap::new();
+        for origin in &self.origin {
+            *single.entry(*origin).or_insert(0) += 1;
+        }
+        for lp in &loops {
+            let Some(preheader) = lp.preheader else { continue };
+            let before = self.loop_size(lp);
+            let mut defined: HashSet<Operand> = HashSet::new();
+            for &id in &lp.blocks {
+                defined.extend(self.phis[id].iter().map(|phi| phi.result));
+                for q in self.blocks[id].start..self.blocks[id].end {
+                    if !self.deleted[q] {
+                        defined.extend(def_mut(&mut function.quads[q]).map(|operand| *operand));
+                    }
+                }
+                for &q in self.after.keys() {
+                    if self.blocks[id].start <= q && q < self.blocks[id].end {
+                        defined.extend(self.after[&q].iter().map(|quad| quad.result));
+                    }
+                }
+            }
+            let mut moved = 0;
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for &id in &lp.blocks {
+                    for q in self.blocks[id].start..self.blocks[id].end {
+                        let quad = &function.quads[q];
+                        let invariant = |operand: Operand| {
+                            operand == Operand::None || (self.is_stable(operand) && !defined.contains(&operand))
+                        };
+                        let safe = quad.op != QuadOp::Div || matches!(quad.arg2, Operand::Const(c) if c != 0);
+                        let temp = matches!(self.original(quad.result), origin @ Operand::Temp(_) if single[&origin] == 1);
+                        if self.deleted[q] || !(quad.op.is_binary() || quad.op == QuadOp::Not) || !safe
+                            || !self.is_value(quad.result) || !temp || !invariant(quad.arg1) || !invariant(quad.arg2) {
+                            continue;
+                        }
+                        defined.remove(&quad.result);
+                        self.hoisted.entry(preheader).or_default().push(quad.clone());
+                        self.deleted[q] = true;
+                        moved += 1;
+                        changed = true;
+                    }
+                }
+            }
+            if moved > 0 {
+                notes.push(format!("{}：外提 {} 条四元式，循环体 {} -> {} 条", self.loop_name(function, lp), moved, before, self.loop_size(lp)));
+            }
+        }
+    }
+
+    // 归纳变量的强度削弱：循环头的 φ 函数 i1 = φ(i0, i2)，循环中 i2 = i1 ± c，
+    // 循环中的 i1 * k、i2 * k 改为读取新变量 j：前置基本块中 j = i0 * k，i2 赋值之后 j = j + c * k
+    pub fn strength_reduce(&mut self, function: &mut Function, notes: &mut Vec<String>) {
+        let (defs, _) = self.sites(function);
+        for lp in self.loops() {
+            let (Some(preheader), &[latch]) = (lp.preheader, &lp.latches[..]) else { continue };
+            if self.blocks[lp.header].preds.len() != 2 {
+                continue;
+            }
+            let before = self.loop_size(&lp);
+            let entry = self.blocks[lp.header].preds.iter().position(|&pred| pred == preheader).unwrap();
+            let back = self.blocks[lp.header].preds.iter().position(|&pred| pred == latch).unwrap();
+            let mut reduced = 0;
+            for i in 0..self.phis[lp.header].len() {
+                let phi = self.phis[lp.header][i].clone();
+                if !matches!(phi.var, Operand::Var(_)) {
+                    continue;
+                }
+                let (current, mut init, next) = (phi.result, phi.args[entry], phi.args[back]);
+                if let Some(&Site::Quad(q)) = defs.get(&init) {
+                    if let Quad { op: QuadOp::Assign, arg1: Operand::Const(c), .. } = function.quads[q] {
+                        init = Operand::Const(c);
+                    }
+                }
+                // i2 = t 这样的复制沿着找到真正的加减法
+                let Some(&Site::Quad(update)) = defs.get(&next) else { continue };
+                let mut step_quad = update;
+                while let Quad { op: QuadOp::Assign, arg1, .. } = function.quads[step_quad] {
+                    match defs.get(&arg1) {
+                        Some(&Site::Quad(q)) => step_quad = q,
+                        _ => break,
+                    }
+                }
+                if !lp.blocks.contains(&self.block_of(update)) {
+                    continue;
+                }
+                let step = match function.quads[step_quad] {
+                    Quad { op: QuadOp::Add, arg1, arg2: Operand::Const(c), .. } if arg1 == current => c,
+                    Quad { op: QuadOp::Add, arg1: Operand::Const(c), arg2, .. } if arg2 == current => c,
+                    Quad { op: QuadOp::Sub, arg1, arg2: Operand::Const(c), .. } if arg1 == current => match c.checked_neg() {
+                        Some(c) => c,
+                        None => continue,
+                    },
+                    _ => continue,
+                };
+                // 同一个归纳变量乘以同一个常数时共用新变量
+                let mut reductions: HashMap<i32, (Operand, Operand)> = HashMap::new();
+                for &id in &lp.blocks {
+                    for q in self.blocks[id].start..self.blocks[id].end {
+                        let quad = &function.quads[q];
+                        if self.deleted[q] || quad.op != QuadOp::Mul || !self.is_value(quad.result) {
+                            continue;
+                        }
+                        let (operand, k) = match (quad.arg1, quad.arg2) {
+                            (operand, Operand::Const(k)) | (Operand::Const(k), operand) => (operand, k),
+                            _ => continue,
+                        };
+                        if operand != current && operand != next {
+                            continue;
+                        }
+                        let Some(increment) = step.checked_mul(k) else { continue };
+                        let (j1, j2) = match reductions.get(&k) {
+                            Some(&pair) => pair,
+                            None => {
+                                let var = Operand::Temp(self.next_temp);
+                                self.next_temp += 1;
+                                let (j0, j1, j2) = (self.new_value(var), self.new_value(var), self.new_value(var));
+                                let token = function.quads[q].token;
+                                let start = match init {
+                                    Operand::Const(c) => match c.checked_mul(k) {
+                                        Some(product) => Quad { op: QuadOp::Assign, arg1: Operand::Const(product), arg2: Operand::None, result: j0, token },
+                                        None => continue,
+                                    },
+                                    _ => Quad { op: QuadOp::Mul, arg1: init, arg2: Operand::Const(k), result: j0, token },
+                                };
+                                self.hoisted.entry(preheader).or_default().push(start);
+                                self.after.entry(update).or_default().push(
+                                    Quad { op: QuadOp::Add, arg1: j1, arg2: Operand::Const(increment), result: j2, token });
+                                let mut args = vec![var; 2];
+                                args[entry] = j0;
+                                args[back] = j2;
+                                self.phis[lp.header].push(Phi { var, result: j1, args });
+                                reductions.insert(k, (j1, j2));
+                                (j1, j2)
+                            }
+                        };
+                        let quad = &mut function.quads[q];
+                        quad.op = QuadOp::Assign;
+                        quad.arg1 = if operand == current { j1 } else { j2 };
+                        quad.arg2 = Operand::None;
+                        reduced += 1;
+                    }
+                }
+            }
+            if reduced > 0 {
+                notes.push(format!("{}：强度削弱 {} 处乘法，循环体 {} -> {} 条", self.loop_name(function, &lp), reduced, before, self.loop_size(&lp)));
+            }
+        }
+    }
+}
+
+// 为没有前置基本块的循环加一个：在循环头的标号前插入新标号，循环外跳到循环头的跳转改为跳到新标号。
+// 回边顺序执行进入循环头的循环（循环体在循环头之前）不处理
+pub fn insert_preheaders(function: &mut Function) {
+    let blocks = blocks(&function.quads);
+    let idom = dominators(&blocks);
+    let dominates = |a: usize, b: usize| {
+        let mut runner = b;
+        loop {
+            if runner == a {
+                return true;
+            }
+            match idom[runner] {
+                Some(dominator) if dominator != runner => runner = dominator,
+                _ => return false,
+            }
+        }
+    };
+    let mut inserts = Vec::new();
+    for (header, block) in blocks.iter().enumerate() {
+        if idom[header].is_none() {
+            continue;
+        }
+        let (latches, outside): (Vec<usize>, Vec<usize>) = block.preds.iter()
+            .filter(|&&pred| idom[pred].is_some())
+            .partition(|&&pred| dominates(header, pred));
+        if latches.is_empty() || (outside.len() == 1 && blocks[outside[0]].succs == [header]) {
+            continue;
+        }
+        let Quad { op: QuadOp::Label, result: target, .. } = function.quads[block.start] else { continue };
+        if latches.iter().any(|&latch| blocks[latch].fallthrough == Some(header)) {
+            continue;
+        }
+        let label = Operand::Label(function.labels);
+        function.labels += 1;
+        for &pred in &outside {
+            let last = &mut function.quads[blocks[pred].end - 1];
+            if matches!(last.op, QuadOp::Jump | QuadOp::JumpFalse) && last.result == target {
+                last.result = label;
+            }
+        }
+        inserts.push((block.start, Quad { op: QuadOp::Label, arg1: Operand::None, arg2: Operand::None, result: label, token: function.quads[block.start].token }));
+    }
+    for (position, quad) in inserts.into_iter().rev() {
+        function.quads.insert(position, quad);
+        for start in &mut function.starts {
+            if *start >= position {
+                *start += 1;
+            }
+        }
+    }
+}
+
+// 把同一条边上的一组并行复制排成顺序执行的复制：目的还要被其它复制读取时先做那些复制，
+// 形成环时借助一个新的临时变量
+fn sequentialize(copies: &[(Operand, Operand)], out: &mut Vec<Quad>, temps: &mut usize, token: usize) {
+    let mut pending: Vec<(Operand, Operand)> = copies.to_vec();
+    let copy = |out: &mut Vec<Quad>, dst: Operand, src: Operand| {
+        out.push(Quad { op: QuadOp::Assign, arg1: src, arg2: Operand::None, result: dst, token });
+    };
+    while !pending.is_empty() {
+        match pending.iter().position(|&(dst, _)| !pending.iter().any(|&(_, src)| src == dst)) {
+            Some(i) => {
+                let (dst, src) = pending.remove(i);
+                copy(out, dst, src);
+            }
+            None => {
+                let (dst, _) = pending[0];
+                let temp = Operand::Temp(*temps);
+                *temps += 1;
+                copy(out, temp, dst);
+                for (_, src) in &mut pending {
+                    if *src == dst {
+                        *src = temp;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// 各基本块的直接支配者（Cooper、Harvey、Kennedy 的迭代算法）
+pub fn dominators(blocks: &[Block]) -> Vec<Option<usize>> {
+    let mut order = Vec::new(); // 逆后序
+    let mut visited = vec![false; blocks.len()];
+    if !blocks.is_empty() {
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some(&mut (id, ref mut next)) = stack.last_mut() {
+            if let Some(&succ) = blocks[id].succs.get(*next) {
+                *next += 1;
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                order.push(id);
+                stack.pop();
+            }
+        }
+    }
+    order.reverse();
+    let mut rank = vec![usize::MAX; blocks.len()];
+    for (i, &id) in order.iter().enumerate() {
+        rank[id] = i;
+    }
+
+    let mut idom: Vec<Option<usize>> = vec![None; blocks.len()];
+    if order.is_empty() {
+        return idom;
+    }
+    idom[0] = Some(0);
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &id in order.iter().skip(1) {
+            let mut new_idom: Option<usize> = None;
+            for &pred in &blocks[id].preds {
+                if idom[pred].is_none() {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    None => pred,
+                    Some(other) => {
+                        let (mut a, mut b) = (pred, other);
+                        while a != b {
+                            while rank[a] > rank[b] {
+                                a = idom[a].unwrap();
+                            }
+                            while rank[b] > rank[a] {
+                                b = idom[b].unwrap();
+                            }
+                        }
+                        a
+                    }
+                });
+            }
+            if new_idom != idom[id] {
+                idom[id] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+// 支配边界
+pub fn frontiers(blocks: &[Block], idom: &[Option<usize>]) -> Vec<Vec<usize>> {
+    let mut frontiers: Vec<Vec<usize>> = vec![Vec::new(); blocks.len()];
+    for (id, block) in blocks.iter().enumerate() {
+        let Some(dominator) = idom[id] else { continue };
+        if block.preds.len() < 2 {
+            continue;
+        }
+        for &pred in &block.preds {
+            let mut runner = pred;
+            while idom[runner].is_some() && runner != dominator {
+                if !frontiers[runner].contains(&id) {
+                    frontiers[runner].push(id);
+                }
+                runner = idom[runner].unwrap();
+            }
+        }
+    }
+    frontiers
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::ops::{Index, IndexMut};
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub enum CategorySymbol {
+    Variable,
+    Function,
+    Constant,
+}
+
+impl Default for CategorySymbol {
+    fn default() -> Self {
+        CategorySymbol::Function // 假设默认值为 Function
+    }
+}
+
+// 变量、常量、函数返回值与表达式的类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Type {
+    #[default]
+    Int,
+    Char,
+    Bool,
+    Void,
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Type::Int => "int",
+            Type::Char => "char",
+            Type::Bool => "bool",
+            Type::Void => "void",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug)]
+#[derive(Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: CategorySymbol,
+    pub address: i32,
+    pub action_function: String,
+    pub var_num: i32, // 参数数量，若本身为变量，则此值为0
+    pub value: i32,   // 常量的值，仅对常量有效
+    pub ty: Type,     // 变量或常量的类型，函数则为返回类型
+    pub by_ref: bool, // 是否为引用形参，引用形参中存放的是实参的地址
+    pub param: bool,  // 是否为形参
+    pub decl_token: usize, // 定义处的单词在单词流中的下标
+    pub reads: u32,   // 被读取的次数，函数则为被调用的次数
+    pub writes: u32,  // 被赋值的次数
+}
+
+impl Default for Symbol {
+    fn default() -> Self {
+        Symbol {
+            name: String::new(),               // 默认空字符串
+            kind: CategorySymbol::default(),    // 使用 CategorySymbol 默认值
+            address: 0,                         // 默认值为 0
+            action_function: String::new(),     // 默认空字符串
+            var_num: 0,                         // 默认值为 0
+            value: 0,                           // 默认值为 0
+            ty: Type::default(),                // 默认为 int
+            by_ref: false,                      // 默认按值传递
+            param: false,
+            decl_token: 0,
+            reads: 0,
+            writes: 0,
+        }
+    }
+}
+
+// 分作用域的符号表
+// 所有符号按定义的先后存放在 symbols 中，下标就是符号的编号，作用域退出后符号仍然保留，
+// 便于输出符号表；scopes 是作用域栈，栈底为全局作用域（函数名），其上依次是函数作用域
+// （形参与局部变量）和各层复合语句的块作用域，每层用名字到编号的 HashMap 查找
+#[derive(Debug)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    scopes: Vec<HashMap<String, usize>>,
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable {
+            symbols: Vec::new(),
+            scopes: vec![HashMap::new()], // 全局作用域
+        }
+    }
+
+    // 进入新的作用域
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    // 退出当前作用域，全局作用域不会被弹出
+    pub fn pop_scope(&mut self) {
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    // 当前作用域的层数，全局作用域为 0
+    pub fn depth(&self) -> usize {
+        self.scopes.len() - 1
+    }
+
+    // 在当前作用域中登记符号，返回它的编号；同名符号已在当前作用域中时返回已有符号的编号
+    pub fn insert(&mut self, symbol: Symbol) -> Result<usize, usize> {
+        let scope = self.scopes.last_mut().unwrap();
+        if let Some(&pos) = scope.get(&symbol.name) {
+            return Err(pos);
+        }
+        let pos = self.symbols.len();
+        scope.insert(symbol.name.clone(), pos);
+        self.symbols.push(symbol);
+        Ok(pos)
+    }
+
+    // 从内层作用域向外查找名字，返回符号的编号
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    // 从内层作用域向外查找名字
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        self.position(name).map(|pos| &self.symbols[pos])
+    }
+
+    // 只在全局作用域中查找，用于查找函数名
+    pub fn global_position(&self, name: &str) -> Option<usize> {
+        self.scopes[0].get(name).copied()
+    }
+
+    // 当前作用域中定义的符号，按定义的先后排列
+    pub fn symbols_in_scope(&self) -> Vec<&Symbol> {
+        let mut positions: Vec<usize> = self.scopes.last().unwrap().values().copied().collect();
+        positions.sort_unstable();
+        positions.into_iter().map(|pos| &self.symbols[pos]).collect()
+    }
+
+    // 已登记的符号总数
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    // 按定义的先后遍历所有符号，包括已退出作用域中的符号
+    pub fn iter(&self) -> std::slice::Iter<'_, Symbol> {
+        self.symbols.iter()
+    }
+}
+
+impl Index<usize> for SymbolTable {
+    type Output = Symbol;
+
+    fn index(&self, pos: usize) -> &Symbol {
+        &self.symbols[pos]
+    }
+}
+
+impl IndexMut<usize> for SymbolTable {
+    fn index_mut(&mut self, pos: usize) -> &mut Symbol {
+        &mut self.symbols[pos]
+    }
+}
+
+impl<'a> IntoIterator for &'a SymbolTable {
+    type Item = &'a Symbol;
+    type IntoIter = std::slice::Iter<'a, Symbol>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.symbols.iter()
+    }
+}
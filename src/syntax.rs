@@ -5,21 +5,46 @@ use std::vec::Vec;
 use std::fmt::Write as FmtWrite;
 use std::{clone, str};
 use serde::{Serialize, Deserialize};
+use crate::symbol_table::{CategorySymbol, Symbol, SymbolTable, Type};
+use crate::frame::{FrameBuilder, FrameLayout, SlotKind};
+use crate::diagnostics::{Diagnostics, WarningConfig, WarningKind};
+use crate::dataflow;
+use crate::interval::{self, Hazard};
+use crate::cfg::Cfg;
+use crate::callgraph::CallGraph;
+use crate::ir;
+use crate::bytecode;
+use crate::optimize::{self, OptimizeError, PassManager};
+
+pub const MAX_SYMBOL_INDEX: usize = 10000;  // 符号表默认的最大符号数
+pub const MAX_CODE_INDEX: usize = 100000;   // 默认的最大中间代码条数
+
+// 类型关键字对应的类型，void 只能作为函数返回类型
+fn type_of_keyword(token: &str) -> Option<Type> {
+    match token {
+        "int" => Some(Type::Int),
+        "char" => Some(Type::Char),
+        "bool" => Some(Type::Bool),
+        "void" => Some(Type::Void),
+        _ => None,
+    }
+}
 
-const MAX_SYMBOL_INDEX: usize = 100;  // 定义符号表的容量
-const MAX_CODE_INDEX: usize = 200;    // 中间代码数组的容量
+// int 与 char 可以参与算术运算和大小比较
+fn is_numeric(ty: Type) -> bool {
+    ty == Type::Int || ty == Type::Char
+}
 
-#[derive(Debug)]
-#[derive(Clone)]
-enum CategorySymbol {
-    Variable,
-    Function,
+// 判断 found 类型的值能否赋给 expected 类型的变量：类型相同，或者把 char 赋给 int
+fn is_assignable(expected: Type, found: Type) -> bool {
+    expected == found || (expected == Type::Int && found == Type::Char)
 }
 
 fn are_kinds_equal(kind1: CategorySymbol, kind2: CategorySymbol) -> bool {
     match (kind1, kind2) {
         (CategorySymbol::Variable, CategorySymbol::Variable) => true,
         (CategorySymbol::Function, CategorySymbol::Function) => true,
+        (CategorySymbol::Constant, CategorySymbol::Constant) => true,
         _ => false,
     }
 }
@@ -44,46 +69,30 @@ impl Node {
 }
 
 #[derive(Debug)]
-#[derive(Clone)]
-pub struct Symbol {
-    name: String,
-    kind: CategorySymbol,
-    address: i32,
-    action_function: String,
-    var_num: i32, // 参数数量，若本身为变量，则此值为0
-}
+#[derive(Serialize, Deserialize)]
 
-impl Default for CategorySymbol {
-    fn default() -> Self {
-        CategorySymbol::Function // 假设默认值为 Function
-    }
+pub struct Code {
+    pub opt: String,  // 操作码
+    pub operand: i32,  // 操作数
+    pub token: usize,  // 生成这条指令时正在分析的单词在单词流中的下标，用于报告位置
 }
-
-impl Default for Symbol {
-    fn default() -> Self {
-        Symbol {
-            name: String::new(),               // 默认空字符串
-            kind: CategorySymbol::default(),    // 使用 CategorySymbol 默认值
-            address: 0,                         // 默认值为 0
-            action_function: String::new(),     // 默认空字符串
-            var_num: 0,                         // 默认值为 0
+impl Code {
+    // 文本形式的中间代码中的一行，没有操作数的指令不输出操作数
+    pub fn listing(&self, address: usize) -> String {
+        if ["LOAD", "LOADI", "LOADA", "LOADR", "STO", "STOR", "BR", "BRF", "CAL", "ENTER", "OUTS"].contains(&self.opt.as_str())
+            || (self.opt == "RETURN" && self.operand != 0) {
+            format!(" {:3} {:<5} {:<3}", address, self.opt, self.operand)
+        } else {
+            format!(" {:3} {:<5}", address, self.opt)
         }
     }
 }
-
-
-#[derive(Debug)]
-#[derive(Serialize, Deserialize)]
-
-pub struct Code {
-    opt: String,  // 操作码
-    operand: i32,  // 操作数
-}
 impl Clone for Code {
     fn clone(&self) -> Self {
         Code {
             opt: self.opt.clone(),
             operand: self.operand,
+            token: self.token,
         }
     }
 }
@@ -92,6 +101,7 @@ impl Default for Code {
         Code {
             opt: String::new(),  // 空字符串作为默认值
             operand: 0,          // 默认操作数为 0
+            token: 0,
         }
     }
 }
@@ -102,6 +112,7 @@ pub struct Compiler {
     token: String,
     token1: String,
     tokenfile: String,
+    sourcefile: String,          // 源程序文件，用于在控制流图中列出源程序的行
     codeout: String,
     codeout2:String,
     syntaxtree: String,
@@ -110,15 +121,33 @@ pub struct Compiler {
     fp_code_text: Option<File>,   // 中间代码文本文件指针
     fp_syntaxtree: Option<File>,  // 语法树文件指针
     codes: Vec<Code>,            // 中间代码数组
-    symbol: Vec<Symbol>,         // 符号表
-    symbol_index: usize,         // 符号表当前索引
+    symbol: SymbolTable,         // 符号表
     codes_index: usize,          // 中间代码数组当前索引
     es: i32,                     // 错误码
     root: Option<Node>,          // 语法树根节点
     Lastdefinedfunction: String,
     numofvariable:usize,
-    offset:i32,
+    frame: FrameBuilder,         // 当前函数的栈帧布局
+    frames: Vec<FrameLayout>,    // 已分析完的各函数的栈帧布局
+    diagnostics: Diagnostics,    // 编译过程中产生的警告
+    calls: Vec<(usize, usize)>,  // 函数调用关系：(主调函数, 被调函数) 在符号表中的编号
     line_num:usize,
+    expr_type: Type,             // 最近一次分析的表达式的类型
+    strings: Vec<String>,        // 字符串常量池
+    return_type: Type,           // 当前函数的返回类型
+    has_return: bool,            // 当前函数中是否出现过 return 语句
+    error_token: Option<usize>,  // 出错单词在单词流中的下标，为 None 时取当前单词
+    type_expected: Type,         // 类型不匹配时需要的类型
+    type_found: Type,            // 类型不匹配时实际的类型
+    max_symbols: usize,          // 符号表最多容纳的符号数
+    max_codes: usize,            // 最多生成的中间代码条数
+    codes_full: bool,            // 指令条数已达上限，之后的指令不再追加
+    zero_divisors: Vec<usize>,   // 除数恒为 0 的除法运算符在单词流中的下标
+    passes: PassManager,         // 代码生成后运行的优化遍
+    pass_error: String,          // 优化遍之后跳转目标无效时的说明
+    emit_ir: bool,               // 是否要输出四元式（--emit-ir）
+    ir_error: String,            // 栈式代码无法翻译为四元式时的说明
+    ir: Option<ir::Module>,      // 由中间代码翻译得到的四元式
 }
 
 // fn string_to_u8_array(s: &str) -> [u8; 10] {
@@ -131,17 +160,6 @@ pub struct Compiler {
 
 //     arr  // 返回字节数组
 // }
-/// 将字符串转换为 `[u8; 10]`
-fn string_to_u8_array(input: &str) -> [u8; 10] {
-    let mut array = [0u8; 10]; // 初始化一个长度为 10 的数组，默认值为 0
-    let bytes = input.as_bytes(); // 将字符串转换为字节切片
-
-    let len = bytes.len().min(10);
-    array[..len].copy_from_slice(&bytes[..len]);
-
-    array
-    }
-    
     /// 将 `[u8; 10]` 写入文件
     fn write_u8_array_to_file(file_path: &str, array: &[u8; 10]) -> io::Result<()> {
     let mut file = File::create(file_path)?; // 打开或创建目标文件
@@ -151,6 +169,51 @@ fn string_to_u8_array(input: &str) -> [u8; 10] {
     
 
     
+// 将单词流中带引号的字符串常量还原为实际内容，转义序列已由词法分析检查过
+fn unescape_string(literal: &str) -> String {
+    let inner = literal
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .unwrap_or(literal);
+    let mut text = String::new();
+    let mut chars = inner.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '\\' {
+            text.push(ch);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => text.push('\n'),
+            Some('t') => text.push('\t'),
+            Some('0') => text.push('\0'),
+            Some(other) => text.push(other), // \\ 与 \"
+            None => {}
+        }
+    }
+    text
+}
+
+// 读取单词流第 index 个单词的位置（行, 列），找不到时返回 None
+fn token_position(tokenfile: &str, index: usize) -> Option<(usize, usize)> {
+    let file = File::open(tokenfile).ok()?;
+    let line = io::BufReader::new(file).lines().nth(index)?.ok()?;
+    parse_position(&line)
+}
+
+// 单词流中每个单词的位置（行, 列），位置无法解析的单词为 None
+fn token_positions(tokenfile: &str) -> Vec<Option<(usize, usize)>> {
+    std::fs::read_to_string(tokenfile).unwrap_or_default()
+        .lines()
+        .map(parse_position)
+        .collect()
+}
+
+// 单词流中的一行以单词的位置 行:列 开头
+fn parse_position(line: &str) -> Option<(usize, usize)> {
+    let (row, col) = line.split_whitespace().next()?.split_once(':')?;
+    Some((row.parse().ok()?, col.parse().ok()?))
+}
+
 fn fscanf_token(tokenfile: &str, line_num: &mut usize) -> io::Result<(String, String)> {
     // 定义 token 和 token1
     let mut token = String::new();
@@ -165,14 +228,16 @@ fn fscanf_token(tokenfile: &str, line_num: &mut usize) -> io::Result<(String, St
         // 只读取当前行
         if current_line == *line_num {
             let line = line?; // 解包错误
-            let mut parts = line.split_whitespace(); // 按空白字符拆分
-
-            if let Some(first) = parts.next() {
-                token = first.to_string(); // 第一个值赋给 token
-            }
-            if let Some(second) = parts.next() {
-                token1 = second.to_string(); // 第二个值赋给 token1
-                // println!("token:{},token1:{}", token, token1);
+            // 每行以单词位置（行:列）开头，跳过它
+            let line = line.trim_start();
+            let line = line.split_once(char::is_whitespace).map_or("", |(_, rest)| rest.trim_start());
+            // 第一个值赋给 token，其余部分赋给 token1（字符串常量中可能含有空白）
+            match line.split_once(char::is_whitespace) {
+                Some((first, rest)) => {
+                    token = first.to_string();
+                    token1 = rest.trim().to_string();
+                }
+                None => token = line.to_string(),
             }
             break;
         }
@@ -218,17 +283,32 @@ fn fun_declaration(compiler: &mut Compiler) -> i32 {
     let root=compiler.root.as_mut().unwrap();
     root.add_child(child_node);
     // println!("!!!!!!!!!!!token:{},token1:{}", compiler.token, compiler.token1);
+    // 函数名前可以写返回类型，省略时为 void
+    let mut return_type = Type::Void;
+    if let Some(ty) = type_of_keyword(&compiler.token) {
+        return_type = ty;
+        let (new_token, new_token1) = fscanf_token(&compiler.tokenfile,&mut compiler.line_num).unwrap(); // 解构返回的元组
+        compiler.token = new_token;  // 分别赋值
+        compiler.token1 = new_token1;
+    }
+
     if compiler.token != "ID" {
-        es = 2;
+        es = 3;
         return es;
     }
 
     child_node = Node::new("<fun_declaration>");
     compiler.add_child(&mut child_node);
     let temp_token = compiler.token1.clone();
-    compiler.insert_symbol(CategorySymbol::Function, &temp_token); // 将函数名插入符号表
+    es = compiler.insert_symbol(CategorySymbol::Function, &temp_token); // 将函数名插入符号表
+    if es > 0 {
+        return es;
+    }
+    let fun_pos = compiler.symbol.len() - 1;
+    compiler.symbol[fun_pos].ty = return_type;
+    compiler.return_type = return_type;
+    compiler.has_return = false;
 
-    let temp = String::from(&compiler.token1);
     let (new_token, new_token1) = fscanf_token(&compiler.tokenfile,&mut compiler.line_num).unwrap(); // 解构返回的元组
     compiler.token = new_token;  // 分别赋值
     compiler.token1 = new_token1;
@@ -243,20 +323,15 @@ fn fun_declaration(compiler: &mut Compiler) -> i32 {
     compiler.token = new_token;  // 分别赋值
     compiler.token1 = new_token1;
 
-    compiler.symbol[compiler.symbol_index - 1].address = compiler.codes_index as i32; // 将函数体的入口地址填入符号表中的地址
+    compiler.symbol[fun_pos].address = compiler.codes_index as i32; // 将函数体的入口地址填入符号表中的地址
+    compiler.frame.begin(compiler.codes_index);
+    compiler.symbol.push_scope(); // 形参与局部变量属于函数作用域
     es = compiler.parameter_list(&mut child_node);
     if es > 0 {
         return es;
     }
 
-    let mut symbol_pos = 0;
-    es = compiler.lookup(&temp, &mut symbol_pos, &compiler.Lastdefinedfunction);
-    if es > 0 {
-        return es;
-    }
-
-    let mut symbol_pos_usize = symbol_pos as usize;
-    compiler.symbol[symbol_pos_usize].var_num = compiler.numofvariable as i32;
+    compiler.symbol[fun_pos].var_num = compiler.numofvariable as i32;
     // println!("es=6 token:{},token1:{}", compiler.token, compiler.token1);
     if compiler.token != ")" {
         es = 6;
@@ -269,6 +344,10 @@ fn fun_declaration(compiler: &mut Compiler) -> i32 {
     compiler.token = new_token;  // 分别赋值
     compiler.token1 = new_token1;
     es = compiler.function_body(&mut child_node);
+    if es == 0 {
+        compiler.check_scope_usage();
+    }
+    compiler.symbol.pop_scope();
 
     es
 }
@@ -280,7 +359,15 @@ fn main_declaration(compiler: &mut Compiler) -> i32 {
     let root=compiler.root.as_mut().unwrap();
     root.add_child(child_node);
 
-    compiler.insert_symbol(CategorySymbol::Function, "main");
+    es = compiler.insert_symbol(CategorySymbol::Function, "main");
+    if es > 0 {
+        return es;
+    }
+    // main 可以返回一个整数作为程序的退出码，省略 return 时退出码为 0
+    let fun_pos = compiler.symbol.len() - 1;
+    compiler.symbol[fun_pos].ty = Type::Int;
+    compiler.return_type = Type::Int;
+    compiler.has_return = false;
 
     if compiler.token != "(" {
         es = 5;
@@ -294,7 +381,9 @@ fn main_declaration(compiler: &mut Compiler) -> i32 {
     compiler.token = new_token;  // 分别赋值
     compiler.token1 = new_token1;
 
-    compiler.symbol[compiler.symbol_index - 1].address = compiler.codes_index as i32; // 填写函数体地址
+    compiler.symbol[fun_pos].address = compiler.codes_index as i32; // 填写函数体地址
+    compiler.frame.begin(compiler.codes_index);
+    compiler.symbol.push_scope();
     es = compiler.parameter_list(&mut child_node);
 
     if es > 0 {
@@ -315,6 +404,10 @@ fn main_declaration(compiler: &mut Compiler) -> i32 {
     compiler.token1 = new_token1;
 
     es = compiler.function_body(&mut child_node);
+    if es == 0 {
+        compiler.check_scope_usage();
+    }
+    compiler.symbol.pop_scope();
 
     es
 }
@@ -326,6 +419,7 @@ impl Compiler {
             token: String::new(),
             token1: String::new(),
             tokenfile: String::new(),
+            sourcefile: String::new(),
             codeout: String::new(),
             codeout2:String::new(),
             syntaxtree: String::new(),
@@ -333,16 +427,34 @@ impl Compiler {
             fp_code_binary: None,
             fp_code_text: None,
             fp_syntaxtree: None,
-            codes: vec![Code::default(); 200],
-            symbol: vec![Symbol::default(); 200],
-            symbol_index: 0,
+            codes: Vec::new(),
+            symbol: SymbolTable::new(),
             codes_index: 0,
             es: 0,
             root: None,
             Lastdefinedfunction: String::new(),
             numofvariable:0,
-            offset:0,
+            frame: FrameBuilder::default(),
+            frames: Vec::new(),
+            diagnostics: Diagnostics::default(),
+            calls: Vec::new(),
             line_num:0,
+            expr_type: Type::Int,
+            strings: Vec::new(),
+            return_type: Type::Void,
+            has_return: false,
+            error_token: None,
+            type_expected: Type::Int,
+            type_found: Type::Int,
+            max_symbols: MAX_SYMBOL_INDEX,
+            max_codes: MAX_CODE_INDEX,
+            codes_full: false,
+            zero_divisors: Vec::new(),
+            passes: PassManager::for_level(optimize::DEFAULT_LEVEL),
+            pass_error: String::new(),
+            emit_ir: false,
+            ir_error: String::new(),
+            ir: None,
         }
     }
 
@@ -351,10 +463,82 @@ impl Compiler {
         self.tokenfile = filename;
     }
 
+    pub fn set_sourcefile(&mut self, filename: String) {//设置源程序文件
+        self.sourcefile = filename;
+    }
+
+    // 各函数的控制流图，按函数定义的先后排列
+    pub fn cfgs(&self) -> Vec<Cfg> {
+        self.frames.iter()
+            .map(|frame| Cfg::build(&self.codes, &frame.function, frame.entry, frame.end))
+            .collect()
+    }
+
+    // 把各函数的控制流图写成一个 DOT 文件，每个函数是一个子图，基本块中标出源程序的行
+    pub fn write_cfg_dot(&self, path: &str) -> io::Result<()> {
+        let source = std::fs::read_to_string(&self.sourcefile).unwrap_or_default();
+        let lines: Vec<&str> = source.lines().collect();
+        // 单词流中每个单词所在的行
+        let rows: Vec<usize> = std::fs::read_to_string(&self.tokenfile).unwrap_or_default()
+            .lines()
+            .map(|line| line.split(':').next().and_then(|row| row.parse().ok()).unwrap_or(0))
+            .collect();
+        let source_line = |token: usize| {
+            let row = *rows.get(token)?;
+            lines.get(row.checked_sub(1)?).map(|text| (row, text.to_string()))
+        };
+        let mut dot = String::from("digraph cfg {\n");
+        for cfg in self.cfgs() {
+            cfg.write_dot(&mut dot, &self.codes, &source_line);
+        }
+        dot.push_str("}\n");
+        std::fs::write(path, dot)
+    }
+
+    // 由最终的指令和栈帧布局建立调用图
+    pub fn call_graph(&self) -> CallGraph {
+        CallGraph::build(&self.codes, &self.frames)
+    }
+
+    // 把调用图的报告写入 text，DOT 形式写入 dot
+    pub fn write_call_graph(&self, text: &str, dot: &str) -> io::Result<()> {
+        let graph = self.call_graph();
+        std::fs::write(text, graph.report())?;
+        std::fs::write(dot, graph.write_dot())
+    }
+
+    // 把四元式以文本形式写入 path，编译失败时没有四元式
+    pub fn write_ir(&self, path: &str) -> io::Result<()> {
+        match &self.ir {
+            Some(module) => std::fs::write(path, module.dump()),
+            None => Ok(()),
+        }
+    }
+
     pub fn set_codeout(&mut self, filename: String) {    //设置codeout
 
         self.codeout = filename;
     }
+    pub fn set_warnings(&mut self, config: WarningConfig) {    //设置各种警告的级别
+        self.diagnostics.config = config;
+    }
+
+    pub fn set_max_symbols(&mut self, max_symbols: usize) {    //设置符号表容量
+        self.max_symbols = max_symbols;
+    }
+
+    pub fn set_max_codes(&mut self, max_codes: usize) {    //设置中间代码条数上限
+        self.max_codes = max_codes;
+    }
+
+    pub fn set_passes(&mut self, passes: PassManager) {    //设置代码生成后运行的优化遍
+        self.passes = passes;
+    }
+
+    pub fn set_emit_ir(&mut self, emit_ir: bool) {    //设置是否输出四元式
+        self.emit_ir = emit_ir;
+    }
+
     pub fn set_codeout2(&mut self, filename: String) {    //设置codeout
 
         self.codeout2 = filename;
@@ -386,6 +570,27 @@ impl Compiler {
         // if es != 0 {
         //     return es;
         // }
+        if es == 0 {
+            self.check_unused_functions();
+        }
+        // 除数恒为 0 与优化级别无关，总是编译错误；错误已经报告，不再重复警告
+        if es == 0 && !self.zero_divisors.is_empty() {
+            for &token in &self.zero_divisors {
+                let position = token_position(&self.tokenfile, token);
+                self.diagnostics.retract(WarningKind::DivisionByZero, position);
+            }
+            es = 53;
+        }
+        if es == 0 && !self.diagnostics.has_errors() {
+            es = self.optimize();
+        }
+        // 优化之后再输出警告：常量折叠发现的除数为 0 已作为错误报告，不再重复警告
+        for item in self.diagnostics.items() {
+            println!("{}", item);
+        }
+        if es == 0 && self.diagnostics.has_errors() {
+            es = 52; // 被 -D 提升为错误的警告
+        }
         self.es=es;
         println!("==语法、语义分析及代码生成程序结果==");
         match self.es {
@@ -401,7 +606,7 @@ impl Compiler {
             11 => println!("函数开头缺少{{!"),
             12 => println!("函数结束缺少}}!"),
             13 => println!("最后一个函数的名字应该是main!"),
-            21 => println!("符号表溢出!"),
+            21 => println!("符号表溢出，最多只能有 {} 个符号!", self.max_symbols),
             22 => println!("变量 {} 重复定义!", self.token1),
             23 => println!("变量未声明!"),
             24 => println!("程序中main函数结束后，还有其它多余字符"),
@@ -413,8 +618,38 @@ impl Compiler {
             36 => println!("赋值语句的左值 {} 不是变量名!", self.token1),
             37 => println!("因子对应的标识符不是变量名!"),
             38 => println!("函数传入的参数数量不对!"),
+            39 => println!("常量表达式中的标识符 {} 不是常量!", self.token1),
+            40 => println!("常量表达式中除数为0!"),
+            41 => println!("不能给常量 {} 赋值!", self.token1),
+            42 => println!("const后面缺少int或char!"),
+            43 => println!("常量定义缺少=!"),
+            44 => println!("条件表达式缺少:!"),
+            45 => println!("类型不匹配：需要{}类型，实际为{}类型!", self.type_expected, self.type_found),
+            46 => println!("条件表达式必须是bool类型，实际为{}类型!", self.type_found),
+            47 => println!("非void函数 {} 缺少return语句!", self.Lastdefinedfunction),
+            48 => println!("无法识别的语句 {}!", self.token1),
+            49 => println!("void类型的表达式没有值，不能使用!"),
+            50 => println!("引用参数只能传入变量，不能传入常量或表达式!"),
+            51 => println!("中间代码过长，最多只能生成 {} 条指令!", self.max_codes),
+            52 => println!("存在被 -D 选项当作错误的警告!"),
+            53 => println!("表达式中的除数恒为0!"),
+            54 => println!("优化遍 {} 之后跳转目标无效!", self.pass_error),
+            55 => println!("中间代码无法翻译为四元式：{}!", self.ir_error),
             _ => {}
         }
+        // 输出错误位置，除数恒为 0 时输出每一处
+        if self.es == 53 {
+            for &token in &self.zero_divisors {
+                if let Some((row, col)) = token_position(&self.tokenfile, token) {
+                    println!("错误位置：第{}行第{}列", row, col);
+                }
+            }
+        } else if self.es != 0 && self.es != 10 && self.es != 52 && self.es != 54 && self.es != 55 {
+            let index = self.error_token.unwrap_or(self.line_num.saturating_sub(1));
+            if let Some((row, col)) = token_position(&self.tokenfile, index) {
+                println!("错误位置：第{}行第{}列", row, col);
+            }
+        }
         // println!("请输入要生成的文本形式的中间代码文件的名字（包括路径）：");
         // let mut codeout = String::new();
         // io::stdin().read_line(&mut codeout).unwrap();
@@ -432,10 +667,13 @@ impl Compiler {
         };
 
         for i in 0..self.codes_index {
-            if ["LOAD", "LOADI", "STO", "BR", "BRF", "CAL", "ENTER"].contains(&self.codes[i].opt.as_str()) {
-                writeln!(fp_code_text, " {:3} {:<5} {:<3}", i, self.codes[i].opt, self.codes[i].operand).unwrap();
-            } else {
-                writeln!(fp_code_text, " {:3} {:<5}", i, self.codes[i].opt).unwrap();
+            writeln!(fp_code_text, "{}", self.codes[i].listing(i)).unwrap();
+        }
+        // 输出字符串常量池
+        if !self.strings.is_empty() {
+            writeln!(fp_code_text, " 常量池").unwrap();
+            for (i, text) in self.strings.iter().enumerate() {
+                writeln!(fp_code_text, " {:3} {:?}", i, text).unwrap();
             }
         }
 
@@ -480,22 +718,18 @@ impl Compiler {
             }
         };
 
-        // 写入每个 Code 对象的二进制数据
-        for code in &self.codes {
-            if code.opt.is_empty() {
-                break; // 如果为空，则停止输出
-            }
-            let code_opt=string_to_u8_array(&code.opt);
-            // let opt_bytes = code_opt.as_bytes();  // 将字符串转为字节数组
-            //println!("长度{}", &code_opt.len());
-            fp_code_binary.write_all(&code_opt);  // 写入操作码
-            let padding = [0u8; 2]; 
-            fp_code_binary.write_all(&padding); 
-
-            
-            // write_u8_array_to_file(&codeout,&code_opt);
-            fp_code_binary.write_all(&code.operand.to_le_bytes());  // 写入操作数
-            // println!("1111长度{}", &code.operand..to_le_bytes().len());
+        // 写入二进制文件，格式见 bytecode.rs；各函数的栈帧布局和每条指令在源程序中的位置作为调试信息一并写入，
+        // 虚拟机用它们在跟踪输出和运行错误中显示函数、行和变量名
+        let positions = token_positions(&self.tokenfile);
+        let lines: Vec<Option<(usize, usize)>> = self.codes.iter()
+            .map(|code| positions.get(code.token).copied().flatten())
+            .collect();
+        let written = bytecode::encode(&self.codes, &self.strings, &self.frames, &lines)
+            .and_then(|bytes| fp_code_binary.write_all(&bytes));
+        if let Err(e) = written {
+            println!("\n写入 {} 错误!{}", self.codeout2, e);
+            self.es = 10;
+            return self.es;
         }
 
 
@@ -540,9 +774,7 @@ impl Compiler {
 
 
         // 添加无条件跳转指令，跳转到 main 函数入口
-        self.codes[self.codes_index].opt = "BR".to_string();
-        // self.codes[self.codes_index].operand = String::from("main");  // 假设 main 地址会在后面填充
-        self.codes_index += 1;
+        self.emit("BR", 0); // main 的入口地址在分析 main 时回填
 
         
 
@@ -591,16 +823,29 @@ impl Compiler {
 
         // 输出符号表内容
         println!("符号表");
-        println!("名字\t \t类型 \t地址\t作用函数");
+        println!("名字\t \t类型 \t数据类型 \t地址\t作用函数");
         for symbol in &self.symbol {
-            if symbol.name.is_empty() {
-                break; // 如果为空，则停止输出
-            }
             println!(
-                "{:<8} \t{:?} \t{:?} \t{:?}",
-                symbol.name, symbol.kind, symbol.address, symbol.action_function
+                "{:<8} \t{:?} \t{:?} \t{:?} \t{:?}",
+                symbol.name, symbol.kind, symbol.ty, symbol.address, symbol.action_function
             );
         }
+
+        // 输出各函数的栈帧布局
+        println!("栈帧布局");
+        for frame in &self.frames {
+            println!("{}\t入口 {}\t栈帧大小 {}", frame.function, frame.entry, frame.size);
+            for slot in &frame.slots {
+                let kind = match slot.kind {
+                    SlotKind::SavedBase => "保存的基地址",
+                    SlotKind::ReturnAddress => "返回地址",
+                    SlotKind::Parameter => "形参",
+                    SlotKind::Local => "局部变量",
+                    SlotKind::Temp => "临时变量",
+                };
+                println!("\t{:<3} {:<8} \t{}", slot.offset, slot.name, kind);
+            }
+        }
         // for symbol in &self.symbol {
         //     // 使用 println! 宏来格式化输出符号表的内容
         //     println!(
@@ -700,7 +945,7 @@ impl Compiler {
 
     //     // es = self.lookup(&temp, &mut fun_pos);
     //     let mut symbol_pos = 0;
-    //     es=self.lookup(&temp, &mut symbol_pos, &self.Lastdefinedfunction);
+    //     es=self.lookup(&temp, &mut symbol_pos);
     //     if es > 0 {
     //         return es;
     //     }
@@ -790,9 +1035,7 @@ impl Compiler {
         //     opt: String::from("ENTER"),
         //     operand: self.offset, // 假设offset为2
         // });
-        self.codes[self.codes_index].opt = "ENTER".to_string();
-        self.codes[self.codes_index].operand=self.offset;
-        self.codes_index += 1;
+        let enter_pos = self.emit("ENTER", 0); // 栈帧大小在函数分析结束后回填
 
         es = self.statement_list(&mut child_node);
 
@@ -806,13 +1049,43 @@ impl Compiler {
         }
         self.add_child(&mut child_node);
 
+        if self.return_type != Type::Void && !self.has_return && !self.in_main() {
+            return 47; // 错误：非 void 函数缺少 return 语句
+        }
+
         // self.codes.push(Code {
         //     opt: String::from("RETURN"),
         //     operand: 0,
         // });
-        self.codes[self.codes_index].opt = "RETURN".to_string();
+        if self.in_main() {
+            // main 执行到末尾时以退出码 0 停机
+            self.emit("LOADI", 0);
+            self.emit("HALT", 0);
+        } else if self.return_type != Type::Void {
+            // 执行到函数末尾仍未返回时，返回该类型的默认值 0
+            self.emit("LOADI", 0);
+            self.emit("RETURN", 1);
+        } else {
+            self.emit("RETURN", 0);
+        }
         // self.codes[self.codes_index].operand=self.offset;
-        self.codes_index+=1;
+        if self.codes_full {
+            return 51; // 中间代码超出上限，不再分析不完整的代码
+        }
+        let layout = self.frame.finish(&self.Lastdefinedfunction, self.codes_index);
+        self.codes[enter_pos].operand = layout.size;
+        for (address, name) in dataflow::uninitialized_reads(&self.codes, &layout) {
+            let message = format!("变量 {} 可能在赋值之前被读取", name);
+            self.warn(WarningKind::Uninitialized, self.codes[address].token, message);
+        }
+        for (address, hazard, message) in interval::arithmetic_hazards(&self.codes, &layout, &self.frames) {
+            let kind = match hazard {
+                Hazard::DivisionByZero => WarningKind::DivisionByZero,
+                Hazard::Overflow => WarningKind::Overflow,
+            };
+            self.warn(kind, self.codes[address].token, message);
+        }
+        self.frames.push(layout);
         es
     }
 
@@ -822,8 +1095,12 @@ impl Compiler {
         let child_node = Node::new("<declaration_list>");
         root.add_child(child_node);
 
-        while self.token == "int" {
-            es = self.declaration_stat(root);
+        while self.token == "int" || self.token == "char" || self.token == "bool" || self.token == "const" {
+            if self.token == "const" {
+                es = self.const_declaration_stat(root);
+            } else {
+                es = self.declaration_stat(root);
+            }
             if es > 0 {
                 return es;
             }
@@ -835,6 +1112,7 @@ impl Compiler {
     // // <declaration_stat> -> int ID;
     fn declaration_stat(&mut self, root: &mut Node) -> i32 {
         let es;
+        let ty = type_of_keyword(&self.token).unwrap_or(Type::Int);
         let mut child_node = Node::new("<declaration_stat>");
         root.add_child(child_node);
 
@@ -858,6 +1136,9 @@ impl Compiler {
         if es > 0 {
             return es;
         }
+        let pos = self.symbol.len() - 1;
+        self.symbol[pos].ty = ty;
+        self.symbol[pos].address = self.frame.alloc_local(&temp_token, self.codes_index);
 
         // 读取下一个 token
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
@@ -878,186 +1159,455 @@ impl Compiler {
         es
     }
 
-    // <statement_list> -> { <statement> }
-    fn statement_list(&mut self, root: &mut Node) -> i32 {
-        let mut es = 0;
-
-        let mut child_node = Node::new("<statement_list>");
+    // <const_declaration_stat> -> const (int | char) ID = <const_expr>;
+    fn const_declaration_stat(&mut self, root: &mut Node) -> i32 {
+        let mut es;
+        let mut child_node = Node::new("<const_declaration_stat>");
         root.add_child(child_node);
-        child_node = Node::new("<statement_list>");
-
-        while self.token != "}" {
-            es = self.statement(&mut child_node);
-            if es > 0 {
-                return es;
-            }
-        }
 
-        es
-    }
-
-    // // <statement> -> <if_stat> | <while_stat> | <for_stat>
-    // //             | <compound_stat> | <expression_stat> | <call_stat>
-    fn statement(&mut self, root: &mut Node) -> i32 {
-        let mut es = 0;
-
-        let mut child_node = Node::new("<statement>");
-        root.add_child(child_node);
-        child_node = Node::new("<statement>");
+        child_node = Node::new("<const_declaration_stat>");
+        self.add_child(&mut child_node);
 
-        if es==0 && self.token == "if" {
-            es = self.if_stat(&mut child_node); // <if 语句>
-        }
-        if es==0&&self.token == "while" {
-            es = self.while_stat(&mut child_node); // <while>
-        } 
-        if es==0&&self.token == "for" {
-            es = self.for_stat(&mut child_node); // <for 语句>
-        } 
-        if es==0&&self.token == "read" {
-            es = self.read_stat(&mut child_node); // <read 语句>
-        }
-        if es==0&&self.token == "write" {
-            es = self.write_stat(&mut child_node); // <write 语句>
-        }
-        if es==0&&self.token == "{" {
-            es = self.compound_stat(&mut child_node); // <复合语句>
-        }
-        if es==0&&self.token == "call" {
-            es = self.call_stat(&mut child_node); // <函数调用语句>
-        }
-        if es==0&&(self.token == "ID" || self.token == "NUM" || self.token == "(") {
-            es = self.expression_stat(&mut child_node); // <表达式语句>
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+        if self.token != "int" && self.token != "char" {
+            return 42; // 错误：const 后面缺少类型
         }
+        let ty = if self.token == "char" { Type::Char } else { Type::Int };
+        self.add_child(&mut child_node);
 
-        es
-    }
-
-    // // <if_stat> -> if '(' <expr> ')' <statement> [else <statement>]
-    fn if_stat(&mut self, root: &mut Node) -> i32 {
-        let mut es;
-        let  cx1;
-        let  cx2;
-
-        let mut child_node = Node::new("<if_stat>");
-        root.add_child(child_node);
-
-        child_node = Node::new("<if_stat>");
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+        if self.token != "ID" {
+            return 3; // 错误：不是标识符
+        }
         self.add_child(&mut child_node);
+        let name = self.token1.clone();
 
-        // 读取 token
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
-        if self.token != "(" {
-            return 5; // 错误：缺少左括号
+        if self.token != "=" {
+            return 43; // 错误：常量定义缺少初值
         }
         self.add_child(&mut child_node);
+
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
 
-        es = self.expression(&mut child_node);
+        // 在编译期求出常量表达式的值
+        let mut value = 0;
+        es = self.const_expr(&mut child_node, &mut value);
         if es > 0 {
             return es;
         }
 
-        if self.token != ")" {
-            // println!("被输出4");
-
-            return 6; // 错误：缺少右括号
+        if self.token != ";" {
+            return 4; // 错误：缺少分号
         }
         self.add_child(&mut child_node);
 
-        // 生成条件判断的指令
-        self.codes[self.codes_index].opt = "BRF".to_string();
-        cx1 = self.codes_index;
-        self.codes_index += 1;
+        // 常量不占用数据区，值直接记录在符号表中
+        es = self.insert_symbol(CategorySymbol::Constant, &name);
+        if es > 0 {
+            return es;
+        }
+        let pos = self.symbol.len() - 1;
+        self.symbol[pos].value = value;
+        self.symbol[pos].ty = ty;
 
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
-        es = self.statement(&mut child_node);
+
+        es
+    }
+
+    // <const_expr> -> <const_term> { (+ | -) <const_term> }
+    fn const_expr(&mut self, root: &mut Node, value: &mut i32) -> i32 {
+        let mut es;
+        let mut child_node = Node::new("<const_expr>");
+        root.add_child(child_node);
+        child_node = Node::new("<const_expr>");
+
+        es = self.const_term(&mut child_node, value);
         if es > 0 {
             return es;
         }
 
-        // 生成跳转指令
-        self.codes[self.codes_index].opt = "BR".to_string();
-        cx2 = self.codes_index;
-        self.codes_index += 1;
-        self.codes[cx1].operand = self.codes_index as i32;
-
-        // 处理 else 部分
-        if self.token == "else" {
+        while self.token == "+" || self.token == "-" {
             self.add_child(&mut child_node);
+            let token2 = self.token.clone(); // 保存运算符
             let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
             self.token = new_token;  // 分别赋值
             self.token1 = new_token1;
-            es = self.statement(&mut child_node);
+
+            let mut right = 0;
+            es = self.const_term(&mut child_node, &mut right);
             if es > 0 {
                 return es;
             }
+
+            match token2.as_str() {
+                "+" => *value = value.wrapping_add(right),
+                "-" => *value = value.wrapping_sub(right),
+                _ => {}
+            }
         }
 
-        self.codes[cx2].operand = self.codes_index as i32;
         es
     }
 
-    // // <while_stat> -> while '(' <expr> ')' <statement>
-    fn while_stat(&mut self, root: &mut Node) -> i32 {
+    // <const_term> -> <const_factor> { (* | /) <const_factor> }
+    fn const_term(&mut self, root: &mut Node, value: &mut i32) -> i32 {
         let mut es;
-        let  cx1;
-        let  cx_entrance;
-
-        let mut child_node = Node::new("<while_stat>");
+        let mut child_node = Node::new("<const_term>");
         root.add_child(child_node);
+        child_node = Node::new("<const_term>");
 
-        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
-        self.token = new_token;  // 分别赋值
-        self.token1 = new_token1;
-
-        child_node = Node::new("<while_stat>");
-        self.add_child(&mut child_node);
-
-        if self.token != "(" {
-            return 5; // 错误：缺少左括号
-        }
-        self.add_child(&mut child_node);
-        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
-        self.token = new_token;  // 分别赋值
-        self.token1 = new_token1;
-
-        cx_entrance = self.codes_index;
-        es = self.expression(&mut child_node);
+        es = self.const_factor(&mut child_node, value);
         if es > 0 {
             return es;
         }
-        // println!("while_stat,token{},token1{}",self.token,self.token1);
-        if self.token != ")" {
-            // println!("被输出5");
-            return 6; // 错误：缺少右括号
-        }
-        self.add_child(&mut child_node);
-
-        // 生成条件判断的指令
-        self.codes[self.codes_index].opt = "BRF".to_string();
-        cx1 = self.codes_index;
-        self.codes_index += 1;
 
-        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
-        self.token = new_token;  // 分别赋值
-        self.token1 = new_token1;
-        es = self.statement(&mut child_node);
-        if es > 0 {
-            return es;
-        }
+        while self.token == "*" || self.token == "/" {
+            self.add_child(&mut child_node);
+            let token2 = self.token.clone(); // 保存运算符
+            let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+            self.token = new_token;  // 分别赋值
+            self.token1 = new_token1;
 
-        // 生成跳转指令
-        self.codes[self.codes_index].opt = "BR".to_string();
-        self.codes[self.codes_index].operand = cx_entrance as i32;
-        self.codes_index += 1;
-        self.codes[cx1].operand = self.codes_index as i32;
+            let mut right = 0;
+            es = self.const_factor(&mut child_node, &mut right);
+            if es > 0 {
+                return es;
+            }
+
+            match token2.as_str() {
+                "*" => *value = value.wrapping_mul(right),
+                "/" => {
+                    if right == 0 {
+                        return 40; // 错误：除数为0
+                    }
+                    *value = value.wrapping_div(right);
+                }
+                _ => {}
+            }
+        }
+
+        es
+    }
+
+    // <const_factor> -> '(' <const_expr> ')' | ID | NUM | CHAR，其中 ID 必须是已定义的常量
+    fn const_factor(&mut self, root: &mut Node, value: &mut i32) -> i32 {
+        let mut es = 0;
+        let mut child_node = Node::new("<const_factor>");
+        root.add_child(child_node);
+        child_node = Node::new("<const_factor>");
+
+        if self.token == "(" {
+            self.add_child(&mut child_node);
+            let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+            self.token = new_token;  // 分别赋值
+            self.token1 = new_token1;
+            es = self.const_expr(&mut child_node, value);
+            if es > 0 {
+                return es;
+            }
+            if self.token != ")" {
+                return 6; // 错误：少右括号
+            }
+        } else if self.token == "ID" {
+            let mut symbol_pos: i32 = 0;
+            es = self.lookup(&self.token1, &mut symbol_pos);
+            if es > 0 {
+                return es; // 标识符未定义
+            }
+            if !are_kinds_equal(self.symbol[symbol_pos as usize].kind.clone(), CategorySymbol::Constant) {
+                return 39; // 错误：常量表达式中只能出现常量
+            }
+            *value = self.symbol[symbol_pos as usize].value;
+        } else if self.token == "NUM" || self.token == "CHAR" {
+            *value = self.token1.parse::<i32>().unwrap();
+        } else {
+            return 7; // 错误：缺少操作数
+        }
+        self.add_child(&mut child_node);
+
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+
+        es
+    }
+
+    // 记录类型不匹配错误的位置和类型，返回错误码
+    fn type_error(&mut self, pos: usize, expected: Type, found: Type) -> i32 {
+        self.error_token = Some(pos);
+        self.type_expected = expected;
+        self.type_found = found;
+        if found == Type::Void {
+            49 // 错误：使用了 void 函数的返回值
+        } else {
+            45 // 错误：类型不匹配
+        }
+    }
+
+    // 条件表达式不是 bool 类型
+    fn condition_error(&mut self, pos: usize) -> i32 {
+        self.error_token = Some(pos);
+        self.type_found = self.expr_type;
+        46
+    }
+
+    // 表达式的值不再使用时将其弹出，避免残留在栈上
+    fn discard_value(&mut self) {
+        if self.expr_type != Type::Void {
+            self.add_code("POP");
+            self.expr_type = Type::Void;
+        }
+    }
+
+    // <return_stat> -> return [ <expression> ] ;
+    fn return_stat(&mut self, root: &mut Node) -> i32 {
+        let mut es = 0;
+        let mut child_node = Node::new("<return_stat>");
+        root.add_child(child_node);
+
+        child_node = Node::new("<return_stat>");
+        self.add_child(&mut child_node);
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+
+        let value_pos = self.line_num - 1;
+        if self.token == ";" && self.in_main() {
+            // main 中不带值的 return 以退出码 0 停机
+            self.add_code("LOADI");
+            self.add_code("HALT");
+        } else if self.token == ";" {
+            if self.return_type != Type::Void {
+                return self.type_error(value_pos, self.return_type, Type::Void);
+            }
+            self.add_code("RETURN");
+        } else {
+            es = self.expression(&mut child_node);
+            if es > 0 {
+                return es;
+            }
+            if self.return_type == Type::Void || !is_assignable(self.return_type, self.expr_type) {
+                let found = self.expr_type;
+                es = self.type_error(value_pos, self.return_type, found);
+                if self.return_type == Type::Void {
+                    es = 45; // void 函数不能返回值
+                }
+                return es;
+            }
+            if self.in_main() {
+                self.add_code("HALT"); // main 的返回值即程序的退出码
+            } else {
+                // 操作数为 1 表示返回时带回栈顶的值
+                self.emit("RETURN", 1);
+            }
+        }
+        self.has_return = true;
+
+        if self.token != ";" {
+            return 4; // 错误：缺少分号
+        }
+        self.add_child(&mut child_node);
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+
+        es
+    }
+
+    // <statement_list> -> { <statement> }
+    fn statement_list(&mut self, root: &mut Node) -> i32 {
+        let mut es = 0;
+
+        let mut child_node = Node::new("<statement_list>");
+        root.add_child(child_node);
+        child_node = Node::new("<statement_list>");
+
+        while self.token != "}" {
+            es = self.statement(&mut child_node);
+            if es > 0 {
+                return es;
+            }
+        }
+
+        es
+    }
+
+    // // <statement> -> <if_stat> | <while_stat> | <for_stat> | <read_stat> | <write_stat>
+    // //             | <compound_stat> | <expression_stat> | <call_stat> | <return_stat>
+    fn statement(&mut self, root: &mut Node) -> i32 {
+        let es;
+
+        let mut child_node = Node::new("<statement>");
+        root.add_child(child_node);
+        child_node = Node::new("<statement>");
+
+        if self.codes_full {
+            return 51; // 中间代码超出上限
+        }
+
+        // 每次只分析一条语句
+        if self.token == "if" {
+            es = self.if_stat(&mut child_node); // <if 语句>
+        } else if self.token == "while" {
+            es = self.while_stat(&mut child_node); // <while>
+        } else if self.token == "for" {
+            es = self.for_stat(&mut child_node); // <for 语句>
+        } else if self.token == "read" {
+            es = self.read_stat(&mut child_node); // <read 语句>
+        } else if self.token == "write" {
+            es = self.write_stat(&mut child_node); // <write 语句>
+        } else if self.token == "{" {
+            es = self.compound_stat(&mut child_node); // <复合语句>
+        } else if self.token == "call" {
+            es = self.call_stat(&mut child_node); // <函数调用语句>
+        } else if self.token == "return" {
+            es = self.return_stat(&mut child_node); // <return 语句>
+        } else if ["ID", "NUM", "CHAR", "true", "false", "(", ";"].contains(&self.token.as_str()) {
+            es = self.expression_stat(&mut child_node); // <表达式语句>
+        } else {
+            es = 48; // 错误：无法识别的语句
+        }
+
+        es
+    }
+
+    // // <if_stat> -> if '(' <expr> ')' <statement> [else <statement>]
+    fn if_stat(&mut self, root: &mut Node) -> i32 {
+        let mut es;
+        let  cx1;
+        let  cx2;
+
+        let mut child_node = Node::new("<if_stat>");
+        root.add_child(child_node);
+
+        child_node = Node::new("<if_stat>");
+        self.add_child(&mut child_node);
+
+        // 读取 token
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+        if self.token != "(" {
+            return 5; // 错误：缺少左括号
+        }
+        self.add_child(&mut child_node);
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+
+        let cond_pos = self.line_num - 1;
+        es = self.expression(&mut child_node);
+        if es > 0 {
+            return es;
+        }
+        if self.expr_type != Type::Bool {
+            return self.condition_error(cond_pos);
+        }
+
+        if self.token != ")" {
+            // println!("被输出4");
+
+            return 6; // 错误：缺少右括号
+        }
+        self.add_child(&mut child_node);
+
+        // 生成条件判断的指令
+        cx1 = self.emit("BRF", 0);
+
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+        es = self.statement(&mut child_node);
+        if es > 0 {
+            return es;
+        }
+
+        // 生成跳转指令
+        cx2 = self.emit("BR", 0);
+        self.codes[cx1].operand = self.codes_index as i32;
+
+        // 处理 else 部分
+        if self.token == "else" {
+            self.add_child(&mut child_node);
+            let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+            self.token = new_token;  // 分别赋值
+            self.token1 = new_token1;
+            es = self.statement(&mut child_node);
+            if es > 0 {
+                return es;
+            }
+        }
+
+        self.codes[cx2].operand = self.codes_index as i32;
+        es
+    }
+
+    // // <while_stat> -> while '(' <expr> ')' <statement>
+    fn while_stat(&mut self, root: &mut Node) -> i32 {
+        let mut es;
+        let  cx1;
+        let  cx_entrance;
+
+        let mut child_node = Node::new("<while_stat>");
+        root.add_child(child_node);
+
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+
+        child_node = Node::new("<while_stat>");
+        self.add_child(&mut child_node);
+
+        if self.token != "(" {
+            return 5; // 错误：缺少左括号
+        }
+        self.add_child(&mut child_node);
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+
+        cx_entrance = self.codes_index;
+        let cond_pos = self.line_num - 1;
+        es = self.expression(&mut child_node);
+        if es > 0 {
+            return es;
+        }
+        if self.expr_type != Type::Bool {
+            return self.condition_error(cond_pos);
+        }
+        // println!("while_stat,token{},token1{}",self.token,self.token1);
+        if self.token != ")" {
+            // println!("被输出5");
+            return 6; // 错误：缺少右括号
+        }
+        self.add_child(&mut child_node);
+
+        // 生成条件判断的指令
+        cx1 = self.emit("BRF", 0);
+
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+        es = self.statement(&mut child_node);
+        if es > 0 {
+            return es;
+        }
+
+        // 生成跳转指令
+        self.emit("BR", cx_entrance as i32);
+        self.codes[cx1].operand = self.codes_index as i32;
 
         es
     }
@@ -1091,6 +1641,7 @@ impl Compiler {
         if es > 0 {
             return es;
         }
+        self.discard_value();
 
         if self.token != ";" {
             return 4; // 错误：缺少分号
@@ -1101,18 +1652,18 @@ impl Compiler {
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
+        let cond_pos = self.line_num - 1;
         es = self.expression(&mut child_node);
         if es > 0 {
             return es;
         }
+        if self.expr_type != Type::Bool {
+            return self.condition_error(cond_pos);
+        }
 
-        self.codes[self.codes_index].opt = "BRF".to_string();
-        cx1 = self.codes_index;
-        self.codes_index += 1;
+        cx1 = self.emit("BRF", 0);
 
-        self.codes[self.codes_index].opt = "BR".to_string();
-        cx2 = self.codes_index;
-        self.codes_index += 1;
+        cx2 = self.emit("BR", 0);
 
         if self.token != ";" {
             return 4; // 错误：缺少分号
@@ -1127,10 +1678,9 @@ impl Compiler {
         if es > 0 {
             return es;
         }
+        self.discard_value();
 
-        self.codes[self.codes_index].opt = "BR".to_string();
-        self.codes[self.codes_index].operand = cx_exp2 as i32;
-        self.codes_index += 1;
+        self.emit("BR", cx_exp2 as i32);
         self.codes[cx2].operand = self.codes_index as i32;
 
         if self.token != ")" {
@@ -1148,30 +1698,66 @@ impl Compiler {
             return es;
         }
 
-        self.codes[self.codes_index].opt = "BR".to_string();
-        self.codes[self.codes_index].operand = cx_exp3 as i32;
-        self.codes_index += 1;
+        self.emit("BR", cx_exp3 as i32);
         self.codes[cx1].operand = self.codes_index as i32;
 
         es
     }
 
-    // // <write_stat> -> write <expression>;
+    // // <write_stat> -> write <write_item> { ',' <write_item> } ;
+    // // <write_item> -> STRING | <expression>
     fn write_stat(&mut self, root: &mut Node) -> i32 {
-        let es;
+        let mut es;
+        let mut item_num = 0;
+        let mut has_string = false;
+        let mut number_outs = Vec::new(); // 输出整数的指令位置，格式化输出时改为 OUTN
 
         let mut child_node = Node::new("<write_stat>");
         root.add_child(child_node);
 
         child_node = Node::new("<write_stat>");
         self.add_child(&mut child_node);
-        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
-        self.token = new_token;  // 分别赋值
-        self.token1 = new_token1;
 
-        es = self.expression(&mut child_node);
-        if es > 0 {
-            return es;
+        loop {
+            let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+            self.token = new_token;  // 分别赋值
+            self.token1 = new_token1;
+            item_num += 1;
+
+            if self.token == "STRING" {
+                // 字符串放入常量池，按下标输出
+                self.add_child(&mut child_node);
+                let text = unescape_string(&self.token1);
+                let index = self.intern_string(text);
+                self.emit("OUTS", index as i32);
+                has_string = true;
+
+                let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+                self.token = new_token;  // 分别赋值
+                self.token1 = new_token1;
+            } else {
+                let item_pos = self.line_num - 1;
+                es = self.expression(&mut child_node);
+                if es > 0 {
+                    return es;
+                }
+                if self.expr_type == Type::Void {
+                    return self.type_error(item_pos, Type::Int, Type::Void);
+                }
+
+                // 生成输出指令，char 类型的值按字符输出
+                if self.expr_type == Type::Char {
+                    self.emit("OUTC", 0);
+                } else {
+                    let pos = self.emit("OUT", 0);
+                    number_outs.push(pos);
+                }
+            }
+
+            if self.token != "," {
+                break;
+            }
+            self.add_child(&mut child_node);
         }
 
         if self.token != ";" {
@@ -1179,14 +1765,26 @@ impl Compiler {
         }
         self.add_child(&mut child_node);
 
-        // 生成输出指令
-        self.codes[self.codes_index].opt = "OUT".to_string();
-        self.codes_index += 1;
+        // 只有单个表达式时保持原来的输出格式，否则按程序给出的内容原样输出
+        if has_string || item_num > 1 {
+            for pos in number_outs {
+                self.codes[pos].opt = "OUTN".to_string();
+            }
+        }
 
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
-        es
+        0
+    }
+
+    // 将字符串加入常量池，相同的字符串只保存一份，返回其下标
+    fn intern_string(&mut self, text: String) -> usize {
+        if let Some(index) = self.strings.iter().position(|s| *s == text) {
+            return index;
+        }
+        self.strings.push(text);
+        self.strings.len() - 1
     }
 
     fn read_stat(&mut self, root: &mut Node) -> i32 {
@@ -1206,23 +1804,27 @@ impl Compiler {
 
         self.add_child(&mut child_node);
         let mut symbol_pos = 0;
-        es = self.lookup(&self.token1, &mut symbol_pos, &self.Lastdefinedfunction);
+        es = self.lookup(&self.token1, &mut symbol_pos);
         if es > 0 {
             return es;
         }
 
+        if are_kinds_equal(self.symbol[symbol_pos as usize].kind.clone() , CategorySymbol::Constant) {
+            return 41; // 错误：不能读入常量
+        }
         if !are_kinds_equal(self.symbol[symbol_pos as usize].kind.clone() , CategorySymbol::Variable) {
             return 35; // 错误：符号不是变量
         }
+        if self.symbol[symbol_pos as usize].ty == Type::Bool {
+            let pos = self.line_num - 1;
+            return self.type_error(pos, Type::Int, Type::Bool); // 错误：不能读入 bool 变量
+        }
 
         let symbol_pos_usize:usize = symbol_pos as usize;
         // let codesIndex_usize:usize = self.codes_index as usize;
 
-        self.codes[self.codes_index].opt = "IN".to_string();
-        self.codes_index += 1;
-        self.codes[self.codes_index].opt = "STO".to_string();
-        self.codes[self.codes_index].operand = self.symbol[symbol_pos_usize].address;
-        self.codes_index += 1;
+        self.emit("IN", 0);
+        self.emit_store(symbol_pos_usize);
 
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
@@ -1240,7 +1842,7 @@ impl Compiler {
         es
     }
 
-    // // <compound_stat> -> '{' <statement_list> '}'
+    // // <compound_stat> -> '{' <declaration_list> <statement_list> '}'
     fn compound_stat(&mut self, root: &mut Node) -> i32 {
         //let mut es: i32;
         let mut child_node = Node::new("<compound_stat>");
@@ -1251,8 +1853,18 @@ impl Compiler {
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
 
-
-        let es = self.statement_list(&mut child_node);
+        // 复合语句开头可以声明只在块内可见的变量和常量
+        self.symbol.push_scope();
+        self.frame.enter_block();
+        let mut es = self.declaration_list(&mut child_node);
+        if es == 0 {
+            es = self.statement_list(&mut child_node);
+        }
+        if es == 0 {
+            self.check_scope_usage();
+        }
+        self.frame.exit_block();
+        self.symbol.pop_scope();
 
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
@@ -1261,7 +1873,7 @@ impl Compiler {
         es
     }
 
-    // // <call_stat> -> call ID '(' <variable_list> ')'
+    // // <call_stat> -> call ID '(' <variable_list> ')' ;
     fn call_stat(&mut self, root: &mut Node) -> i32 {
         let mut es;
         let mut symbol_pos = 0;
@@ -1277,7 +1889,7 @@ impl Compiler {
         
         child_node = Node::new("<call_stat>");
         self.add_child(&mut child_node);
-        es = self.lookup(&self.token1, &mut symbol_pos, &self.token1); // 查找函数
+        es = self.lookup_function(&self.token1, &mut symbol_pos); // 查找函数
         if es > 0 {
             return es;
         }
@@ -1292,21 +1904,50 @@ impl Compiler {
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
 
+        es = self.call_expr(&mut child_node, symobol_pos_usize);
+        if es > 0 {
+            return es;
+        }
+
+        if self.token != ";" {
+            return 4; // 错误：缺少分号
+        }
+
+        self.add_child(&mut child_node);
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+
+        // 作为语句调用时丢弃返回值
+        self.discard_value();
+
+        es
+    }
+
+    // // <call_expr> -> '(' <variable_list> ')'，函数名已经读过，结果类型为函数的返回类型
+    fn call_expr(&mut self, root: &mut Node, fun_pos: usize) -> i32 {
+        let mut es = 0;
+
         if self.token != "(" {
             return 5; // 错误：缺少左括号
         }
 
-        self.add_child(&mut child_node);
+        // 记录调用关系，用于找出不会被调用的函数
+        if let Some(caller) = self.symbol.global_position(&self.Lastdefinedfunction) {
+            self.calls.push((caller, fun_pos));
+        }
+        self.symbol[fun_pos].reads += 1;
+
+        self.add_child(root);
 
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
-        if self.symbol[symobol_pos_usize].var_num != 0 {
-            es = self.variable_list(&mut child_node, self.symbol[symobol_pos_usize].var_num);
-            let mut a = self.symbol[symobol_pos_usize].var_num;
+        if self.token != ")" || self.symbol[fun_pos].var_num != 0 {
+            es = self.variable_list(root, fun_pos);
+            let mut a = self.symbol[fun_pos].var_num;
             while a != 0 {
-                self.codes[self.codes_index].opt = "PAS".to_string();
-                self.codes_index += 1;
+                self.emit("PAS", 0);
                 a -= 1;
             }
 
@@ -1320,24 +1961,14 @@ impl Compiler {
             return 6; // 错误：缺少右括号
         }
 
-        self.add_child(&mut child_node);
-
-        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
-        self.token = new_token;  // 分别赋值
-        self.token1 = new_token1;
-
-        if self.token != ";" {
-            return 4; // 错误：缺少分号
-        }
+        self.add_child(root);
 
-        self.add_child(&mut child_node);
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
 
-        self.codes[self.codes_index].opt = "CAL".to_string();
-        self.codes[self.codes_index].operand = self.symbol[symobol_pos_usize].address;
-        self.codes_index += 1;
+        self.emit("CAL", self.symbol[fun_pos].address);
+        self.expr_type = self.symbol[fun_pos].ty;
 
         es
     }
@@ -1364,6 +1995,7 @@ impl Compiler {
         if es > 0 {
             return es;
         }
+        self.discard_value();
 
         if self.token == ";" {
             self.add_child(&mut child_node);
@@ -1376,7 +2008,7 @@ impl Compiler {
         }
     }
 
-    // // <expression> -> ID = <bool_expr> | <bool_expr>
+    // // <expression> -> ID = <cond_expr> | <cond_expr>
     fn expression(&mut self, root: &mut Node) -> i32 {
         let mut es;
         let mut file_add = 0usize; // 这里应该是 u64 类型，因为 stream_position 返回的是 u64
@@ -1408,12 +2040,15 @@ impl Compiler {
                 self.add_child(&mut child_node);
                 let son = Node::new(&token2);
                 child_node.add_child(son);
-                es = self.lookup(&self.token1, &mut symbol_pos, &self.Lastdefinedfunction);
+                es = self.lookup(&self.token1, &mut symbol_pos);
                 if es > 0 {
                     return es;
                 }
     
                 let symbol_pos_usize = symbol_pos as usize;
+                if are_kinds_equal(self.symbol[symbol_pos_usize].kind.clone() , CategorySymbol::Constant) {
+                    return 41; // 错误：不能给常量赋值
+                }
                 if are_kinds_equal(self.symbol[symbol_pos_usize].kind.clone() , CategorySymbol::Variable) == false{
                     return 36; // 错误：不是变量
                 }
@@ -1424,14 +2059,21 @@ impl Compiler {
                 };
                 self.token = new_token;  // 分别赋值
                 self.token1 = new_token1;
-                es = self.bool_expr(&mut child_node);
+                let value_pos = self.line_num - 1;
+                es = self.cond_expr(&mut child_node);
                 if es > 0 {
                     return es;
                 }
+
+                // 值的类型必须能赋给变量，char 可以赋给 int
+                let target_type = self.symbol[symbol_pos_usize].ty;
+                if !is_assignable(target_type, self.expr_type) {
+                    let found = self.expr_type;
+                    return self.type_error(value_pos, target_type, found);
+                }
+                self.expr_type = Type::Void; // 赋值表达式不在栈上留下值
     
-                self.codes[self.codes_index].opt = "STO".to_string();
-                self.codes[self.codes_index].operand = self.symbol[symbol_pos_usize].address.clone();
-                self.codes_index += 1;
+                self.emit_store(symbol_pos_usize);
             } else {
                 // 回到"="之前，调整文件指针
                 // if let Some(file) = &mut self.fp_tokenin {
@@ -1441,20 +2083,84 @@ impl Compiler {
                 // }
                 self.line_num=file_add;
                 child_node = Node::new("<expression>");
-                es = self.bool_expr(&mut child_node);
+                es = self.cond_expr(&mut child_node);
                 if es > 0 {
                     return es;
                 }
             }
         } else {
             child_node = Node::new("<expression>");
-            es = self.bool_expr(&mut child_node);
+            es = self.cond_expr(&mut child_node);
         }
         // println!("expression_stat,token{},token1{}",&self.token,&self.token1);
         es
     }
     
+    // // <cond_expr> -> <bool_expr> [ '?' <cond_expr> ':' <cond_expr> ]
+    fn cond_expr(&mut self, root: &mut Node) -> i32 {
+        let mut es;
+        let mut child_node = Node::new("<cond_expr>");
+        root.add_child(child_node);
+
+        child_node = Node::new("<cond_expr>");
+        let cond_pos = self.line_num - 1;
+        es = self.bool_expr(&mut child_node);
+        if es > 0 || self.token != "?" {
+            return es;
+        }
+        if self.expr_type != Type::Bool {
+            return self.condition_error(cond_pos);
+        }
+        self.add_child(&mut child_node);
+
+        // 条件为假时跳到 ':' 之后的分支，与 if_stat 相同的回填方式
+        let cx1 = self.emit("BRF", 0);
+
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+        let then_pos = self.line_num - 1;
+        es = self.cond_expr(&mut child_node);
+        if es > 0 {
+            return es;
+        }
+        let then_type = self.expr_type;
+        if then_type == Type::Void {
+            return self.type_error(then_pos, Type::Int, Type::Void);
+        }
+
+        if self.token != ":" {
+            return 44; // 错误：条件表达式缺少 ':'
+        }
+        self.add_child(&mut child_node);
+
+        let cx2 = self.emit("BR", 0);
+        self.codes[cx1].operand = self.codes_index as i32;
+
+        let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+        self.token = new_token;  // 分别赋值
+        self.token1 = new_token1;
+        let else_pos = self.line_num - 1;
+        es = self.cond_expr(&mut child_node);
+        if es > 0 {
+            return es;
+        }
+        // 两个分支类型相同时保留该类型，int 与 char 混合时提升为 int
+        if then_type != self.expr_type {
+            if is_numeric(then_type) && is_numeric(self.expr_type) {
+                self.expr_type = Type::Int;
+            } else {
+                let found = self.expr_type;
+                return self.type_error(else_pos, then_type, found);
+            }
+        }
+
+        self.codes[cx2].operand = self.codes_index as i32;
+        es
+    }
+
     // // <bool_expr> -> <additive_expr> | <additive_expr> ( > | < | >= | <= | == | != ) <additive_expr>
+    // // 比较的结果为 bool 类型
     fn bool_expr(&mut self, root: &mut Node) -> i32 {
         let mut es;
         let mut child_node = Node::new("<bool_expr>");
@@ -1462,25 +2168,41 @@ impl Compiler {
 
         // 处理 addtive_expr 部分
         child_node = Node::new("<bool_expr>");
+        let left_pos = self.line_num - 1;
         es = self.additive_expr(&mut child_node);
         if es > 0 {
             return es;
         }
 
         // 处理关系运算符部分
-        if ["=", ">", ">=", "<", "<=", "==", "!="].contains(&self.token.as_str()) {
+        if [">", ">=", "<", "<=", "==", "!="].contains(&self.token.as_str()) {
             self.add_child(&mut child_node);
+            let left_type = self.expr_type;
 
             let token2 = self.token.clone(); // 保存运算符
             let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
             self.token = new_token;  // 分别赋值
             self.token1 = new_token1;
 
+            let right_pos = self.line_num - 1;
             es = self.additive_expr(&mut child_node);
             if es > 0 {
                 return es;
             }
 
+            // == 与 != 可以比较两个 bool，其余比较要求操作数为 int 或 char
+            let right_type = self.expr_type;
+            let both_bool = left_type == Type::Bool && right_type == Type::Bool;
+            if !(both_bool && (token2 == "==" || token2 == "!=")) {
+                if !is_numeric(left_type) {
+                    return self.type_error(left_pos, Type::Int, left_type);
+                }
+                if !is_numeric(right_type) {
+                    return self.type_error(right_pos, Type::Int, right_type);
+                }
+            }
+            self.expr_type = Type::Bool; // 比较结果为 bool
+
             // 根据 token2 设置不同的操作符
             match token2.as_str() {
                 ">" =>  self.add_code("GT"),
@@ -1504,6 +2226,7 @@ impl Compiler {
 
         child_node = Node::new("<additive_expr>");
 
+        let left_pos = self.line_num - 1;
         es = self.term(&mut child_node);
         if es > 0 {
             return es;
@@ -1511,22 +2234,32 @@ impl Compiler {
 
         // 处理 + 或 - 运算符
         while self.token == "+" || self.token == "-" {
+            // 算术运算的操作数只能是 int 或 char
+            if !is_numeric(self.expr_type) {
+                let found = self.expr_type;
+                return self.type_error(left_pos, Type::Int, found);
+            }
             self.add_child(&mut child_node);
             let token2 = self.token.clone(); // 保存运算符
+            let op_pos = self.line_num - 1; // 运算符的位置，运行时可能出错的运算在这里报告
             let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
             self.token = new_token;  // 分别赋值
             self.token1 = new_token1;
 
+            let right_pos = self.line_num - 1;
             es = self.term(&mut child_node);
             if es > 0 {
                 return es;
             }
-
-            match token2.as_str() {
-                "+" => self.add_code("ADD"),
-                "-" => self.add_code("SUB"),
-                _ => {}
+            if !is_numeric(self.expr_type) {
+                let found = self.expr_type;
+                return self.type_error(right_pos, Type::Int, found);
             }
+
+            self.expr_type = Type::Int; // 算术运算的结果提升为 int
+
+            let op = if token2 == "+" { "ADD" } else { "SUB" };
+            self.emit_at(op, 0, op_pos);
         }
 
         es
@@ -1539,6 +2272,7 @@ impl Compiler {
         root.add_child(child_node);
         child_node = Node::new("<term>");
 
+        let left_pos = self.line_num - 1;
         es = self.factor(&mut child_node);
         if es > 0 {
             return es;
@@ -1546,22 +2280,37 @@ impl Compiler {
 
         // 处理 * 或 / 运算符
         while self.token == "*" || self.token == "/" {
+            // 算术运算的操作数只能是 int 或 char
+            if !is_numeric(self.expr_type) {
+                let found = self.expr_type;
+                return self.type_error(left_pos, Type::Int, found);
+            }
             self.add_child(&mut child_node);
             let token2 = self.token.clone(); // 保存运算符
+            let op_pos = self.line_num - 1;
             let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
             self.token = new_token;  // 分别赋值
             self.token1 = new_token1;
 
+            let right_pos = self.line_num - 1;
+            let divisor_start = self.codes_index;
             es = self.factor(&mut child_node);
             if es > 0 {
                 return es;
             }
+            if !is_numeric(self.expr_type) {
+                let found = self.expr_type;
+                return self.type_error(right_pos, Type::Int, found);
+            }
 
-            match token2.as_str() {
-                "*" => self.add_code("MULT"),
-                "/" => self.add_code("DIV"),
-                _ => {}
+            self.expr_type = Type::Int; // 算术运算的结果提升为 int
+
+            let op = if token2 == "*" { "MULT" } else { "DIV" };
+            // 除数是值为 0 的常量表达式时记下位置，分析完整个程序后一并报告
+            if op == "DIV" && optimize::constant_value(&self.codes[divisor_start..self.codes_index]) == Some(0) {
+                self.zero_divisors.push(op_pos);
             }
+            self.emit_at(op, 0, op_pos);
         }
 
         es
@@ -1569,11 +2318,44 @@ impl Compiler {
 
     // // 辅助方法：添加操作代码
     fn add_code(&mut self, op: &str) {
-        // 假设 `codes` 是一个存储代码的数组，`codesIndex` 是当前索引
-        self.codes[self.codes_index].opt = op.to_string();
+        self.emit(op, 0);
+    }
+
+    // 在代码末尾追加一条指令，返回它的地址，供跳转指令回填使用
+    fn emit(&mut self, op: &str, operand: i32) -> usize {
+        self.emit_at(op, operand, self.line_num.saturating_sub(1))
+    }
+
+    // 同 emit，位置记为单词流中下标为 token 的单词
+    // 指令条数达到上限后不再追加，只记下超限，由语句和函数的分析报告错误 51；
+    // 此时返回最后一条指令的地址，回填写到它身上也无妨，反正不会生成代码
+    fn emit_at(&mut self, op: &str, operand: i32, token: usize) -> usize {
+        if self.codes_index >= self.max_codes {
+            self.codes_full = true;
+            return self.codes_index.saturating_sub(1);
+        }
+        self.codes.push(Code { opt: op.to_string(), operand, token });
         self.codes_index += 1;
+        self.codes_index - 1
+    }
+
+    // 当前是否在分析 main 函数，main 总是最后一个定义的函数
+    fn in_main(&self) -> bool {
+        self.Lastdefinedfunction == "main"
+    }
+
+    // 取变量的值，引用形参要经保存的地址间接取值
+    fn emit_load(&mut self, pos: usize) {
+        self.symbol[pos].reads += 1;
+        self.emit(if self.symbol[pos].by_ref { "LOADR" } else { "LOAD" }, self.symbol[pos].address);
     }
-    // // <factor> -> '(' <additive_expr> ')' | ID | NUM
+
+    // 把栈顶的值存入变量，引用形参要经保存的地址间接存值
+    fn emit_store(&mut self, pos: usize) {
+        self.symbol[pos].writes += 1;
+        self.emit(if self.symbol[pos].by_ref { "STOR" } else { "STO" }, self.symbol[pos].address);
+    }
+    // // <factor> -> '(' <additive_expr> ')' | ID | NUM | CHAR | true | false | ID '(' <variable_list> ')'
     fn factor(&mut self, root: &mut Node) -> i32 {
         let mut es = 0;
 
@@ -1603,18 +2385,41 @@ impl Compiler {
                 child_node = Node::new("<factor>");
                 self.add_child(&mut child_node);
                 let mut symbol_pos: i32 = 0;
-                es = self.lookup(&self.token1, &mut symbol_pos, &self.Lastdefinedfunction);
+                // 标识符后面紧跟 '(' 时是函数调用，按函数名查找，不会被同名的局部变量遮蔽
+                let mut next_line = self.line_num;
+                let is_call = fscanf_token(&self.tokenfile, &mut next_line).is_ok_and(|(next, _)| next == "(");
+                es = if is_call {
+                    self.lookup_function(&self.token1, &mut symbol_pos)
+                } else {
+                    self.lookup(&self.token1, &mut symbol_pos)
+                };
                 if es > 0 {
                     return es; // 变量未定义
                 }
+                if is_call && !are_kinds_equal(self.symbol[symbol_pos as usize].kind.clone(), CategorySymbol::Function) {
+                    return 37; // 不是函数名
+                }
+                if are_kinds_equal(self.symbol[symbol_pos as usize].kind.clone(), CategorySymbol::Function) {
+                    // 标识符是函数名时按函数调用处理
+                    let call_pos = self.line_num - 1;
+                    if self.symbol[symbol_pos as usize].ty == Type::Void {
+                        return self.type_error(call_pos, Type::Int, Type::Void);
+                    }
+                    let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+                    self.token = new_token;  // 分别赋值
+                    self.token1 = new_token1;
+                    return self.call_expr(&mut child_node, symbol_pos as usize);
+                }
 
-                if are_kinds_equal(self.symbol[symbol_pos as usize].kind.clone() , CategorySymbol::Variable) == false {
+                if are_kinds_equal(self.symbol[symbol_pos as usize].kind.clone() , CategorySymbol::Constant) {
+                    // 常量直接生成立即数
+                    self.emit("LOADI", self.symbol[symbol_pos as usize].value);
+                } else if are_kinds_equal(self.symbol[symbol_pos as usize].kind.clone() , CategorySymbol::Variable) == false {
                     return 37; // 变量语义检查失败
+                } else {
+                    self.emit_load(symbol_pos as usize);
                 }
-
-                self.codes[self.codes_index as usize].opt = "LOAD".to_string();
-                self.codes[self.codes_index as usize].operand = self.symbol[symbol_pos as usize].address;
-                self.codes_index += 1;
+                self.expr_type = self.symbol[symbol_pos as usize].ty;
 
                 let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
                 self.token = new_token;  // 分别赋值
@@ -1625,9 +2430,33 @@ impl Compiler {
             if self.token == "NUM" {
                 child_node = Node::new("<factor>");
                 self.add_child(&mut child_node);
-                self.codes[self.codes_index as usize].opt = "LOADI".to_string();
-                self.codes[self.codes_index as usize].operand = self.token1.parse::<i32>().unwrap();
-                self.codes_index += 1;
+                self.emit("LOADI", self.token1.parse::<i32>().unwrap());
+                self.expr_type = Type::Int;
+
+                let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+                self.token = new_token;  // 分别赋值
+                self.token1 = new_token1;
+                return es;
+            }
+
+            if self.token == "true" || self.token == "false" {
+                child_node = Node::new("<factor>");
+                self.add_child(&mut child_node);
+                self.emit("LOADI", if self.token == "true" { 1 } else { 0 });
+                self.expr_type = Type::Bool;
+
+                let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+                self.token = new_token;  // 分别赋值
+                self.token1 = new_token1;
+                return es;
+            }
+
+            if self.token == "CHAR" {
+                // 字符字面量在单词流中记为其字符编码
+                child_node = Node::new("<factor>");
+                self.add_child(&mut child_node);
+                self.emit("LOADI", self.token1.parse::<i32>().unwrap());
+                self.expr_type = Type::Char;
 
                 let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
                 self.token = new_token;  // 分别赋值
@@ -1642,62 +2471,153 @@ impl Compiler {
         es
     }
 
-    // // 插入符号到符号表
+    // // 插入符号到符号表，函数名登记在全局作用域，变量和常量登记在当前作用域
     fn insert_symbol(&mut self, category: CategorySymbol, name: &str) -> i32 {
-        if self.symbol_index >= 100 {
+        if self.symbol.len() >= self.max_symbols {
             return 21; // 符号表溢出
         }
 
-        let mut es = 0;
+        let mut entry = Symbol::default();
+        if are_kinds_equal(category.clone(), CategorySymbol::Function) {
+            self.Lastdefinedfunction = name.to_string();
+        } else if self.symbol.depth() > 1 && self.symbol.lookup(name).is_some_and(|prev| prev.param) {
+            // 块作用域中的声明遮蔽了函数的形参
+            let message = format!("块内声明的 {} 遮蔽了同名形参", name);
+            self.warn(WarningKind::ShadowParameter, self.line_num - 1, message);
+        }
+        entry.name = name.to_string();
+        entry.kind = category.clone();
+        entry.action_function = self.Lastdefinedfunction.clone();
+        entry.decl_token = self.line_num - 1;
 
-        match category {
-            CategorySymbol::Function => {
-                self.Lastdefinedfunction = name.to_string();
-                for i in (0..self.symbol_index).rev() {
-                    if self.symbol[i as usize].name == name &&  are_kinds_equal(self.symbol[i as usize].kind.clone() , CategorySymbol::Function) {
-                        es = 32; // 错误：函数名重复
-                        break;
-                    }
-                }
-                self.symbol[self.symbol_index as usize].kind = CategorySymbol::Function;
+        if self.symbol.insert(entry).is_err() {
+            return match category {
+                CategorySymbol::Function => 32, // 错误：函数名重复
+                _ => 22,                        // 错误：同一作用域内变量或常量重复定义
+            };
+        }
+
+        0
+    }
+
+    // 记录一条警告，位置为单词流中下标为 token 的单词
+    fn warn(&mut self, kind: WarningKind, token: usize, message: String) {
+        let position = token_position(&self.tokenfile, token);
+        self.diagnostics.report(kind, position, message);
+    }
+
+    // 退出作用域前检查其中的变量和形参是否被使用
+    fn check_scope_usage(&mut self) {
+        let mut found = Vec::new();
+        for symbol in self.symbol.symbols_in_scope() {
+            if !are_kinds_equal(symbol.kind.clone(), CategorySymbol::Variable) {
+                continue;
             }
-            CategorySymbol::Variable => {
-                for i in (0..self.symbol_index).rev() {
-                    if self.symbol[i as usize].name == name && are_kinds_equal(self.symbol[i as usize].kind.clone() , CategorySymbol::Variable)
-                        && self.symbol[i as usize].action_function == self.Lastdefinedfunction
-                    {
-                        es = 22; // 错误：同一作用域内变量重复定义
-                        break;
-                    }
-                }
-                self.symbol[self.symbol_index as usize].kind = CategorySymbol::Variable;
-                self.symbol[self.symbol_index as usize].address = self.offset;
-                self.offset += 1; // 数据区指针加1
+            let warning = if symbol.param {
+                // 按值传递的形参在调用时已经赋值，只看是否被读取；引用形参只写不读也算使用
+                let used = symbol.reads > 0 || (symbol.by_ref && symbol.writes > 0);
+                (!used).then(|| (WarningKind::UnusedParameter, format!("形参 {} 从未使用", symbol.name)))
+            } else if symbol.reads == 0 && symbol.writes == 0 {
+                Some((WarningKind::UnusedVariable, format!("变量 {} 声明后从未使用", symbol.name)))
+            } else if symbol.reads == 0 {
+                Some((WarningKind::UnreadVariable, format!("变量 {} 赋值后从未读取", symbol.name)))
+            } else {
+                None
+            };
+            if let Some((kind, message)) = warning {
+                found.push((kind, symbol.decl_token, message));
             }
         }
-
-        if es > 0 {
-            return es;
+        for (kind, token, message) in found {
+            self.warn(kind, token, message);
         }
+    }
 
-        self.symbol[self.symbol_index as usize].name = name.to_string();
-        self.symbol[self.symbol_index as usize].action_function = self.Lastdefinedfunction.clone();
-        self.symbol_index += 1;
-
-        es
+    // 把中间代码翻译为四元式再生成栈式代码，然后按优化级别优化，最后重新填写函数的入口地址
+    fn optimize(&mut self) -> i32 {
+        // 栈式代码转换为四元式，经四元式上的优化后重新生成栈式代码。
+        // 没有优化遍时只在 --emit-ir 需要时才翻译为四元式，写入文件的仍是语法分析生成的栈式代码
+        if self.passes.is_empty() && !self.emit_ir {
+            return 0;
+        }
+        let mut module = match ir::Module::build(&self.codes, &self.frames) {
+            Ok(module) => module,
+            Err(message) => {
+                self.ir_error = message;
+                return 55;
+            }
+        };
+        if self.passes.is_empty() {
+            self.ir = Some(module);
+            return 0;
+        }
+        let result = self.passes.run(&mut module).map(|(codes, frames)| {
+            self.codes = codes;
+            self.frames = frames;
+        });
+        self.ir = Some(module);
+        self.codes_index = self.codes.len();
+        for frame in &self.frames {
+            if let Some(pos) = self.symbol.global_position(&frame.function) {
+                self.symbol[pos].address = frame.entry as i32;
+            }
+        }
+        match result {
+            Ok(()) => 0,
+            Err(OptimizeError::InvalidJump { pass, message }) => {
+                self.pass_error = format!("{}：{}", pass, message);
+                54
+            }
+        }
     }
 
-    // // 查找符号表中的标识符
-    fn lookup(&self, name: &str, p_position: &mut i32, fun_name: &str) -> i32 {
+    // 从 main 出发沿调用关系找不到的函数不会被执行
+    fn check_unused_functions(&mut self) {
+        let Some(main_pos) = self.symbol.global_position("main") else {
+            return;
+        };
+        let mut reached = vec![main_pos];
+        let mut i = 0;
+        while i < reached.len() {
+            let caller = reached[i];
+            for &(from, to) in &self.calls {
+                if from == caller && !reached.contains(&to) {
+                    reached.push(to);
+                }
+            }
+            i += 1;
+        }
+        let mut found = Vec::new();
+        for (pos, symbol) in self.symbol.iter().enumerate() {
+            if are_kinds_equal(symbol.kind.clone(), CategorySymbol::Function) && !reached.contains(&pos) {
+                found.push((symbol.decl_token, format!("函数 {} 不会从 main 被调用", symbol.name)));
+            }
+        }
+        for (token, message) in found {
+            self.warn(WarningKind::UnusedFunction, token, message);
+        }
+    }
 
-        for i in 0..self.symbol_index {
-            if self.symbol[i as usize].name == name && self.symbol[i as usize].action_function == fun_name {
-                *p_position = i as i32;
-                return 0; // 找到符号
+    // // 从内层作用域向外查找标识符
+    fn lookup(&self, name: &str, p_position: &mut i32) -> i32 {
+        match self.symbol.position(name) {
+            Some(pos) => {
+                *p_position = pos as i32;
+                0 // 找到符号
             }
+            None => 23, // 错误：标识符未定义
         }
+    }
 
-        23 // 错误：标识符未定义
+    // // 查找函数名，函数名只登记在全局作用域中，不会被同名的局部变量遮蔽
+    fn lookup_function(&self, name: &str, p_position: &mut i32) -> i32 {
+        match self.symbol.global_position(name) {
+            Some(pos) => {
+                *p_position = pos as i32;
+                0
+            }
+            None => 23, // 错误：标识符未定义
+        }
     }
 
     // 用于区分父子节点
@@ -1725,9 +2645,11 @@ impl Compiler {
             }
         }
     }
-    // // <parameter_stat> -> int ID
+    // // <parameter_stat> -> (int | char | bool) ['&'] ID
     fn parameter_stat(&mut self, root: &mut Node) -> i32 {
         let es;
+        let ty = type_of_keyword(&self.token).unwrap_or(Type::Int);
+        let mut by_ref = false;
 
         let mut child_node = Node::new("<parameter_stat>");
         root.add_child(child_node);
@@ -1740,6 +2662,15 @@ impl Compiler {
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
 
+        if self.token == "&" {
+            // 引用形参
+            by_ref = true;
+            self.add_child(&mut child_node);
+            let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
+            self.token = new_token;  // 分别赋值
+            self.token1 = new_token1;
+        }
+
         if self.token != "ID" {
             return 3; // 错误：不是标识符
         }
@@ -1751,6 +2682,11 @@ impl Compiler {
         if es > 0 {
             return es;
         }
+        let pos = self.symbol.len() - 1;
+        self.symbol[pos].ty = ty;
+        self.symbol[pos].by_ref = by_ref;
+        self.symbol[pos].param = true;
+        self.symbol[pos].address = self.frame.alloc_parameter(&token);
 
         // 读取下一个 token
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
@@ -1764,14 +2700,14 @@ impl Compiler {
     fn parameter_list(&mut self, root: &mut Node) -> i32 {
         let mut flag = 0; // 判断是否参数设置错误
         let mut es = 0;
-        self.offset=2;
+        self.numofvariable = 0; // 每个函数单独统计参数个数
         let mut child_node = Node::new("<parameter_list>");
         root.add_child(child_node);
         child_node = Node::new("<parameter_list>");
 
 
         if self.token != ")" {
-            while self.token == "int" {
+            while self.token == "int" || self.token == "char" || self.token == "bool" {
                 flag = 0;
                 self.numofvariable += 1;
 
@@ -1800,9 +2736,9 @@ impl Compiler {
         }
     }
 
-    // // <variable_stat> -> ID
-    fn variable_stat(&mut self, root: &mut Node) -> i32 {
-        let es;
+    // // <variable_stat> -> <expression>，实参的类型必须能赋给对应形参
+    fn variable_stat(&mut self, root: &mut Node, expected: Option<Type>) -> i32 {
+        let mut es;
 
         let mut child_node = Node::new("<variable_stat>");
         root.add_child(child_node);
@@ -1810,45 +2746,90 @@ impl Compiler {
         child_node = Node::new("<variable_stat>");
         self.add_child(&mut child_node);
 
-        // 检查传入的参数是否已定义
-        let mut pos: i32 = 0;
+        let arg_pos = self.line_num - 1;
+        es = self.expression(&mut child_node);
+        if es > 0 {
+            return es;
+        }
 
-        es = self.lookup(&self.token1, &mut pos, &self.Lastdefinedfunction);
+        let found = self.expr_type;
+        match expected {
+            Some(ty) if !is_assignable(ty, found) => es = self.type_error(arg_pos, ty, found),
+            None if found == Type::Void => es = self.type_error(arg_pos, Type::Int, found),
+            _ => {}
+        }
+
+        es
+    }
+
+    // // <reference_stat> -> ID，引用形参对应的实参只能是变量，传入的是变量的地址
+    fn reference_stat(&mut self, root: &mut Node, expected: Type) -> i32 {
+        let mut child_node = Node::new("<reference_stat>");
+        root.add_child(child_node);
+
+        child_node = Node::new("<reference_stat>");
+        self.add_child(&mut child_node);
+
+        let arg_pos = self.line_num - 1;
+        if self.token != "ID" {
+            self.error_token = Some(arg_pos);
+            return 50; // 错误：实参不是变量
+        }
+
+        let mut symbol_pos = 0;
+        let es = self.lookup(&self.token1, &mut symbol_pos);
         if es > 0 {
             return es;
         }
+        let symbol_pos = symbol_pos as usize;
+        if !are_kinds_equal(self.symbol[symbol_pos].kind.clone(), CategorySymbol::Variable) {
+            self.error_token = Some(arg_pos);
+            return 50; // 错误：常量没有地址
+        }
+        // 引用要求类型完全相同，不做 char 到 int 的转换
+        if self.symbol[symbol_pos].ty != expected {
+            let found = self.symbol[symbol_pos].ty;
+            return self.type_error(arg_pos, expected, found);
+        }
 
-        // 读取下一个 token
         let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
         self.token = new_token;  // 分别赋值
         self.token1 = new_token1;
+        if self.token != "," && self.token != ")" {
+            self.error_token = Some(arg_pos);
+            return 50; // 错误：实参是表达式，不是单独的变量
+        }
 
-        // 生成代码
-        let pos1: usize = pos as usize;
-        self.codes[self.codes_index].opt = "LOAD".to_string();
-        self.codes[self.codes_index].operand = self.symbol[pos1].address;
-        self.codes_index += 1;
+        // 实参本身是引用形参时，直接传递它保存的地址
+        // 被调函数可能读取也可能修改引用的变量
+        self.symbol[symbol_pos].reads += 1;
+        self.symbol[symbol_pos].writes += 1;
+        self.emit(if self.symbol[symbol_pos].by_ref { "LOAD" } else { "LOADA" }, self.symbol[symbol_pos].address);
 
-        es
+        0
     }
 
     // // <variable_list> -> { <variable_stat> ',' } <variable_stat>
-    fn variable_list(&mut self, root: &mut Node, num: i32) -> i32 {
-        let mut flag = 0;
+    fn variable_list(&mut self, root: &mut Node, fun_pos: usize) -> i32 {
         let mut cnt = 0;
         let mut es = 0;
-
+        let num = self.symbol[fun_pos].var_num as usize;
 
         let mut child_node = Node::new("<variable_list>");
         root.add_child(child_node);
         child_node = Node::new("<variable_list>");
 
         if self.token != ")" {
-            while self.token == "ID" {
-                flag = 0;
+            loop {
                 cnt += 1;
 
-                es = self.variable_stat(&mut child_node);
+                // 形参紧跟在函数名之后登记在符号表中
+                let expected = if cnt <= num { Some(self.symbol[fun_pos + cnt].ty) } else { None };
+                es = if cnt <= num && self.symbol[fun_pos + cnt].by_ref {
+                    self.reference_stat(&mut child_node, self.symbol[fun_pos + cnt].ty)
+                } else {
+                    self.variable_stat(&mut child_node, expected)
+                };
                 if es > 0 {
                     return es;
                 }
@@ -1857,22 +2838,17 @@ impl Compiler {
                     let (new_token, new_token1) = fscanf_token(&self.tokenfile,&mut self.line_num).unwrap(); // 解构返回的元组
                     self.token = new_token;  // 分别赋值
                     self.token1 = new_token1;
-                    flag = 1;
                 } else {
                     break;
                 }
             }
+        }
 
-            if flag == 1 {
-                es = 26; // 错误：变量列表格式错误
-            } else if cnt != num {
-                es = 38; // 错误：变量数目不符
-            }
-
-            es
-        } else {
-            es
+        if cnt != num {
+            es = 38; // 错误：变量数目不符
         }
+
+        es
     }
 
 }
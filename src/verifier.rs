@@ -0,0 +1,231 @@
+// 字节码校验
+// 虚拟机执行之前检查从二进制文件读出的程序，损坏或手工改动过的文件不会让虚拟机越界访问或跳到任意地址：
+// 每个操作码都是已知的，BR、BRF、CAL 的目标在代码之内，CAL 的目标是 ENTER；
+// 从程序入口和每个被调函数的入口出发沿所有路径推算操作数栈的深度，深度不能为负，
+// 汇合处各路径的深度必须相同；LOAD、STO 等访问的单元在 ENTER 开辟的栈帧之内
+use std::fmt;
+
+use crate::virtual_machine::{stack_effect, Program};
+
+#[derive(Debug, Clone)]
+pub struct VerifyError {
+    pub address: usize,
+    pub opt: String,
+    pub operand: i32,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "第 {} 条指令 {} {}：{}", self.address, self.opt, self.operand, self.message)
+    }
+}
+
+// 执行到某条指令之前的状态：函数内操作数栈的深度，ENTER 开辟的单元数（还没有执行 ENTER 时为 None）
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct State {
+    depth: usize,
+    frame: Option<usize>,
+}
+
+struct Verifier<'a> {
+    program: &'a Program,
+    names: Vec<String>,
+    valid: Vec<bool>, // 指令本身没有错误，可以继续推算
+    errors: Vec<VerifyError>,
+}
+
+// 校验整个程序，返回发现的全部错误
+pub fn verify(program: &Program) -> Result<(), Vec<VerifyError>> {
+    let mut verifier = Verifier {
+        program,
+        names: program.codes.iter().map(|code| code.name()).collect(),
+        valid: vec![true; program.codes.len()],
+        errors: Vec::new(),
+    };
+    if program.codes.is_empty() {
+        verifier.errors.push(VerifyError { address: 0, opt: String::new(), operand: 0, message: "程序中没有指令".to_string() });
+        return Err(verifier.errors);
+    }
+    verifier.check_instructions();
+
+    // 程序从 0 号指令开始，每个 CAL 的目标是一个函数的入口
+    let mut entries = vec![0];
+    for (address, name) in verifier.names.iter().enumerate() {
+        let target = program.codes[address].operand() as usize;
+        if name == "CAL" && verifier.valid[address] && !entries.contains(&target) {
+            entries.push(target);
+        }
+    }
+    let returns: Vec<(usize, bool)> = entries.iter().skip(1).map(|&entry| (entry, verifier.returns_value(entry))).collect();
+    for (i, &entry) in entries.iter().enumerate() {
+        verifier.check_flow(entry, i == 0, &returns);
+    }
+
+    if verifier.errors.is_empty() {
+        Ok(())
+    } else {
+        verifier.errors.sort_by_key(|error| error.address);
+        Err(verifier.errors)
+    }
+}
+
+impl Verifier<'_> {
+    fn error(&mut self, address: usize, message: String) {
+        let code = &self.program.codes[address];
+        self.errors.push(VerifyError { address, opt: self.names[address].clone(), operand: code.operand(), message });
+    }
+
+    // 逐条检查操作码和操作数
+    fn check_instructions(&mut self) {
+        let len = self.program.codes.len();
+        for address in 0..len {
+            let operand = self.program.codes[address].operand();
+            let name = self.names[address].clone();
+            let message = if stack_effect(&name, operand).is_none() {
+                Some(format!("未知的操作码 {:?}", name))
+            } else {
+                match name.as_str() {
+                    "BR" | "BRF" | "CAL" if operand < 0 || operand as usize >= len => {
+                        Some(format!("目标地址 {} 不在代码的范围 0..{} 之内", operand, len))
+                    }
+                    "CAL" if self.names[operand as usize] != "ENTER" => {
+                        Some(format!("目标地址 {} 处是 {}，不是函数入口的 ENTER", operand, self.names[operand as usize]))
+                    }
+                    "ENTER" if operand < 2 => Some(format!("开辟 {} 个单元，容纳不下保存的基地址和返回地址", operand)),
+                    "OUTS" if operand < 0 || operand as usize >= self.program.strings.len() => {
+                        Some(format!("字符串常量 {} 不存在，常量池中只有 {} 个字符串", operand, self.program.strings.len()))
+                    }
+                    _ => None,
+                }
+            };
+            if let Some(message) = message {
+                self.valid[address] = false;
+                self.error(address, message);
+            }
+        }
+    }
+
+    // 指令之后可能执行的指令，CAL 返回后执行下一条
+    fn successors(&self, address: usize) -> Vec<usize> {
+        let target = self.program.codes[address].operand() as usize;
+        match self.names[address].as_str() {
+            "BR" => vec![target],
+            "BRF" => vec![address + 1, target],
+            "RETURN" | "HALT" => Vec::new(),
+            _ => vec![address + 1],
+        }
+    }
+
+    // 函数是否带值返回：从入口能到达的 RETURN 必须都带值或都不带值
+    fn returns_value(&mut self, entry: usize) -> bool {
+        let mut seen = vec![false; self.program.codes.len()];
+        let mut work = vec![entry];
+        let mut returns: Option<(usize, bool)> = None;
+        while let Some(address) = work.pop() {
+            if address >= seen.len() || seen[address] {
+                continue;
+            }
+            seen[address] = true;
+            if self.names[address] == "RETURN" {
+                let value = self.program.codes[address].operand() != 0;
+                match returns {
+                    None => returns = Some((address, value)),
+                    Some((first, other)) if other != value => {
+                        let message = format!("与第 {} 条指令的 RETURN 不同，同一个函数有的返回值有的不返回值", first);
+                        self.error(address, message);
+                    }
+                    _ => {}
+                }
+            }
+            work.extend(self.successors(address));
+        }
+        returns.is_some_and(|(_, value)| value)
+    }
+
+    // 从入口出发推算每条指令之前的状态
+    fn check_flow(&mut self, entry: usize, program_entry: bool, returns: &[(usize, bool)]) {
+        let len = self.program.codes.len();
+        let mut states: Vec<Option<State>> = vec![None; len];
+        let mut mismatched = vec![false; len];
+        states[entry] = Some(State { depth: 0, frame: None });
+        let mut work = vec![entry];
+        while let Some(address) = work.pop() {
+            if !self.valid[address] {
+                continue;
+            }
+            let state = states[address].unwrap();
+            let operand = self.program.codes[address].operand();
+            let name = self.names[address].clone();
+            let Some(after) = self.transfer(address, &name, operand, state, program_entry, returns) else {
+                continue;
+            };
+            for next in self.successors(address) {
+                if next >= len {
+                    self.error(address, "执行后越过了代码末尾".to_string());
+                    continue;
+                }
+                match states[next] {
+                    None => {
+                        states[next] = Some(after);
+                        work.push(next);
+                    }
+                    Some(old) if old != after && !mismatched[next] => {
+                        mismatched[next] = true;
+                        let message = if old.frame != after.frame {
+                            format!("到达第 {} 条指令时栈帧为 {}，另一条路径上为 {}", next, describe_frame(after.frame), describe_frame(old.frame))
+                        } else {
+                            format!("到达第 {} 条指令时操作数栈有 {} 个值，另一条路径上有 {} 个", next, after.depth, old.depth)
+                        };
+                        self.error(address, message);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // 执行一条指令后的状态，出错时返回 None，不再沿这条路径推算
+    fn transfer(&mut self, address: usize, name: &str, operand: i32, state: State, program_entry: bool, returns: &[(usize, bool)]) -> Option<State> {
+        let (pops, mut pushes) = stack_effect(name, operand)?;
+        match name {
+            "LOAD" | "STO" | "LOADA" | "LOADR" | "STOR" => match state.frame {
+                None => {
+                    self.error(address, "在 ENTER 开辟栈帧之前访问栈帧中的单元".to_string());
+                    return None;
+                }
+                Some(frame) if operand < 0 || operand as usize >= frame => {
+                    self.error(address, format!("偏移 {} 超出了 ENTER 开辟的 {} 个单元", operand, frame));
+                    return None;
+                }
+                _ => {}
+            },
+            "ENTER" if state.frame.is_some() => {
+                self.error(address, "同一个函数中再次执行 ENTER".to_string());
+                return None;
+            }
+            "RETURN" if program_entry => {
+                self.error(address, "从程序入口开始执行的代码没有主调函数可以返回".to_string());
+                return None;
+            }
+            "CAL" => {
+                let callee = operand as usize;
+                pushes += returns.iter().any(|&(entry, value)| entry == callee && value) as usize;
+            }
+            _ => {}
+        }
+        if state.depth < pops {
+            self.error(address, format!("需要 {} 个操作数，操作数栈上只有 {} 个", pops, state.depth));
+            return None;
+        }
+        let frame = if name == "ENTER" { Some(operand as usize) } else { state.frame };
+        Some(State { depth: state.depth - pops + pushes, frame })
+    }
+}
+
+fn describe_frame(frame: Option<usize>) -> String {
+    match frame {
+        Some(size) => format!("{} 个单元", size),
+        None => "还没有开辟".to_string(),
+    }
+}
@@ -1,12 +1,20 @@
 use std::fs::File;
 use std::io::{self, Read};
 use std::collections::HashMap;
+use crate::bytecode;
+use crate::frame::{layout_at, FrameLayout};
 
+pub const STACK_SIZE: usize = 1000; // 运行栈默认的单元数
+
+#[allow(clippy::upper_case_acronyms)] // 操作码沿用助记符写法
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Opt {
     LOAD,
     LOADI,
+    LOADA,
+    LOADR,
     STO,
+    STOR,
     STI,
     ADD,
     SUB,
@@ -25,40 +33,70 @@ pub enum Opt {
     NOT,
     IN,
     OUT,
+    OUTC,
+    OUTN,
+    OUTS,
+    POP,
+    DUP,
     RETURN,
     ENTER,
     CAL,
     PAS,
+    HALT,
 }
 
-#[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct Code {
     opt: [u8; 10], // 操作码
     operand: i32,  // 操作数
 }
 
-// 读取二进制文件
-pub fn read_codes(file_path: &str) -> io::Result<Vec<Code>> {
-    let mut file = File::open(file_path)?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
+impl Code {
+    pub fn new(name: &str, operand: i32) -> Code {
+        let mut opt = [0u8; 10];
+        let len = name.len().min(opt.len());
+        opt[..len].copy_from_slice(&name.as_bytes()[..len]);
+        Code { opt, operand }
+    }
+
+    // 操作码的助记符
+    pub fn name(&self) -> String {
+        byte_array_to_opt_str(&self.opt)
+    }
 
-    // 确保文件大小是 Code 结构体的整数倍
-    assert_eq!(buffer.len() % std::mem::size_of::<Code>(), 0);
+    pub fn operand(&self) -> i32 {
+        self.operand
+    }
+}
 
-    let num_codes = buffer.len() / std::mem::size_of::<Code>();
-    let mut codes = Vec::with_capacity(num_codes);
+// 从二进制文件读出的程序：指令序列、字符串常量池和调试信息
+pub struct Program {
+    pub codes: Vec<Code>,
+    pub strings: Vec<String>,
+    pub frames: Vec<FrameLayout>,             // 符号表段，没有时为空
+    pub lines: Vec<Option<(usize, usize)>>,   // 每条指令在源程序中的（行, 列），没有行号表段时为空
+}
 
-    for i in 0..num_codes {
-        let offset = i * std::mem::size_of::<Code>();
-        let code: Code = unsafe {
-            std::ptr::read(buffer[offset..].as_ptr() as *const Code)
-        };
-        codes.push(code);
+impl Program {
+    // 指令所在的函数和源程序的行，如 main:line 5，缺少调试信息的部分省略
+    pub fn location(&self, address: usize) -> String {
+        let function = layout_at(&self.frames, address).map(|frame| frame.function.as_str());
+        let line = self.lines.get(address).copied().flatten();
+        match (function, line) {
+            (Some(function), Some((row, _))) => format!("{}:line {}", function, row),
+            (Some(function), None) => function.to_string(),
+            (None, Some((row, _))) => format!("line {}", row),
+            (None, None) => format!("指令 {}", address),
+        }
     }
+}
 
-    Ok(codes)
+// 读取二进制文件，格式见 bytecode.rs
+pub fn read_codes(file_path: &str) -> io::Result<Program> {
+    let mut file = File::open(file_path)?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+    bytecode::decode(&buffer)
 }
 
 // 字节转字符串
@@ -82,7 +120,10 @@ pub fn init_map() -> HashMap<String, Opt> {
     let mut map = HashMap::new();
     map.insert("LOAD".to_string(), Opt::LOAD);
     map.insert("LOADI".to_string(), Opt::LOADI);
+    map.insert("LOADA".to_string(), Opt::LOADA);
+    map.insert("LOADR".to_string(), Opt::LOADR);
     map.insert("STO".to_string(), Opt::STO);
+    map.insert("STOR".to_string(), Opt::STOR);
     map.insert("STI".to_string(), Opt::STI);
     map.insert("ADD".to_string(), Opt::ADD);
     map.insert("SUB".to_string(), Opt::SUB);
@@ -101,43 +142,80 @@ pub fn init_map() -> HashMap<String, Opt> {
     map.insert("NOT".to_string(), Opt::NOT);
     map.insert("IN".to_string(), Opt::IN);
     map.insert("OUT".to_string(), Opt::OUT);
+    map.insert("OUTC".to_string(), Opt::OUTC);
+    map.insert("OUTN".to_string(), Opt::OUTN);
+    map.insert("OUTS".to_string(), Opt::OUTS);
+    map.insert("POP".to_string(), Opt::POP);
+    map.insert("DUP".to_string(), Opt::DUP);
     map.insert("RETURN".to_string(), Opt::RETURN);
     map.insert("ENTER".to_string(), Opt::ENTER);
     map.insert("CAL".to_string(), Opt::CAL);
     map.insert("PAS".to_string(), Opt::PAS);
+    map.insert("HALT".to_string(), Opt::HALT);
     map
 }
 
+// 指令从操作数栈取走和压入的值的个数，未知的操作码为 None；
+// CAL 本身不改变栈顶，被调函数带值返回时返回值由 RETURN 压入主调函数的操作数栈
+pub fn stack_effect(opt: &str, operand: i32) -> Option<(usize, usize)> {
+    let effect = match opt {
+        "LOAD" | "LOADI" | "LOADA" | "LOADR" | "IN" => (0, 1),
+        "DUP" => (1, 2),
+        "STO" | "STOR" | "OUT" | "OUTC" | "OUTN" | "POP" | "BRF" | "PAS" | "HALT" => (1, 0),
+        "ADD" | "SUB" | "MULT" | "DIV" | "EQ" | "NOTEQ" | "GT" | "LES" | "GE" | "LE" | "AND" | "OR" => (2, 1),
+        "NOT" => (1, 1),
+        "OUTS" | "BR" | "ENTER" | "CAL" => (0, 0),
+        "RETURN" => ((operand != 0) as usize, 0),
+        _ => return None,
+    };
+    Some(effect)
+}
 
-fn show_stack_info(stack: &Vec<i32>, top: usize, base: usize) {
-    let mut temp = 0;
+// 按栈帧布局给运行栈中的单元标上 函数.变量 的名字
+// 从当前函数的栈帧开始，沿保存的基地址和返回地址逐层找到主调函数的栈帧
+fn stack_labels(stack: &[i32], top: usize, base: usize, ip: usize, frames: &[FrameLayout]) -> Vec<Option<String>> {
+    let mut labels = vec![None; top];
+    let mut frame_base = base;
+    let mut at = ip;
+    while let Some(frame) = layout_at(frames, at) {
+        for slot in &frame.slots {
+            let cell = frame_base + slot.offset as usize;
+            if cell < top {
+                labels[cell] = frame.slot_name(slot.offset).map(|name| format!("{}.{}", frame.function, name));
+            }
+        }
+        if frame_base == 0 {
+            break; // 已经到了 main 的栈帧
+        }
+        let caller_base = stack[frame_base] as usize;
+        if caller_base >= frame_base {
+            break;
+        }
+        at = stack[frame_base + 1] as usize;
+        frame_base = caller_base;
+    }
+    labels
+}
+
+fn show_stack_info(stack: &[i32], top: usize, base: usize, labels: &[Option<String>]) {
     println!("\t************");
 
-    while temp <= top && stack[temp] >= 0 {
-        if top == base {
-            if top == temp {
-                println!("\t*    {}    *    <----top(base)", stack[temp]);
-            } else {
-                println!("\t*    {}    *", stack[temp]);
-            }
-        } else {
-            if top == temp {
-                println!("\t*    {}    *    <----top", stack[temp]);
-            }
-            if base == temp {
-                println!("\t*    {}    *    <----base", stack[temp]);
-            }
-            if top != temp && base != temp {
-                println!("\t*    {}    *", stack[temp]);
-            }
+    for temp in 0..top {
+        let mut marks = String::new();
+        if base == temp {
+            marks.push_str("    <----base");
         }
-        temp += 1;
+        if let Some(label) = &labels[temp] {
+            marks.push_str("    ");
+            marks.push_str(label);
+        }
+        println!("\t*    {}    *{}", stack[temp], marks);
     }
 
-    if top == temp {
-        println!("\t*          *    <----top\n");
+    if top == base {
+        println!("\t*          *    <----top(base)\n");
     } else {
-        println!("\t*         *\n");
+        println!("\t*          *    <----top\n");
     }
 
     println!("\t------------\n");
@@ -146,9 +224,82 @@ fn show_stack_info(stack: &Vec<i32>, top: usize, base: usize) {
 
 
 
-// 模拟抽象机运行
-pub fn test_machine(codes: &[Code], map: &HashMap<String, Opt>) {
-    let mut stack = vec![0; 100];
+// 指令访问的单元必须在运行栈已使用的部分 [0, top) 之内。
+// 引用形参中保存的地址在运行时才知道，校验器无法证明它有效，只能由虚拟机检查
+fn checked_cell(cell: i64, top: usize) -> Result<usize, String> {
+    if cell >= 0 && (cell as usize) < top {
+        Ok(cell as usize)
+    } else {
+        Err(format!("访问的单元地址 {} 不在运行栈已使用的 0 到 {} 之内", cell, top as i64 - 1))
+    }
+}
+
+// 调试模式下读取未赋值单元时的错误信息
+fn uninitialized_read(stack: &[i32], top: usize, base: usize, ip: usize, frames: &[FrameLayout], cell: usize) -> String {
+    let labels = stack_labels(stack, top, base, ip, frames);
+    match labels.get(cell).cloned().flatten() {
+        Some(name) => format!("读取了尚未赋值的变量 {}", name),
+        None => format!("读取了尚未赋值的栈单元 {}", cell),
+    }
+}
+
+// 运行错误中最多输出的调用层数（相邻的相同位置合并后计数）
+const MAX_BACKTRACE: usize = 16;
+
+// 运行错误的位置：出错的指令所在的函数和行，以及沿保存的基地址和返回地址找到的各层调用处
+fn backtrace(program: &Program, stack: &[i32], base: usize, address: usize) -> String {
+    let mut calls = vec![program.location(address)];
+    let mut frame_base = base;
+    while frame_base != 0 {
+        let caller_base = stack[frame_base] as usize;
+        let return_address = stack[frame_base + 1] as usize;
+        if caller_base >= frame_base || return_address == 0 {
+            break;
+        }
+        calls.push(program.location(return_address - 1)); // 返回地址的前一条是 CAL
+        frame_base = caller_base;
+    }
+    // 递归造成的栈溢出会留下成百上千层调用：相邻的相同位置合并为一项并标出次数，
+    // 合并后仍超过 MAX_BACKTRACE 项时只输出最内层的几项和最外层的一项
+    let mut groups: Vec<(String, usize)> = Vec::new();
+    for call in calls {
+        match groups.last_mut() {
+            Some((last, count)) if *last == call => *count += 1,
+            _ => groups.push((call, 1)),
+        }
+    }
+    let mut items: Vec<String> = groups
+        .into_iter()
+        .map(|(call, count)| if count > 1 { format!("{} (×{})", call, count) } else { call })
+        .collect();
+    if items.len() > MAX_BACKTRACE {
+        let omitted = items.len() - MAX_BACKTRACE;
+        let outermost = items.pop().unwrap_or_default();
+        items.truncate(MAX_BACKTRACE - 1);
+        items.push(format!("…（省略 {} 项）", omitted));
+        items.push(outermost);
+    }
+    items.join(" <- ")
+}
+
+// 指令访问的栈帧单元中的变量名，不访问栈帧单元或没有符号表时为 None
+fn variable_name(program: &Program, address: usize) -> Option<&str> {
+    let code = &program.codes[address];
+    if !["LOAD", "LOADA", "LOADR", "STO", "STOR"].contains(&code.name().as_str()) {
+        return None;
+    }
+    let slot = layout_at(&program.frames, address)?.slot_at(code.operand, address)?;
+    Some(&slot.name)
+}
+
+// 模拟抽象机运行，执行到 HALT 时返回程序的退出码
+// poison 为真时进入调试模式：ENTER 新开辟的局部变量单元在赋值之前不能读取
+// 程序带有调试信息时，跟踪输出和运行错误中标出指令所在的函数、源程序的行和变量名
+pub fn test_machine(program: &Program, map: &HashMap<String, Opt>, stack_size: usize, poison: bool) -> Result<i32, String> {
+    let codes = &program.codes;
+    let frames = &program.frames;
+    let mut stack = vec![0; stack_size];
+    let mut poisoned = vec![false; stack_size]; // 调试模式下尚未赋值的单元
     let mut top = 0;
     let mut base = 0;
     let mut ip = 0;
@@ -158,14 +309,31 @@ pub fn test_machine(codes: &[Code], map: &HashMap<String, Opt>) {
 
 
     loop{
+        if ip >= codes.len() {
+            let message = format!("指令地址 {} 越过了代码末尾，程序没有执行 HALT", ip);
+            return Err(format!("{}：{}", backtrace(program, &stack, base, ip.saturating_sub(1)), message));
+        }
+        let address = ip;
         let instruction = &codes[ip];
         ip += 1;
         outflag = false;
         let opt_str = byte_array_to_opt_str(&instruction.opt);
         if let Some(&operation) = map.get(&opt_str) {
+            // 每条指令最多向上使用两个单元，ENTER 还要再开辟整个栈帧
+            let growth = if operation == Opt::ENTER { instruction.operand.max(0) as usize } else { 0 };
+            if top + growth + 2 >= stack.len() {
+                let message = format!("运行栈溢出，运行栈最多只有 {} 个单元", stack.len());
+                return Err(format!("{}：{}", backtrace(program, &stack, base, address), message));
+            }
             match operation {
                 Opt::LOAD => {
-                    stack[top] = stack[base + instruction.operand as usize];
+                    let cell = checked_cell(base as i64 + instruction.operand as i64, top)
+                        .map_err(|message| format!("{}：{}", backtrace(program, &stack, base, address), message))?;
+                    if poisoned[cell] {
+                        let message = uninitialized_read(&stack, top, base, ip, frames, cell);
+                        return Err(format!("{}：{}", backtrace(program, &stack, base, address), message));
+                    }
+                    stack[top] = stack[cell];
                     top += 1;
                     outflag = true;
                 }
@@ -176,29 +344,93 @@ pub fn test_machine(codes: &[Code], map: &HashMap<String, Opt>) {
                 }
                 Opt::STO => {
                     top -= 1;
-                    stack[base + instruction.operand as usize] = stack[top];
+                    let cell = checked_cell(base as i64 + instruction.operand as i64, top)
+                        .map_err(|message| format!("{}：{}", backtrace(program, &stack, base, address), message))?;
+                    stack[cell] = stack[top];
+                    poisoned[cell] = false;
+                    outflag = true;
+                }
+                Opt::LOADA => {
+                    stack[top] = (base + instruction.operand as usize) as i32; // 取变量在栈中的绝对地址
+                    top += 1;
+                    outflag = true;
+                }
+                Opt::LOADR => {
+                    let cell = checked_cell(base as i64 + instruction.operand as i64, top)
+                        .and_then(|reference| checked_cell(stack[reference] as i64, top))
+                        .map_err(|message| format!("{}：{}", backtrace(program, &stack, base, address), message))?;
+                    if poisoned[cell] {
+                        let message = uninitialized_read(&stack, top, base, ip, frames, cell);
+                        return Err(format!("{}：{}", backtrace(program, &stack, base, address), message));
+                    }
+                    stack[top] = stack[cell]; // 经引用形参中保存的地址间接取值
+                    top += 1;
+                    outflag = true;
+                }
+                Opt::STOR => {
+                    top -= 1;
+                    let cell = checked_cell(base as i64 + instruction.operand as i64, top)
+                        .and_then(|reference| checked_cell(stack[reference] as i64, top))
+                        .map_err(|message| format!("{}：{}", backtrace(program, &stack, base, address), message))?;
+                    stack[cell] = stack[top]; // 经引用形参中保存的地址间接存值
+                    poisoned[cell] = false;
                     outflag = true;
                 }
                 Opt::ADD => {
                     top -= 1;
-                    stack[top - 1] += stack[top];
+                    match stack[top - 1].checked_add(stack[top]) {
+                        Some(value) => stack[top - 1] = value,
+                        None => return Err(format!("{}：整数溢出", backtrace(program, &stack, base, address))),
+                    }
                 }
                 Opt::SUB => {
                     top -= 1;
-                    stack[top - 1] -= stack[top];
+                    match stack[top - 1].checked_sub(stack[top]) {
+                        Some(value) => stack[top - 1] = value,
+                        None => return Err(format!("{}：整数溢出", backtrace(program, &stack, base, address))),
+                    }
                 }
                 Opt::MULT => {
                     top -= 1;
-                    stack[top - 1] *= stack[top];
+                    match stack[top - 1].checked_mul(stack[top]) {
+                        Some(value) => stack[top - 1] = value,
+                        None => return Err(format!("{}：整数溢出", backtrace(program, &stack, base, address))),
+                    }
                 }
                 Opt::DIV => {
                     top -= 1;
-                    stack[top - 1] /= stack[top];
+                    match stack[top - 1].checked_div(stack[top]) {
+                        Some(quotient) => stack[top - 1] = quotient,
+                        None => {
+                            let message = if stack[top] == 0 { "除数为 0" } else { "除法溢出" };
+                            return Err(format!("{}：{}", backtrace(program, &stack, base, address), message));
+                        }
+                    }
                 }
                 Opt::OUT => {
                     top -= 1;
                     println!("程序输出: {}", stack[top]);
                 }
+                Opt::OUTN => {
+                    use std::io::Write;
+                    top -= 1;
+                    print!("{}", stack[top]);
+                    io::stdout().flush().unwrap();
+                }
+                Opt::OUTS => {
+                    use std::io::Write;
+                    // 按下标从常量池取出字符串原样输出
+                    print!("{}", program.strings[instruction.operand as usize]);
+                    io::stdout().flush().unwrap();
+                    outflag = true;
+                }
+                Opt::OUTC => {
+                    use std::io::Write;
+                    top -= 1;
+                    // 按字符原样输出，不附加前缀和换行
+                    print!("{}", char::from_u32(stack[top] as u32).unwrap_or('?'));
+                    io::stdout().flush().unwrap();
+                }
                 Opt::BR => {
                     ip = instruction.operand as usize; // 无条件跳转
                     outflag = true;
@@ -255,13 +487,39 @@ pub fn test_machine(codes: &[Code], map: &HashMap<String, Opt>) {
                     top += 1;
                 }
                 Opt::ENTER => {
+                    if poison {
+                        // 保存的基地址、返回地址和形参已经赋值，其余的局部变量单元标记为未赋值
+                        let params = layout_at(frames, ip - 1).map_or(0, |frame| frame.parameter_count());
+                        let end = top + instruction.operand as usize;
+                        for (cell, flag) in poisoned.iter_mut().enumerate().take(end).skip(base) {
+                            *flag = cell >= base + 2 + params;
+                        }
+                    }
                     top += instruction.operand as usize; // 为被调函数开辟栈空间
                     outflag = true;
                 }
                 Opt::RETURN => {
+                    let value = if instruction.operand != 0 {
+                        top -= 1;
+                        Some(stack[top]) // 带返回值时先取出栈顶的值
+                    } else {
+                        None
+                    };
                     top = base;          // 释放被调函数的栈空间
                     ip = stack[top + 1] as usize; // 获取返回地址
                     base = stack[top] as usize;   // 恢复主调函数的基地址
+                    if let Some(value) = value {
+                        stack[top] = value; // 返回值放在主调函数的栈顶
+                        top += 1;
+                    }
+                    outflag = instruction.operand != 0;
+                }
+                Opt::POP => {
+                    top -= 1; // 丢弃栈顶的值
+                }
+                Opt::DUP => {
+                    stack[top] = stack[top - 1]; // 复制栈顶的值
+                    top += 1;
                 }
                 Opt::CAL => {
                     stack[top] = base as i32;        // 保存当前基地址
@@ -274,30 +532,40 @@ pub fn test_machine(codes: &[Code], map: &HashMap<String, Opt>) {
                     top -= 1;
                     stack[top + 2] = stack[top]; // 参数传递
                 }
+                Opt::HALT => {
+                    top -= 1;
+                    println!("Step{}:  {}", step, opt_str);
+                    println!("程序结束，退出码: {}", stack[top]);
+                    return Ok(stack[top]); // 栈顶的值为程序的退出码
+                }
                 _ => println!("未实现的操作: {}", opt_str),
             }
         } else {
             println!("未知操作码: {}", opt_str);
         }
 
+        // 带调试信息时在指令之后标出变量名和所在的函数、行
+        let mut note = String::new();
+        if let Some(name) = variable_name(program, address) {
+            note.push_str(&format!(" ({})", name));
+        }
+        if !frames.is_empty() || !program.lines.is_empty() {
+            note.push_str(&format!("    ; {}", program.location(address)));
+        }
         if outflag{
-            println!("Step{}:  {}    {}", step, opt_str, instruction.operand);
+            println!("Step{}:  {}    {}{}", step, opt_str, instruction.operand, note);
             step += 1;
         }
 
         else{
-            println!("Step{}:  {}", step, opt_str);
+            println!("Step{}:  {}{}", step, opt_str, note);
             step += 1;
         }
 
         // println!("Step {}: {}", step, opt_str);
         // step += 1;
-        show_stack_info(&stack, top, base);
-
-
-        if ip == 0 {
-            break;
-        }
+        let labels = stack_labels(&stack, top, base, ip, frames);
+        show_stack_info(&stack, top, base, &labels);
     }
 }
 
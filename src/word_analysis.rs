@@ -2,8 +2,8 @@ use std::fs::File;
 use std::path::Path;
 use std::io::{self, Read, Write, Seek, SeekFrom};
 
-const KEYWORDS: &[&str] = &["int", "if", "while", "for", "else", "read", "write", "function", "call"];
-const SINGLE_WORDS: &[char] = &['+', '-', '*', '(', ')', ';', ',', ':', '{', '}'];
+const KEYWORDS: &[&str] = &["int", "if", "while", "for", "else", "read", "write", "function", "call", "const", "char", "bool", "void", "true", "false", "return"];
+const SINGLE_WORDS: &[char] = &['+', '-', '*', '/', '(', ')', ';', ',', ':', '?', '{', '}', '&'];
 const DOUBLE_WORDS: &[char] = &['<', '>', '=', '!'];
 
 fn is_single_word(ch: char) -> bool {
@@ -14,7 +14,77 @@ fn is_double_word(ch: char) -> bool {
     DOUBLE_WORDS.contains(&ch)
 }
 
-fn compile_word(rfile: &mut File, wfile: &mut File, row: &mut usize) -> io::Result<i32> {
+// 读取字符字面量 'a' 或 '\n' 的内容，返回字符编码，格式错误时返回错误原因
+// 字符按字节读取，字符常量只能是单字节（ASCII）字符
+fn read_char_literal(rfile: &mut File) -> io::Result<Result<u32, &'static str>> {
+    let mut buffer = [0; 1];
+    if rfile.read(&mut buffer)? == 0 {
+        return Ok(Err("缺少右单引号"));
+    }
+    let code = match buffer[0] {
+        b'\\' => {
+            if rfile.read(&mut buffer)? == 0 {
+                return Ok(Err("缺少右单引号"));
+            }
+            match buffer[0] {
+                b'n' => '\n' as u32,
+                b't' => '\t' as u32,
+                b'0' => 0,
+                b'\\' => '\\' as u32,
+                b'\'' => '\'' as u32,
+                _ => return Ok(Err("未知的转义序列")),
+            }
+        }
+        b'\'' | b'\n' => return Ok(Err("字符常量为空或跨行")),
+        byte if !byte.is_ascii() => {
+            // 跳过这个字符余下的字节和右单引号，避免把它们当作非法字符再报错
+            while rfile.read(&mut buffer)? != 0 && buffer[0] != b'\'' && buffer[0] != b'\n' {}
+            if buffer[0] == b'\n' {
+                rfile.seek(SeekFrom::Current(-1))?; // 换行留给行号统计
+            }
+            return Ok(Err("字符常量只能是单字节字符，多字节字符请写在字符串中"));
+        }
+        byte => byte as u32,
+    };
+
+    // 读取右单引号
+    if rfile.read(&mut buffer)? == 0 || buffer[0] != b'\'' {
+        return Ok(Err("缺少右单引号"));
+    }
+    Ok(Ok(code))
+}
+
+// 读取字符串常量 "..." 的内容（不含引号），转义序列保持原样，格式错误时返回错误原因
+// 先收集原始字节，读完后按 UTF-8 解码，字符串中可以有中文等多字节字符
+fn read_string_literal(rfile: &mut File) -> io::Result<Result<String, &'static str>> {
+    let mut bytes = Vec::new();
+    let mut buffer = [0; 1];
+    loop {
+        if rfile.read(&mut buffer)? == 0 {
+            return Ok(Err("字符串没有结束"));
+        }
+        match buffer[0] {
+            b'"' => break,
+            b'\n' => return Ok(Err("字符串不能跨行")),
+            b'\\' => {
+                if rfile.read(&mut buffer)? == 0 {
+                    return Ok(Err("字符串没有结束"));
+                }
+                let escaped = buffer[0];
+                if !b"nt0\\\"".contains(&escaped) {
+                    return Ok(Err("未知的转义序列"));
+                }
+                bytes.push(b'\\');
+                bytes.push(escaped);
+            }
+            other => bytes.push(other),
+        }
+    }
+    Ok(String::from_utf8(bytes).map_err(|_| "字符串不是合法的 UTF-8 文本"))
+}
+
+// 单词流每行的格式为：行:列 单词类别 单词原文
+fn compile_word(rfile: &mut File, wfile: &mut File, row: &mut usize, line_start: &mut u64) -> io::Result<i32> {
     let mut word = String::new();
     let mut buffer = [0; 1];
 
@@ -26,6 +96,7 @@ fn compile_word(rfile: &mut File, wfile: &mut File, row: &mut usize) -> io::Resu
         let ch: char = buffer[0] as char;
         if ch == '\n' {
             *row += 1;
+            *line_start = rfile.stream_position()?; // 记录行首位置，用于计算列号
         } else if !ch.is_whitespace() {
             break; // 跳过空白字符并继续
         }
@@ -33,6 +104,7 @@ fn compile_word(rfile: &mut File, wfile: &mut File, row: &mut usize) -> io::Resu
 
     // 读取当前字符
     let ch = buffer[0] as char;
+    let col = rfile.stream_position()? - *line_start; // 单词起始列号（从1开始）
 
     if ch.is_alphabetic() {
         // 处理标识符或关键字
@@ -49,9 +121,9 @@ fn compile_word(rfile: &mut File, wfile: &mut File, row: &mut usize) -> io::Resu
         }
         let word_lower = word.to_lowercase();
         if KEYWORDS.contains(&word_lower.as_str()) {
-            writeln!(wfile, "\t{}\t\t{}", word_lower, word)?;
+            writeln!(wfile, "{}:{}\t{}\t\t{}", row, col, word_lower, word)?;
         } else {
-            writeln!(wfile, "\tID\t\t{}", word)?;
+            writeln!(wfile, "{}:{}\tID\t\t{}", row, col, word)?;
         }
     } else if ch.is_digit(10) {
         // 处理数字
@@ -66,10 +138,28 @@ fn compile_word(rfile: &mut File, wfile: &mut File, row: &mut usize) -> io::Resu
                 break;
             }
         }
-        writeln!(wfile, "\tNUM\t\t{}", word)?;
+        writeln!(wfile, "{}:{}\tNUM\t\t{}", row, col, word)?;
+    } else if ch == '\'' {
+        // 处理字符字面量，单词流中记录其字符编码
+        match read_char_literal(rfile)? {
+            Ok(code) => writeln!(wfile, "{}:{}\tCHAR\t\t{}", row, col, code)?,
+            Err(reason) => {
+                println!("错误：字符常量格式错误：{}\t错误位置在第{}行", reason, row);
+                return Ok(2);
+            }
+        }
+    } else if ch == '"' {
+        // 处理字符串常量，单词流中保留带引号的原文
+        match read_string_literal(rfile)? {
+            Ok(literal) => writeln!(wfile, "{}:{}\tSTRING\t\t\"{}\"", row, col, literal)?,
+            Err(reason) => {
+                println!("错误：字符串常量格式错误：{}\t错误位置在第{}行", reason, row);
+                return Ok(2);
+            }
+        }
     } else if is_single_word(ch) {
         // 处理单字符操作符（包括括号）
-        writeln!(wfile, "\t{}\t\t{}", ch, ch)?;
+        writeln!(wfile, "{}:{}\t{}\t\t{}", row, col, ch, ch)?;
     } else if is_double_word(ch) {
         // 处理双字符操作符
         let mut operator = String::new();
@@ -83,7 +173,7 @@ fn compile_word(rfile: &mut File, wfile: &mut File, row: &mut usize) -> io::Resu
                 rfile.seek(SeekFrom::Current(-1))?; // 向前回退1个字节
             }
         }
-        writeln!(wfile, "\t{}\t\t{}", operator, operator)?;
+        writeln!(wfile, "{}:{}\t{}\t\t{}", row, col, operator, operator)?;
     } else {
         // 处理非法字符
         println!("错误：非法字符：{}\t错误位置在第{}行", ch, row);
@@ -101,8 +191,9 @@ pub fn compile(input_filename: &str, output_filename: &str) -> io::Result<()> {
     //writeln!(wfile, "--------------------编译结果--------------------")?;
 
     let mut row = 1;
+    let mut line_start = 0;
     loop {
-        match compile_word(&mut rfile, &mut wfile, &mut row)? {
+        match compile_word(&mut rfile, &mut wfile, &mut row, &mut line_start)? {
             -1 => break,
             0 => (),
             _ => println!("错误发生在第{}行", row),